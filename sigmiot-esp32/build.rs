@@ -1,3 +1,13 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+/// Directory of the dashboard static files, relative to the crate
+const WEB_DIR: &str = "web";
+
 // Necessary because of this issue: https://github.com/rust-lang/cargo/issues/9641
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     embuild::build::CfgArgs::output_propagated("ESP_IDF")?;
@@ -9,5 +19,129 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .input("../protos/sigmiot_data.proto")
         .run_from_script();
 
+    embed_web_assets()?;
+    embed_tls_credentials()?;
+    embed_ota_public_key()?;
+
+    Ok(())
+}
+
+/// Compress the dashboard files and generate the `WEB_ASSETS` table included by web.rs
+fn embed_web_assets() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed={}", WEB_DIR);
+
+    let out_dir = std::env::var("OUT_DIR")?;
+    let assets_dir = Path::new(&out_dir).join("web");
+    fs::create_dir_all(&assets_dir)?;
+
+    let mut entries = fs::read_dir(WEB_DIR)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+
+    let mut table = String::from("pub static WEB_ASSETS: &[WebAsset] = &[\n");
+    for path in entries.iter().filter(|path| path.is_file()) {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let content = fs::read(path)?;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&content)?;
+        let compressed = encoder.finish()?;
+
+        let gz_path = assets_dir.join(format!("{}.gz", name));
+        fs::write(&gz_path, &compressed)?;
+
+        table.push_str(&format!(
+            "    WebAsset {{ path: \"/{}\", content_type: \"{}\", etag: \"\\\"{:016x}\\\"\", data: include_bytes!({:?}) }},\n",
+            name,
+            content_type(&name),
+            fnv1a(&content),
+            gz_path.to_string_lossy(),
+        ));
+    }
+    table.push_str("];\n");
+
+    fs::write(Path::new(&out_dir).join("web_assets.rs"), table)?;
+
+    Ok(())
+}
+
+/// Embed the server certificate and key given by the SIGMIOT_TLS_CERT and SIGMIOT_TLS_KEY
+/// variables as NUL-terminated PEM, as ESP-TLS wants them
+fn embed_tls_credentials() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-env-changed=SIGMIOT_TLS_CERT");
+    println!("cargo:rerun-if-env-changed=SIGMIOT_TLS_KEY");
+
+    let out_dir = std::env::var("OUT_DIR")?;
+    let mut code = String::new();
+
+    for (var, name) in [
+        ("SIGMIOT_TLS_CERT", "EMBEDDED_TLS_CERT"),
+        ("SIGMIOT_TLS_KEY", "EMBEDDED_TLS_KEY"),
+    ] {
+        code.push_str(&embedded_pem_const(&out_dir, var, name)?);
+    }
+
+    fs::write(Path::new(&out_dir).join("tls_embedded.rs"), code)?;
+
+    Ok(())
+}
+
+/// Generate `ota_key.rs` with the public key checking the signature of the OTA images,
+/// read from the PEM file named by SIGMIOT_OTA_PUBLIC_KEY
+fn embed_ota_public_key() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-env-changed=SIGMIOT_OTA_PUBLIC_KEY");
+
+    let out_dir = std::env::var("OUT_DIR")?;
+    let code = embedded_pem_const(&out_dir, "SIGMIOT_OTA_PUBLIC_KEY", "OTA_PUBLIC_KEY")?;
+
+    fs::write(Path::new(&out_dir).join("ota_key.rs"), code)?;
+
     Ok(())
 }
+
+/// Copy the PEM file named by an environment variable to OUT_DIR with a trailing nul
+/// # Returns
+/// * The declaration of the `Option<&'static [u8]>` const holding it, None if the variable is not set
+fn embedded_pem_const(
+    out_dir: &str,
+    var: &str,
+    name: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let path = match std::env::var(var) {
+        Ok(path) => path,
+        Err(_) => return Ok(format!("const {}: Option<&'static [u8]> = None;\n", name)),
+    };
+    println!("cargo:rerun-if-changed={}", path);
+
+    let mut pem = fs::read(&path)?;
+    pem.push(0);
+    let embedded_path = Path::new(out_dir).join(format!("{}.pem", name.to_lowercase()));
+    fs::write(&embedded_path, pem)?;
+
+    Ok(format!(
+        "const {}: Option<&'static [u8]> = Some(include_bytes!({:?}));\n",
+        name,
+        embedded_path.to_string_lossy()
+    ))
+}
+
+fn content_type(name: &str) -> &'static str {
+    match name.rsplit('.').next() {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        Some("json") => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Hash of the file content, used as its ETag
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Error};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::sensors::SensorData;
+use crate::storage::{storage_load, storage_store};
+
+const ALERTS_STORAGE_KEY: &str = "alert_rules";
+const MAX_RULES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Condition {
+    /// The value is above the threshold
+    Above,
+    /// The value is below the threshold
+    Below,
+    /// The absolute change of the value per second is above the threshold
+    RateOfChange,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlertRule {
+    pub rule_id: String,
+    pub sensor_name: String,
+    pub value_name: String,
+    pub condition: Condition,
+    pub threshold: f32,
+    /// Number of consecutive samples the condition must hold before the alert is raised
+    pub samples: u32,
+    /// Distance from the threshold the value must get back to before the alert is cleared
+    pub hysteresis: f32,
+}
+
+impl AlertRule {
+    fn validate(&self) -> Result<(), Error> {
+        if self.rule_id.is_empty() || self.sensor_name.is_empty() || self.value_name.is_empty() {
+            return Err(anyhow!("rule id, sensor and value name are required"));
+        }
+
+        if !self.threshold.is_finite() || !self.hysteresis.is_finite() || self.hysteresis < 0.0 {
+            return Err(anyhow!("invalid threshold or hysteresis"));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlertState {
+    Raised,
+    Cleared,
+}
+
+#[derive(Debug, Clone)]
+pub struct AlertEvent {
+    pub rule_id: String,
+    pub sensor_name: String,
+    pub value_name: String,
+    pub value: f32,
+    pub state: AlertState,
+    pub timestamp: u64,
+    pub message: String,
+}
+
+#[derive(Debug, Default)]
+struct RuleState {
+    active: bool,
+    consecutive: u32,
+    /// The previous value and its timestamp in milliseconds, used for the rate of change
+    previous: Option<(f32, u64)>,
+}
+
+#[derive(Debug, Default)]
+pub struct AlertEngine {
+    rules: Vec<AlertRule>,
+    states: HashMap<String, RuleState>,
+}
+
+impl AlertEngine {
+    pub fn new(rules: Vec<AlertRule>) -> Self {
+        Self {
+            rules,
+            states: HashMap::new(),
+        }
+    }
+
+    pub fn get_rules(&self) -> &Vec<AlertRule> {
+        &self.rules
+    }
+
+    /// Add a rule or replace the rule with the same id
+    pub fn add_rule(&mut self, rule: AlertRule) -> Result<(), Error> {
+        rule.validate()?;
+
+        let replaced = self.remove_rule(&rule.rule_id);
+        if !replaced && self.rules.len() >= MAX_RULES {
+            return Err(anyhow!("too many rules, max is {}", MAX_RULES));
+        }
+
+        self.rules.push(rule);
+        Ok(())
+    }
+
+    /// Remove a rule by its id
+    /// # Returns
+    /// * true if the rule existed
+    pub fn remove_rule(&mut self, rule_id: &str) -> bool {
+        let len = self.rules.len();
+        self.rules.retain(|r| r.rule_id != rule_id);
+        self.states.remove(rule_id);
+        len != self.rules.len()
+    }
+
+    /// Evaluate all rules over the freshly read sensors data
+    /// # Arguments
+    /// * `sensors_data` - The data to evaluate
+    /// * `timestamp_ms` - The time of the reading in milliseconds
+    /// # Returns
+    /// * The alerts which were raised or cleared by this reading
+    pub fn evaluate(&mut self, sensors_data: &[SensorData], timestamp_ms: u64) -> Vec<AlertEvent> {
+        let mut events = vec![];
+
+        for rule in self.rules.iter() {
+            let value = sensors_data
+                .iter()
+                .find(|s| s.get_name() == &rule.sensor_name)
+                .and_then(|s| s.get_value(&rule.value_name))
+                .map(|v| v.value);
+
+            // The sensor may be absent or not read yet, the rule just waits for it
+            let value = match value {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let state = self.states.entry(rule.rule_id.clone()).or_default();
+            if let Some(event) = evaluate_rule(rule, state, value, timestamp_ms) {
+                events.push(event);
+            }
+        }
+
+        events
+    }
+}
+
+fn evaluate_rule(
+    rule: &AlertRule,
+    state: &mut RuleState,
+    value: f32,
+    timestamp_ms: u64,
+) -> Option<AlertEvent> {
+    let measured = match rule.condition {
+        Condition::Above | Condition::Below => Some(value),
+        Condition::RateOfChange => match state.previous {
+            Some((prev_value, prev_ts)) if timestamp_ms > prev_ts => {
+                let dt = (timestamp_ms - prev_ts) as f32 / 1000.0;
+                Some((value - prev_value).abs() / dt)
+            }
+            _ => None,
+        },
+    };
+    state.previous = Some((value, timestamp_ms));
+
+    let measured = measured?;
+
+    let (triggered, cleared) = match rule.condition {
+        Condition::Above | Condition::RateOfChange => (
+            measured > rule.threshold,
+            measured <= rule.threshold - rule.hysteresis,
+        ),
+        Condition::Below => (
+            measured < rule.threshold,
+            measured >= rule.threshold + rule.hysteresis,
+        ),
+    };
+
+    let new_state = if !state.active {
+        state.consecutive = if triggered { state.consecutive + 1 } else { 0 };
+        if state.consecutive >= rule.samples.max(1) {
+            state.active = true;
+            Some(AlertState::Raised)
+        } else {
+            None
+        }
+    } else if cleared {
+        state.active = false;
+        state.consecutive = 0;
+        Some(AlertState::Cleared)
+    } else {
+        None
+    };
+
+    new_state.map(|alert_state| AlertEvent {
+        rule_id: rule.rule_id.clone(),
+        sensor_name: rule.sensor_name.clone(),
+        value_name: rule.value_name.clone(),
+        value,
+        state: alert_state,
+        timestamp: timestamp_ms / 1000,
+        message: format!(
+            "{}/{} {} {:?} {} ({})",
+            rule.sensor_name,
+            rule.value_name,
+            measured,
+            rule.condition,
+            rule.threshold,
+            match alert_state {
+                AlertState::Raised => "raised",
+                AlertState::Cleared => "cleared",
+            }
+        ),
+    })
+}
+
+lazy_static! {
+    static ref ALERT_ENGINE: Mutex<AlertEngine> = Mutex::new(AlertEngine::default());
+}
+
+/// Load the alert rules from NVS, or use the default rules if none are stored
+pub fn alerts_load(default_rules: Vec<AlertRule>) {
+    let rules = match storage_load::<Vec<AlertRule>>(ALERTS_STORAGE_KEY) {
+        Some(rules) => {
+            info!("Loaded {} alert rules", rules.len());
+            rules
+        }
+        None => default_rules,
+    };
+
+    // The stored rules go through the same checks as the rules set by the clients
+    let mut engine = AlertEngine::default();
+    for rule in rules {
+        let rule_id = rule.rule_id.clone();
+        if let Err(e) = engine.add_rule(rule) {
+            warn!("Ignoring the stored alert rule '{}': {:?}", rule_id, e);
+        }
+    }
+
+    *ALERT_ENGINE.lock().unwrap() = engine;
+}
+
+fn alerts_save(engine: &AlertEngine) -> Result<(), Error> {
+    storage_store(ALERTS_STORAGE_KEY, engine.get_rules())
+}
+
+/// Add or replace an alert rule and persist the rules
+pub fn alerts_add_rule(rule: AlertRule) -> Result<(), Error> {
+    let mut engine = ALERT_ENGINE.lock().unwrap();
+
+    // The running rules only change once the new set is stored
+    let mut updated = AlertEngine::new(engine.get_rules().clone());
+    updated.add_rule(rule.clone())?;
+    alerts_save(&updated)?;
+
+    info!("Alert rule '{}' set to {:?}", rule.rule_id, rule);
+    engine.add_rule(rule)
+}
+
+/// Remove an alert rule and persist the rules
+/// # Returns
+/// * Ok(false) if the rule did not exist
+pub fn alerts_remove_rule(rule_id: &str) -> Result<bool, Error> {
+    let mut engine = ALERT_ENGINE.lock().unwrap();
+
+    let mut updated = AlertEngine::new(engine.get_rules().clone());
+    if !updated.remove_rule(rule_id) {
+        return Ok(false);
+    }
+
+    alerts_save(&updated)?;
+    Ok(engine.remove_rule(rule_id))
+}
+
+pub fn alerts_get_rules() -> Vec<AlertRule> {
+    ALERT_ENGINE.lock().unwrap().get_rules().clone()
+}
+
+/// Evaluate the alert rules and log the raised and cleared alerts
+pub fn alerts_evaluate(sensors_data: &[SensorData], timestamp_ms: u64) -> Vec<AlertEvent> {
+    let events = ALERT_ENGINE
+        .lock()
+        .unwrap()
+        .evaluate(sensors_data, timestamp_ms);
+
+    for event in events.iter() {
+        match event.state {
+            AlertState::Raised => warn!("Alert '{}': {}", event.rule_id, event.message),
+            AlertState::Cleared => info!("Alert '{}': {}", event.rule_id, event.message),
+        }
+    }
+
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(condition: Condition, threshold: f32, samples: u32, hysteresis: f32) -> AlertRule {
+        AlertRule {
+            rule_id: "rule".into(),
+            sensor_name: "BME280".into(),
+            value_name: "temperature".into(),
+            condition,
+            threshold,
+            samples,
+            hysteresis,
+        }
+    }
+
+    /// Feed the values one second apart and collect the state changes
+    fn run(rule: &AlertRule, values: &[f32]) -> Vec<Option<AlertState>> {
+        let mut state = RuleState::default();
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                evaluate_rule(rule, &mut state, *value, i as u64 * 1000).map(|event| event.state)
+            })
+            .collect()
+    }
+
+    const RAISED: Option<AlertState> = Some(AlertState::Raised);
+    const CLEARED: Option<AlertState> = Some(AlertState::Cleared);
+
+    #[test]
+    fn above_is_raised_once_and_cleared_below_the_hysteresis_band() {
+        let rule = rule(Condition::Above, 30.0, 1, 2.0);
+
+        assert_eq!(
+            run(&rule, &[29.0, 30.0, 31.0, 35.0, 29.0, 28.5, 28.0, 31.0]),
+            vec![None, None, RAISED, None, None, None, CLEARED, RAISED]
+        );
+    }
+
+    #[test]
+    fn below_is_cleared_above_the_hysteresis_band() {
+        let rule = rule(Condition::Below, 10.0, 1, 1.0);
+
+        assert_eq!(
+            run(&rule, &[11.0, 9.0, 10.5, 11.0, 9.5]),
+            vec![None, RAISED, None, CLEARED, RAISED]
+        );
+    }
+
+    #[test]
+    fn samples_debounce_the_raise() {
+        let rule = rule(Condition::Above, 30.0, 3, 0.0);
+
+        // An interrupted run starts the count again
+        assert_eq!(
+            run(&rule, &[31.0, 31.0, 29.0, 31.0, 31.0, 31.0, 31.0]),
+            vec![None, None, None, None, None, RAISED, None]
+        );
+    }
+
+    #[test]
+    fn samples_do_not_delay_the_clear() {
+        let rule = rule(Condition::Above, 30.0, 2, 0.0);
+
+        assert_eq!(
+            run(&rule, &[31.0, 31.0, 30.0, 31.0, 31.0]),
+            vec![None, RAISED, CLEARED, None, RAISED]
+        );
+    }
+
+    #[test]
+    fn rate_of_change_is_per_second_in_both_directions() {
+        let rule = rule(Condition::RateOfChange, 5.0, 1, 1.0);
+
+        // The first value has no rate yet, a fall raises the alert as a rise does
+        assert_eq!(
+            run(&rule, &[20.0, 24.0, 30.0, 25.5, 21.0, 18.0, 11.0]),
+            vec![None, None, RAISED, None, None, CLEARED, RAISED]
+        );
+    }
+
+    #[test]
+    fn rate_of_change_uses_the_time_between_the_samples() {
+        let rule = rule(Condition::RateOfChange, 5.0, 1, 0.0);
+        let mut state = RuleState::default();
+
+        assert!(evaluate_rule(&rule, &mut state, 20.0, 0).is_none());
+        // 8 in 2 s is below the threshold
+        assert!(evaluate_rule(&rule, &mut state, 28.0, 2000).is_none());
+        // Same timestamp, no rate
+        assert!(evaluate_rule(&rule, &mut state, 50.0, 2000).is_none());
+
+        let event = evaluate_rule(&rule, &mut state, 38.0, 3000).unwrap();
+        assert_eq!(event.state, AlertState::Raised);
+        assert_eq!(event.value, 38.0);
+        assert_eq!(event.timestamp, 3);
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+/// A sensor value as published to the clients, over protobuf and JSON
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ValueReading {
+    pub name: String,
+    pub value: f32,
+    /// The value as read from the sensor, before calibration and filtering
+    pub raw_value: f32,
+    pub unit: String,
+    pub filtered: bool,
+}
+
+/// The values of a sensor at one poll
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SensorReading {
+    pub name: String,
+    pub sensor_type: Vec<String>,
+    pub location: String,
+    /// Milliseconds since the Unix epoch if wall_clock is set, since boot otherwise
+    pub timestamp_ms: u64,
+    pub wall_clock: bool,
+    /// Sorted by name
+    pub values: Vec<ValueReading>,
+}
+
+impl SensorReading {
+    pub fn new(
+        name: &str,
+        sensor_type: &[String],
+        location: &str,
+        timestamp_ms: u64,
+        wall_clock: bool,
+        values: impl IntoIterator<Item = ValueReading>,
+    ) -> Self {
+        let mut values: Vec<ValueReading> = values.into_iter().collect();
+        values.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Self {
+            name: name.to_string(),
+            sensor_type: sensor_type.to_vec(),
+            location: location.to_string(),
+            timestamp_ms,
+            wall_clock,
+            values,
+        }
+    }
+
+    /// The sensor types as the single string of the protobuf message, e.g. "temperature,humidity"
+    pub fn sensor_type_label(&self) -> String {
+        self.sensor_type.join(",")
+    }
+}
+
+/// Find the reading of a sensor by its name
+pub fn find_reading<'a>(readings: &'a [SensorReading], name: &str) -> Option<&'a SensorReading> {
+    readings.iter().find(|reading| reading.name == name)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WifiInfo {
+    pub connected: bool,
+    pub ssid: String,
+    pub rssi: i8,
+    pub channel: u8,
+}
+
+/// The reply of /api/v1/device
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeviceInfo {
+    pub id: String,
+    pub firmware_version: String,
+    pub hostname: String,
+    pub ip: Option<String>,
+    pub uptime_ms: u64,
+    pub time_valid: bool,
+    pub free_heap: u32,
+    pub wifi: WifiInfo,
+}
+
+/// A remote log entry
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LogRecord {
+    pub level: String,
+    pub target: String,
+    pub message: String,
+    /// Milliseconds since the Unix epoch if wall_clock is set, since boot otherwise
+    pub timestamp_ms: u64,
+    pub wall_clock: bool,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub task_name: String,
+}
+
+/// The reply of /api/v1/logs
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LogPage {
+    pub entries: Vec<LogRecord>,
+    /// Pass it as `since` to get the next entries
+    pub next_seq: u64,
+    /// Total number of log entries overwritten since boot, whether a client read them or not
+    pub overwritten: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HeapInfo {
+    pub free: u32,
+    /// The lowest free heap since boot
+    pub min_free: u32,
+    pub largest_free_block: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TaskInfo {
+    pub name: String,
+    pub priority: u32,
+    /// e.g. "running" or "blocked"
+    pub state: String,
+    /// The least free stack since the task started, in bytes
+    pub stack_high_water_mark: u32,
+}
+
+/// The reply of /api/v1/diagnostics
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostics {
+    pub heap: HeapInfo,
+    /// Sorted by name
+    pub tasks: Vec<TaskInfo>,
+}
+
+/// The reply of /api/v1/diagnostics/i2c
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct I2cScan {
+    /// The 7-bit addresses which answered
+    pub addresses: Vec<u8>,
+}
+
+/// The reply of /api/v1/health, also pushed to the WebSocket clients
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Health {
+    pub uptime_ms: u64,
+    /// Why the device last restarted, e.g. "power-on" or "panic"
+    pub reset_reason: String,
+    pub heap: HeapInfo,
+    pub tasks: Vec<TaskInfo>,
+    /// None when Wi-Fi is not connected
+    pub rssi: Option<i8>,
+    /// None on the chips without a temperature sensor
+    pub cpu_temperature: Option<f32>,
+    pub ws_clients: u32,
+    /// The open HTTP connections, the WebSocket connections excluded
+    pub http_clients: u32,
+}
+
+/// A measurement of a battery node, the sink receives an array of them
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SinkSample {
+    /// Milliseconds since the Unix epoch if wall_clock is set, since power-on otherwise,
+    /// the RTC keeps counting through the deep sleep
+    pub timestamp_ms: u64,
+    pub wall_clock: bool,
+    /// The values by sensor name, then by value name
+    pub sensors: BTreeMap<String, BTreeMap<String, f32>>,
+}
+
+impl SinkSample {
+    pub fn new(readings: &[SensorReading], timestamp_ms: u64, wall_clock: bool) -> Self {
+        Self {
+            timestamp_ms,
+            wall_clock,
+            sensors: readings
+                .iter()
+                .map(|reading| {
+                    let values = reading
+                        .values
+                        .iter()
+                        .map(|value| (value.name.clone(), value.value))
+                        .collect();
+                    (reading.name.clone(), values)
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn value(name: &str, value: f32, raw_value: f32) -> ValueReading {
+        ValueReading {
+            name: name.into(),
+            value,
+            raw_value,
+            unit: "%".into(),
+            filtered: value != raw_value,
+        }
+    }
+
+    fn reading() -> SensorReading {
+        SensorReading::new(
+            "BME280",
+            &["temperature".into(), "humidity".into()],
+            "room1",
+            1_700_000_000_000,
+            true,
+            vec![
+                value("temperature", 21.5, 21.25),
+                value("humidity", 40.0, 40.0),
+            ],
+        )
+    }
+
+    #[test]
+    fn values_are_sorted_by_name() {
+        let reading = reading();
+        let names: Vec<&str> = reading.values.iter().map(|v| v.name.as_str()).collect();
+
+        assert_eq!(names, vec!["humidity", "temperature"]);
+    }
+
+    #[test]
+    fn sensor_type_label_joins_the_types() {
+        assert_eq!(reading().sensor_type_label(), "temperature,humidity");
+    }
+
+    #[test]
+    fn find_reading_by_name() {
+        let readings = vec![reading()];
+
+        assert_eq!(find_reading(&readings, "BME280"), Some(&readings[0]));
+        assert_eq!(find_reading(&readings, "GY30"), None);
+    }
+
+    #[test]
+    fn sensor_reading_json_shape() {
+        assert_eq!(
+            serde_json::to_value(reading()).unwrap(),
+            json!({
+                "name": "BME280",
+                "sensor_type": ["temperature", "humidity"],
+                "location": "room1",
+                "timestamp_ms": 1_700_000_000_000_u64,
+                "wall_clock": true,
+                "values": [
+                    {"name": "humidity", "value": 40.0, "raw_value": 40.0, "unit": "%", "filtered": false},
+                    {"name": "temperature", "value": 21.5, "raw_value": 21.25, "unit": "%", "filtered": true},
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn missing_options_are_null() {
+        let heap = HeapInfo {
+            free: 100_000,
+            min_free: 80_000,
+            largest_free_block: 60_000,
+        };
+        let health = Health {
+            uptime_ms: 5000,
+            reset_reason: "power-on".into(),
+            heap,
+            tasks: vec![TaskInfo {
+                name: "main".into(),
+                priority: 1,
+                state: "running".into(),
+                stack_high_water_mark: 2048,
+            }],
+            rssi: None,
+            cpu_temperature: None,
+            ws_clients: 1,
+            http_clients: 0,
+        };
+
+        assert_eq!(
+            serde_json::to_value(&health).unwrap(),
+            json!({
+                "uptime_ms": 5000,
+                "reset_reason": "power-on",
+                "heap": {"free": 100_000, "min_free": 80_000, "largest_free_block": 60_000},
+                "tasks": [{"name": "main", "priority": 1, "state": "running", "stack_high_water_mark": 2048}],
+                "rssi": null,
+                "cpu_temperature": null,
+                "ws_clients": 1,
+                "http_clients": 0,
+            })
+        );
+
+        let health = Health {
+            rssi: Some(-61),
+            cpu_temperature: Some(42.5),
+            ..health
+        };
+        let json = serde_json::to_value(&health).unwrap();
+        assert_eq!(json["rssi"], json!(-61));
+        assert_eq!(json["cpu_temperature"], json!(42.5));
+    }
+
+    #[test]
+    fn sink_sample_groups_the_values_by_sensor() {
+        let mut other = reading();
+        other.name = "GY30".into();
+        other.values = vec![value("light", 350.0, 350.0)];
+
+        let sample = SinkSample::new(&[reading(), other], 1234, false);
+
+        assert_eq!(
+            serde_json::to_value(&sample).unwrap(),
+            json!({
+                "timestamp_ms": 1234,
+                "wall_clock": false,
+                "sensors": {
+                    "BME280": {"humidity": 40.0, "temperature": 21.5},
+                    "GY30": {"light": 350.0},
+                },
+            })
+        );
+    }
+}
@@ -0,0 +1,68 @@
+use std::sync::Mutex;
+
+use anyhow::Error;
+use lazy_static::lazy_static;
+use log::info;
+
+use crate::auth_check::AuthConfig;
+use crate::storage::{storage_load, storage_store};
+
+const AUTH_CONFIG_STORAGE_KEY: &str = "auth";
+
+lazy_static! {
+    static ref AUTH_CONFIG: Mutex<AuthConfig> = Mutex::new(AuthConfig::default());
+}
+
+/// Load the client credentials from NVS
+pub fn auth_config_load() {
+    let config = storage_load::<AuthConfig>(AUTH_CONFIG_STORAGE_KEY)
+        .filter(|config| config.validate().is_ok())
+        .unwrap_or_default();
+
+    info!(
+        "Authentication {}",
+        if config.enabled() {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+    *AUTH_CONFIG.lock().unwrap() = config;
+}
+
+/// Set and persist the client credentials, empty ones turn the authentication off
+pub fn auth_config_set(config: AuthConfig) -> Result<(), Error> {
+    config.validate()?;
+    storage_store(AUTH_CONFIG_STORAGE_KEY, &config)?;
+
+    info!(
+        "Authentication {}",
+        if config.enabled() {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+    *AUTH_CONFIG.lock().unwrap() = config;
+
+    Ok(())
+}
+
+/// The authentication state reported to the clients, the secrets are never sent
+/// # Returns
+/// * Whether a token is set and the Basic authentication user name
+pub fn auth_status() -> (bool, String) {
+    let config = AUTH_CONFIG.lock().unwrap();
+    (!config.token.is_empty(), config.username.clone())
+}
+
+/// Check the credentials of a request
+/// # Arguments
+/// * `authorization` - The value of the Authorization header
+/// * `query_token` - The `token` query parameter
+pub fn auth_check(authorization: Option<&str>, query_token: Option<&str>) -> bool {
+    AUTH_CONFIG
+        .lock()
+        .unwrap()
+        .authorize(authorization, query_token)
+}
@@ -0,0 +1,228 @@
+use anyhow::{anyhow, Error};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+const MIN_TOKEN_LEN: usize = 16;
+const MAX_CREDENTIAL_LEN: usize = 64;
+
+/// The credentials of the HTTP and WebSocket clients, authentication is off when
+/// neither a token nor a user name is set
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AuthConfig {
+    /// Accepted as `Authorization: Bearer <token>` or as the `token` query parameter
+    #[serde(default)]
+    pub token: String,
+    /// Accepted as HTTP Basic authentication
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+}
+
+impl AuthConfig {
+    pub fn enabled(&self) -> bool {
+        !self.token.is_empty() || !self.username.is_empty()
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if !self.token.is_empty()
+            && !(MIN_TOKEN_LEN..=MAX_CREDENTIAL_LEN).contains(&self.token.len())
+        {
+            return Err(anyhow!(
+                "token must be {} to {} bytes long",
+                MIN_TOKEN_LEN,
+                MAX_CREDENTIAL_LEN
+            ));
+        }
+
+        if self.username.contains(':') || self.username.len() > MAX_CREDENTIAL_LEN {
+            return Err(anyhow!(
+                "user name must be at most {} bytes long without ':'",
+                MAX_CREDENTIAL_LEN
+            ));
+        }
+
+        if !self.username.is_empty()
+            && (self.password.is_empty() || self.password.len() > MAX_CREDENTIAL_LEN)
+        {
+            return Err(anyhow!(
+                "password must be 1 to {} bytes long",
+                MAX_CREDENTIAL_LEN
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Check the credentials of a request
+    /// # Arguments
+    /// * `authorization` - The value of the Authorization header
+    /// * `query_token` - The `token` query parameter, for the browser WebSockets
+    ///   which cannot set headers
+    pub fn authorize(&self, authorization: Option<&str>, query_token: Option<&str>) -> bool {
+        if !self.enabled() {
+            return true;
+        }
+
+        if !self.token.is_empty() {
+            if let Some(token) = query_token {
+                if constant_time_eq(token.as_bytes(), self.token.as_bytes()) {
+                    return true;
+                }
+            }
+        }
+
+        let (scheme, credentials) =
+            match authorization.and_then(|value| value.trim().split_once(' ')) {
+                Some(parts) => parts,
+                None => return false,
+            };
+        let credentials = credentials.trim();
+
+        if scheme.eq_ignore_ascii_case("bearer") {
+            !self.token.is_empty()
+                && constant_time_eq(credentials.as_bytes(), self.token.as_bytes())
+        } else if scheme.eq_ignore_ascii_case("basic") {
+            if self.username.is_empty() {
+                return false;
+            }
+
+            let expected = format!("{}:{}", self.username, self.password);
+            BASE64
+                .decode(credentials)
+                .map(|decoded| constant_time_eq(&decoded, expected.as_bytes()))
+                .unwrap_or(false)
+        } else {
+            false
+        }
+    }
+}
+
+/// Compare without returning early, so the time does not tell how much of a guess matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter()
+        .zip(b.iter())
+        .fold(0_u8, |diff, (x, y)| diff | (x ^ y))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    fn token_config() -> AuthConfig {
+        AuthConfig {
+            token: TOKEN.into(),
+            ..Default::default()
+        }
+    }
+
+    fn basic_config() -> AuthConfig {
+        AuthConfig {
+            username: "admin".into(),
+            password: "secret".into(),
+            ..Default::default()
+        }
+    }
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", BASE64.encode(credentials))
+    }
+
+    #[test]
+    fn everything_is_allowed_without_credentials() {
+        let config = AuthConfig::default();
+
+        assert!(!config.enabled());
+        assert!(config.authorize(None, None));
+        assert!(config.authorize(Some("Bearer anything"), None));
+    }
+
+    #[test]
+    fn bearer_token() {
+        let config = token_config();
+
+        assert!(config.authorize(Some(&format!("Bearer {}", TOKEN)), None));
+        assert!(config.authorize(Some(&format!("bearer  {} ", TOKEN)), None));
+        assert!(!config.authorize(Some("Bearer 0123456789abcdeF"), None));
+        assert!(!config.authorize(Some("Bearer 0123456789abcde"), None));
+        assert!(!config.authorize(Some(TOKEN), None));
+        assert!(!config.authorize(None, None));
+    }
+
+    #[test]
+    fn query_token() {
+        let config = token_config();
+
+        assert!(config.authorize(None, Some(TOKEN)));
+        assert!(!config.authorize(None, Some("wrong")));
+        assert!(!config.authorize(None, Some("")));
+    }
+
+    #[test]
+    fn basic_credentials() {
+        let config = basic_config();
+
+        assert!(config.authorize(Some(&basic("admin:secret")), None));
+        assert!(!config.authorize(Some(&basic("admin:wrong")), None));
+        assert!(!config.authorize(Some(&basic("admin")), None));
+        assert!(!config.authorize(Some("Basic not-base64"), None));
+        assert!(!config.authorize(Some("Digest admin:secret"), None));
+    }
+
+    #[test]
+    fn an_empty_token_never_matches() {
+        // Only Basic authentication is configured
+        let config = basic_config();
+
+        assert!(!config.authorize(Some("Bearer "), None));
+        assert!(!config.authorize(Some("Bearer x"), None));
+        assert!(!config.authorize(None, Some("")));
+        assert!(!config.authorize(Some(&basic(":")), None));
+    }
+
+    #[test]
+    fn token_and_basic_together() {
+        let config = AuthConfig {
+            token: TOKEN.into(),
+            ..basic_config()
+        };
+
+        assert!(config.authorize(Some(&format!("Bearer {}", TOKEN)), None));
+        assert!(config.authorize(Some(&basic("admin:secret")), None));
+        assert!(config.authorize(Some("Bearer wrong"), Some(TOKEN)));
+        assert!(!config.authorize(Some("Bearer wrong"), Some("wrong")));
+    }
+
+    #[test]
+    fn validate_lengths() {
+        assert!(AuthConfig::default().validate().is_ok());
+        assert!(token_config().validate().is_ok());
+        assert!(basic_config().validate().is_ok());
+
+        let short_token = AuthConfig {
+            token: "short".into(),
+            ..Default::default()
+        };
+        assert!(short_token.validate().is_err());
+
+        let colon = AuthConfig {
+            username: "ad:min".into(),
+            ..basic_config()
+        };
+        assert!(colon.validate().is_err());
+
+        let no_password = AuthConfig {
+            password: String::new(),
+            ..basic_config()
+        };
+        assert!(no_password.validate().is_err());
+    }
+}
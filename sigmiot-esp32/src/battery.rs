@@ -0,0 +1,160 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Error};
+use embedded_svc::http::client::Client;
+use embedded_svc::io::Write;
+use esp_idf_svc::http::client::{Configuration as HttpClientConfiguration, EspHttpConnection};
+use log::{info, warn};
+
+use crate::api::SinkSample;
+use crate::data_channel::sensor_readings;
+use crate::device::uptime_ms;
+use crate::device_config::device_config_get;
+use crate::network::network_config_load;
+use crate::sensors::SensorManager;
+use crate::sleep_schedule::{sleep_duration_ms, PowerMode, SampleQueue, SleepState};
+use crate::sntp::{sntp_start, sntp_wait_sync};
+use crate::wifi::{wifi_networks_load, Wifi};
+use crate::wifi_supervisor::wifi_connect_best;
+
+/// Size of the queue of unsent samples, the RTC slow memory of the ESP32 is 8 KiB
+const RTC_QUEUE_SIZE: usize = 4096;
+/// How long the first wakes wait for SNTP, the RTC keeps the time afterwards
+const SNTP_WAIT: Duration = Duration::from_secs(5);
+const SINK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Kept through the deep sleep, reset at power-on
+#[link_section = ".rtc.data"]
+static mut RTC_SAMPLES: SampleQueue<RTC_QUEUE_SIZE> = SampleQueue::new();
+#[link_section = ".rtc.data"]
+static mut RTC_STATE: SleepState = SleepState::new();
+
+/// One wake of the battery mode: measure, queue the sample, push the queue when due
+/// and deep-sleep until the next wake
+/// # Arguments
+/// * `sensor_manager` - The sensors, read once
+/// * `wifi` - Started only when the samples are pushed
+pub fn run_battery_cycle(mut sensor_manager: SensorManager, mut wifi: Wifi) -> ! {
+    let config = device_config_get().power;
+
+    // Only this thread runs until the deep sleep
+    let (samples, state) = unsafe {
+        (
+            &mut *std::ptr::addr_of_mut!(RTC_SAMPLES),
+            &mut *std::ptr::addr_of_mut!(RTC_STATE),
+        )
+    };
+
+    info!(
+        "Battery mode wake {}, {} samples queued",
+        state.cycle,
+        samples.len()
+    );
+
+    sensor_manager.measure();
+    sensor_manager.read();
+
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    // The SNTP client is not restarted at every wake, the RTC keeps the synced time
+    let readings = sensor_readings(
+        &sensor_manager.collect_data(),
+        timestamp_ms,
+        state.clock_synced,
+    );
+    let sample = SinkSample::new(&readings, timestamp_ms, state.clock_synced);
+
+    match samples.push(serde_json::to_string(&sample).unwrap().as_bytes()) {
+        Ok(0) => {}
+        Ok(dropped) => warn!("Sample queue full, {} oldest samples dropped", dropped),
+        Err(e) => warn!("Cannot queue the sample: {:?}", e),
+    }
+
+    if state.push_due(&config, samples.len(), samples.is_nearly_full()) {
+        let sent = connect(&mut wifi, state).and_then(|()| push_samples(&config.sink_url, samples));
+
+        match sent {
+            Ok(()) => {
+                info!("{} samples pushed to {}", samples.len(), config.sink_url);
+                samples.clear();
+            }
+            Err(e) => warn!(
+                "Cannot push the samples, keeping {}: {:?}",
+                samples.len(),
+                e
+            ),
+        }
+        state.push_done(sent.is_ok());
+    }
+
+    state.next_cycle();
+    drop(wifi);
+
+    let sleep_ms = sleep_duration_ms(config.wake_interval_secs, uptime_ms());
+    info!("Deep sleep for {} ms", sleep_ms);
+
+    unsafe { esp_idf_sys::esp_deep_sleep(sleep_ms * 1000) }
+}
+
+/// Connect to the best known network, and sync the clock once
+fn connect(wifi: &mut Wifi, state: &mut SleepState) -> Result<(), Error> {
+    let network_config = network_config_load();
+    wifi.configure_network(
+        &network_config.effective_hostname(),
+        network_config.static_ip.as_ref(),
+    )?;
+
+    wifi_connect_best(wifi, &wifi_networks_load())?;
+
+    if !state.clock_synced {
+        sntp_start()?;
+        state.clock_synced = sntp_wait_sync(SNTP_WAIT);
+    }
+
+    Ok(())
+}
+
+/// Post the queued samples to the sink as a JSON array
+fn push_samples<const N: usize>(sink_url: &str, samples: &SampleQueue<N>) -> Result<(), Error> {
+    // The records are JSON documents already
+    let mut body = b"[".to_vec();
+    for (i, sample) in samples.iter().enumerate() {
+        if i > 0 {
+            body.push(b',');
+        }
+        body.extend_from_slice(sample);
+    }
+    body.push(b']');
+
+    let connection = EspHttpConnection::new(&HttpClientConfiguration {
+        timeout: Some(SINK_TIMEOUT),
+        crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+    let mut client = Client::wrap(connection);
+
+    let content_length = body.len().to_string();
+    let headers = [
+        ("Content-Type", "application/json"),
+        ("Content-Length", content_length.as_str()),
+    ];
+
+    let mut request = client.post(sink_url, &headers)?;
+    request.write_all(&body)?;
+    request.flush()?;
+
+    let status = request.submit()?.status();
+    if !(200..300).contains(&status) {
+        return Err(anyhow!("the sink answered with status {}", status));
+    }
+
+    Ok(())
+}
+
+/// Check if the device runs in battery mode, read from the device config.
+/// Without a known network it stays on so that it can be provisioned.
+pub fn battery_mode() -> bool {
+    device_config_get().power.mode == PowerMode::DeepSleep && !wifi_networks_load().is_empty()
+}
@@ -0,0 +1,163 @@
+use std::sync::Mutex;
+
+use anyhow::Error;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::sensors::SensorData;
+use crate::storage::{storage_load, storage_store};
+
+const CALIBRATION_STORAGE_KEY: &str = "calibration";
+
+/// A correction applied to a raw sensor value
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Correction {
+    /// Add a constant to the raw value
+    Offset(f32),
+    /// Multiply the raw value by a constant
+    Gain(f32),
+    /// Interpolate between (raw, actual) reference points.
+    /// Values outside of the points are extrapolated from the outermost segments.
+    Piecewise(Vec<(f32, f32)>),
+}
+
+impl Correction {
+    /// Apply the correction to a raw value
+    pub fn apply(&self, raw: f32) -> f32 {
+        match self {
+            Correction::Offset(offset) => raw + offset,
+            Correction::Gain(gain) => raw * gain,
+            Correction::Piecewise(points) => piecewise_linear(points, raw),
+        }
+    }
+
+    /// Sort the reference points of a piecewise correction by the raw value
+    fn normalize(&mut self) {
+        if let Correction::Piecewise(points) = self {
+            points.sort_by(|a, b| a.0.total_cmp(&b.0));
+            points.dedup_by(|a, b| a.0 == b.0);
+        }
+    }
+}
+
+fn piecewise_linear(points: &[(f32, f32)], raw: f32) -> f32 {
+    match points.len() {
+        0 => raw,
+        1 => raw + (points[0].1 - points[0].0),
+        len => {
+            // Index of the segment containing the raw value, clamped to the outermost ones
+            let segment = points
+                .windows(2)
+                .position(|w| raw <= w[1].0)
+                .unwrap_or(len - 2);
+
+            let (x0, y0) = points[segment];
+            let (x1, y1) = points[segment + 1];
+
+            y0 + (raw - x0) * (y1 - y0) / (x1 - x0)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CalibrationEntry {
+    pub sensor_name: String,
+    pub value_name: String,
+    pub correction: Correction,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CalibrationTable {
+    entries: Vec<CalibrationEntry>,
+}
+
+impl CalibrationTable {
+    fn find(&self, sensor_name: &str, value_name: &str) -> Option<&CalibrationEntry> {
+        self.entries
+            .iter()
+            .find(|e| e.sensor_name == sensor_name && e.value_name == value_name)
+    }
+
+    fn set(&mut self, mut entry: CalibrationEntry) {
+        entry.correction.normalize();
+        self.clear(&entry.sensor_name, &entry.value_name);
+        self.entries.push(entry);
+    }
+
+    fn clear(&mut self, sensor_name: &str, value_name: &str) -> bool {
+        let len = self.entries.len();
+        self.entries
+            .retain(|e| !(e.sensor_name == sensor_name && e.value_name == value_name));
+        len != self.entries.len()
+    }
+}
+
+lazy_static! {
+    static ref CALIBRATION: Mutex<CalibrationTable> = Mutex::new(CalibrationTable::default());
+}
+
+/// Load the calibration table from NVS
+pub fn calibration_load() {
+    match storage_load::<CalibrationTable>(CALIBRATION_STORAGE_KEY) {
+        Some(table) => {
+            info!("Loaded {} calibration entries", table.entries.len());
+            *CALIBRATION.lock().unwrap() = table;
+        }
+        None => info!("No calibration stored, using raw values"),
+    }
+}
+
+fn calibration_save(table: &CalibrationTable) -> Result<(), Error> {
+    storage_store(CALIBRATION_STORAGE_KEY, table)
+}
+
+/// Set the correction for a sensor value and persist it
+pub fn calibration_set(entry: CalibrationEntry) -> Result<(), Error> {
+    let mut table = CALIBRATION.lock().unwrap();
+    info!(
+        "Calibration of {}/{} set to {:?}",
+        entry.sensor_name, entry.value_name, entry.correction
+    );
+    table.set(entry);
+    calibration_save(&table)
+}
+
+/// Remove the correction of a sensor value and persist the change
+/// # Returns
+/// * Ok(false) if the sensor value had no correction
+pub fn calibration_clear(sensor_name: &str, value_name: &str) -> Result<bool, Error> {
+    let mut table = CALIBRATION.lock().unwrap();
+    if !table.clear(sensor_name, value_name) {
+        return Ok(false);
+    }
+
+    calibration_save(&table)?;
+    Ok(true)
+}
+
+/// Get all the configured corrections
+pub fn calibration_get_all() -> Vec<CalibrationEntry> {
+    CALIBRATION.lock().unwrap().entries.clone()
+}
+
+/// Apply the configured corrections to the raw values of the sensor data
+pub fn calibration_apply(data: &mut SensorData) {
+    let table = CALIBRATION.lock().unwrap();
+    let sensor_name = data.get_name().clone();
+
+    for value in data.get_values_mut() {
+        value.value = match table.find(&sensor_name, &value.value_name) {
+            Some(entry) => entry.correction.apply(value.raw_value),
+            None => value.raw_value,
+        };
+
+        if !value.value.is_finite() {
+            warn!(
+                "Calibration of {}/{} produced {}, using raw value",
+                sensor_name, value.value_name, value.value
+            );
+            value.value = value.raw_value;
+        }
+    }
+}
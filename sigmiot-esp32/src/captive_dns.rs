@@ -0,0 +1,94 @@
+use std::net::{Ipv4Addr, UdpSocket};
+
+const DNS_HEADER_LEN: usize = 12;
+const DNS_TYPE_A: u16 = 1;
+const DNS_TYPE_ANY: u16 = 255;
+const DNS_CLASS_IN: u16 = 1;
+const DNS_ANSWER_TTL: u32 = 60;
+
+/// Build the answer to a DNS query resolving every name to the same address
+/// # Arguments
+/// * `query` - The received DNS packet
+/// * `ip` - The address returned for A queries
+/// # Returns
+/// * None if the packet is not a standard query with a question
+pub fn dns_answer(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    if query.len() < DNS_HEADER_LEN {
+        return None;
+    }
+
+    let flags = u16::from_be_bytes([query[2], query[3]]);
+    let is_response = flags & 0x8000 != 0;
+    let opcode = (flags >> 11) & 0xf;
+    let questions = u16::from_be_bytes([query[4], query[5]]);
+    if is_response || opcode != 0 || questions == 0 {
+        return None;
+    }
+
+    // Skip the labels of the first question name
+    let mut pos = DNS_HEADER_LEN;
+    loop {
+        let len = *query.get(pos)? as usize;
+        if len == 0 {
+            pos += 1;
+            break;
+        }
+        if len & 0xc0 != 0 {
+            // Compressed names are not expected in a question
+            return None;
+        }
+        pos += 1 + len;
+    }
+
+    let qtype = u16::from_be_bytes([*query.get(pos)?, *query.get(pos + 1)?]);
+    let qclass = u16::from_be_bytes([*query.get(pos + 2)?, *query.get(pos + 3)?]);
+    let question_end = pos + 4;
+
+    let answer = (qtype == DNS_TYPE_A || qtype == DNS_TYPE_ANY) && qclass == DNS_CLASS_IN;
+
+    let mut response = Vec::with_capacity(question_end + 16);
+    // Same id, response with recursion desired copied and recursion available
+    response.extend_from_slice(&query[0..2]);
+    response.extend_from_slice(&(0x8080 | (flags & 0x0100)).to_be_bytes());
+    response.extend_from_slice(&1_u16.to_be_bytes());
+    response.extend_from_slice(&(answer as u16).to_be_bytes());
+    response.extend_from_slice(&0_u32.to_be_bytes());
+    response.extend_from_slice(&query[DNS_HEADER_LEN..question_end]);
+
+    if answer {
+        // Pointer to the question name
+        response.extend_from_slice(&0xc00c_u16.to_be_bytes());
+        response.extend_from_slice(&DNS_TYPE_A.to_be_bytes());
+        response.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+        response.extend_from_slice(&DNS_ANSWER_TTL.to_be_bytes());
+        response.extend_from_slice(&4_u16.to_be_bytes());
+        response.extend_from_slice(&ip.octets());
+    }
+
+    Some(response)
+}
+
+/// Answer every DNS query on port 53 with the given address, so the clients
+/// of the access point open the provisioning page whatever they browse to
+pub fn captive_dns_start(ip: Ipv4Addr) -> std::io::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 53))?;
+
+    std::thread::Builder::new()
+        .name("captive_dns".into())
+        .stack_size(4096)
+        .spawn(move || {
+            let mut buf = [0_u8; 512];
+            loop {
+                let (size, peer) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(_) => continue,
+                };
+
+                if let Some(response) = dns_answer(&buf[..size], ip) {
+                    let _ = socket.send_to(&response, peer);
+                }
+            }
+        })?;
+
+    Ok(())
+}
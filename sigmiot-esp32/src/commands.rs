@@ -0,0 +1,835 @@
+use log::{info, warn};
+use protobuf::{EnumOrUnknown, Message};
+
+use crate::alerts::{alerts_add_rule, alerts_get_rules, alerts_remove_rule, AlertRule, Condition};
+use crate::api::{Health, HeapInfo, TaskInfo};
+use crate::auth::auth_config_set;
+use crate::auth_check::AuthConfig;
+use crate::calibration::{
+    calibration_clear, calibration_get_all, calibration_set, CalibrationEntry, Correction,
+};
+use crate::data_channel::reading_to_proto;
+use crate::data_channel::sigmiot_data::{
+    self, alert_rule, calibration, filter_spec, message_request, message_response, Calibration,
+    CalibrationPoint, FilterSpec, HistoryResponse, HistorySeries, MadFilter, MessageRequest,
+    MessageResponse, PiecewiseCorrection, TimeSyncStatus,
+};
+use crate::device::{device_id, device_restart};
+use crate::device_config::{device_config_export, device_config_import};
+use crate::diagnostics::{diagnostics, i2c_scan};
+use crate::filters::{filters_get_all, filters_set, FilterConfig, FilterKind};
+use crate::history::history_query;
+use crate::log_buffer::LogCursor;
+use crate::network::{network_config_load, network_config_store, NetworkConfig, StaticIpConfig};
+use crate::ota::{ota_abort, ota_begin, ota_finish, ota_progress, ota_write};
+use crate::ota_image::{OtaManifest, OtaProgress};
+use crate::sensors::sensors_force_read;
+use crate::sigmiot_log::{
+    log_levels_get, log_levels_set, remote_logger_oldest, LogFilter, LogLevelConfig, TargetLogLevel,
+};
+use crate::sntp::{time_config_set, time_sync_info};
+use crate::storage::storage_erase_all;
+use crate::time_sync::{TimeConfig, TimeStatus};
+use crate::wifi::{wifi_networks_add, wifi_networks_load, wifi_networks_remove};
+use crate::wifi_policy::KnownNetwork;
+use crate::wifi_supervisor::{wifi_status, WifiStatus};
+
+/// The state of a connected client which its requests can change
+pub struct ClientSession {
+    /// Position of the client in the remote log
+    pub log_cursor: LogCursor,
+    pub log_filter: LogFilter,
+    /// Sequence number of the last Wi-Fi status sent to the client
+    pub wifi_status_seq: u64,
+    /// Uptime when the device health was last sent to the client
+    pub health_sent_ms: Option<u64>,
+}
+
+impl ClientSession {
+    pub fn new(log_cursor: LogCursor) -> Self {
+        Self {
+            log_cursor,
+            log_filter: LogFilter::default(),
+            wifi_status_seq: 0,
+            health_sent_ms: None,
+        }
+    }
+}
+
+/// Handle a serialized MessageRequest received from a client
+/// # Arguments
+/// * `data` - The serialized request
+/// * `session` - The state of the client which sent the request
+/// # Returns
+/// * The serialized MessageResponse to send back
+pub async fn handle_request(data: &[u8], session: &mut ClientSession) -> Vec<u8> {
+    let response = match MessageRequest::parse_from_bytes(data) {
+        Ok(request) => dispatch(request, session).await,
+        Err(e) => {
+            warn!("Cannot parse MessageRequest: {:?}", e);
+            error_response(message_response::Status::ERR, "malformed request")
+        }
+    };
+
+    response.write_to_bytes().unwrap()
+}
+
+async fn dispatch(request: MessageRequest, session: &mut ClientSession) -> MessageResponse {
+    match request.request {
+        Some(message_request::Request::SetCalibration(req)) => set_calibration(req),
+        Some(message_request::Request::ClearCalibration(req)) => clear_calibration(req),
+        Some(message_request::Request::GetCalibration(_)) => get_calibration(),
+        Some(message_request::Request::AddAlertRule(req)) => add_alert_rule(req),
+        Some(message_request::Request::RemoveAlertRule(req)) => remove_alert_rule(req),
+        Some(message_request::Request::GetAlertRules(_)) => get_alert_rules(),
+        Some(message_request::Request::SetFilters(req)) => set_filters(req),
+        Some(message_request::Request::GetFilters(_)) => get_filters(),
+        Some(message_request::Request::GetHistory(req)) => get_history(req),
+        Some(message_request::Request::SubscribeLogs(req)) => subscribe_logs(req, session),
+        Some(message_request::Request::SetTimeConfig(req)) => set_time_config(req),
+        Some(message_request::Request::GetTimeStatus(_)) => get_time_status(),
+        Some(message_request::Request::SetLogLevels(req)) => set_log_levels(req),
+        Some(message_request::Request::GetLogLevels(_)) => get_log_levels(),
+        Some(message_request::Request::AddWifiNetwork(req)) => add_wifi_network(req),
+        Some(message_request::Request::RemoveWifiNetwork(req)) => remove_wifi_network(req),
+        Some(message_request::Request::GetWifiStatus(_)) => get_wifi_status(),
+        Some(message_request::Request::SetNetworkConfig(req)) => set_network_config(req),
+        Some(message_request::Request::GetNetworkConfig(_)) => get_network_config(),
+        Some(message_request::Request::SetAuthConfig(req)) => set_auth_config(req),
+        Some(message_request::Request::GetDeviceConfig(_)) => get_device_config(),
+        Some(message_request::Request::SetDeviceConfig(req)) => set_device_config(req),
+        Some(message_request::Request::OtaBegin(req)) => ota_begin_request(req),
+        Some(message_request::Request::OtaChunk(req)) => ota_chunk_request(req),
+        Some(message_request::Request::OtaEnd(_)) => ota_end_request(),
+        Some(message_request::Request::OtaAbort(_)) => {
+            ota_abort();
+            ok_response()
+        }
+        Some(message_request::Request::Reboot(_)) => {
+            info!("Reboot requested");
+            device_restart();
+            ok_response()
+        }
+        Some(message_request::Request::FactoryReset(_)) => factory_reset(),
+        Some(message_request::Request::I2cScan(_)) => i2c_scan_request(),
+        Some(message_request::Request::ForceRead(_)) => force_read().await,
+        Some(message_request::Request::GetDiagnostics(_)) => get_diagnostics(),
+        None => error_response(message_response::Status::ERR, "empty request"),
+    }
+}
+
+fn ok_response() -> MessageResponse {
+    let mut response = MessageResponse::new();
+    response.status = EnumOrUnknown::new(message_response::Status::OK);
+    response
+}
+
+fn error_response(status: message_response::Status, message: &str) -> MessageResponse {
+    let mut response = MessageResponse::new();
+    response.status = EnumOrUnknown::new(status);
+    response.status_message = message.to_string();
+    response
+}
+
+fn set_calibration(req: sigmiot_data::SetCalibrationRequest) -> MessageResponse {
+    let entry = match req.calibration.into_option().map(calibration_from_proto) {
+        Some(Ok(entry)) => entry,
+        Some(Err(e)) => return error_response(message_response::Status::ERR, e),
+        None => return error_response(message_response::Status::ERR, "no calibration given"),
+    };
+
+    info!(
+        "Set calibration request for {}/{}",
+        entry.sensor_name, entry.value_name
+    );
+
+    match calibration_set(entry) {
+        Ok(()) => get_calibration(),
+        Err(e) => error_response(message_response::Status::ERR, &e.to_string()),
+    }
+}
+
+fn clear_calibration(req: sigmiot_data::ClearCalibrationRequest) -> MessageResponse {
+    match calibration_clear(&req.sensor_name, &req.value_name) {
+        Ok(true) => get_calibration(),
+        Ok(false) => error_response(message_response::Status::NOT_FOUND, "no such calibration"),
+        Err(e) => error_response(message_response::Status::ERR, &e.to_string()),
+    }
+}
+
+fn get_calibration() -> MessageResponse {
+    let mut response = ok_response();
+    response.calibrations = calibration_get_all()
+        .into_iter()
+        .map(calibration_to_proto)
+        .collect();
+    response
+}
+
+fn calibration_from_proto(calibration: Calibration) -> Result<CalibrationEntry, &'static str> {
+    if calibration.sensor_name.is_empty() || calibration.value_name.is_empty() {
+        return Err("sensor and value name are required");
+    }
+
+    let correction = match calibration.correction {
+        Some(calibration::Correction::Offset(offset)) => Correction::Offset(offset),
+        Some(calibration::Correction::Gain(gain)) => Correction::Gain(gain),
+        Some(calibration::Correction::Piecewise(piecewise)) => {
+            if piecewise.points.is_empty() {
+                return Err("piecewise correction needs at least one point");
+            }
+
+            Correction::Piecewise(piecewise.points.iter().map(|p| (p.raw, p.actual)).collect())
+        }
+        None => return Err("no correction given"),
+    };
+
+    Ok(CalibrationEntry {
+        sensor_name: calibration.sensor_name,
+        value_name: calibration.value_name,
+        correction,
+    })
+}
+
+fn calibration_to_proto(entry: CalibrationEntry) -> Calibration {
+    let mut calibration = Calibration::new();
+    calibration.sensor_name = entry.sensor_name;
+    calibration.value_name = entry.value_name;
+    calibration.correction = Some(match entry.correction {
+        Correction::Offset(offset) => calibration::Correction::Offset(offset),
+        Correction::Gain(gain) => calibration::Correction::Gain(gain),
+        Correction::Piecewise(points) => {
+            let mut piecewise = PiecewiseCorrection::new();
+            piecewise.points = points
+                .into_iter()
+                .map(|(raw, actual)| {
+                    let mut point = CalibrationPoint::new();
+                    point.raw = raw;
+                    point.actual = actual;
+                    point
+                })
+                .collect();
+            calibration::Correction::Piecewise(piecewise)
+        }
+    });
+    calibration
+}
+
+fn add_alert_rule(req: sigmiot_data::AddAlertRuleRequest) -> MessageResponse {
+    let rule = match req.rule.into_option().map(alert_rule_from_proto) {
+        Some(Ok(rule)) => rule,
+        Some(Err(e)) => return error_response(message_response::Status::ERR, e),
+        None => return error_response(message_response::Status::ERR, "no rule given"),
+    };
+
+    match alerts_add_rule(rule) {
+        Ok(()) => get_alert_rules(),
+        Err(e) => error_response(message_response::Status::ERR, &e.to_string()),
+    }
+}
+
+fn remove_alert_rule(req: sigmiot_data::RemoveAlertRuleRequest) -> MessageResponse {
+    match alerts_remove_rule(&req.rule_id) {
+        Ok(true) => get_alert_rules(),
+        Ok(false) => error_response(message_response::Status::NOT_FOUND, "no such rule"),
+        Err(e) => error_response(message_response::Status::ERR, &e.to_string()),
+    }
+}
+
+fn get_alert_rules() -> MessageResponse {
+    let mut response = ok_response();
+    response.alert_rules = alerts_get_rules()
+        .into_iter()
+        .map(alert_rule_to_proto)
+        .collect();
+    response
+}
+
+fn alert_rule_from_proto(rule: sigmiot_data::AlertRule) -> Result<AlertRule, &'static str> {
+    let condition = match rule.condition.enum_value() {
+        Ok(alert_rule::Condition::ABOVE) => Condition::Above,
+        Ok(alert_rule::Condition::BELOW) => Condition::Below,
+        Ok(alert_rule::Condition::RATE_OF_CHANGE) => Condition::RateOfChange,
+        Err(_) => return Err("unknown condition"),
+    };
+
+    Ok(AlertRule {
+        rule_id: rule.rule_id,
+        sensor_name: rule.sensor_name,
+        value_name: rule.value_name,
+        condition,
+        threshold: rule.threshold,
+        samples: rule.samples,
+        hysteresis: rule.hysteresis,
+    })
+}
+
+fn alert_rule_to_proto(rule: AlertRule) -> sigmiot_data::AlertRule {
+    let mut proto = sigmiot_data::AlertRule::new();
+    proto.rule_id = rule.rule_id;
+    proto.sensor_name = rule.sensor_name;
+    proto.value_name = rule.value_name;
+    proto.condition = EnumOrUnknown::new(match rule.condition {
+        Condition::Above => alert_rule::Condition::ABOVE,
+        Condition::Below => alert_rule::Condition::BELOW,
+        Condition::RateOfChange => alert_rule::Condition::RATE_OF_CHANGE,
+    });
+    proto.threshold = rule.threshold;
+    proto.samples = rule.samples;
+    proto.hysteresis = rule.hysteresis;
+    proto
+}
+
+fn set_filters(req: sigmiot_data::SetFiltersRequest) -> MessageResponse {
+    let config = match req.config.into_option().map(filter_config_from_proto) {
+        Some(Ok(config)) => config,
+        Some(Err(e)) => return error_response(message_response::Status::ERR, e),
+        None => return error_response(message_response::Status::ERR, "no filter config given"),
+    };
+
+    match filters_set(config) {
+        Ok(()) => get_filters(),
+        Err(e) => error_response(message_response::Status::ERR, &e.to_string()),
+    }
+}
+
+fn get_filters() -> MessageResponse {
+    let mut response = ok_response();
+    response.filters = filters_get_all()
+        .into_iter()
+        .map(filter_config_to_proto)
+        .collect();
+    response
+}
+
+fn filter_config_from_proto(
+    config: sigmiot_data::FilterConfig,
+) -> Result<FilterConfig, &'static str> {
+    if config.sensor_name.is_empty() || config.value_name.is_empty() {
+        return Err("sensor and value name are required");
+    }
+
+    let filters = config
+        .filters
+        .iter()
+        .map(|spec| match &spec.filter {
+            Some(filter_spec::Filter::MovingAverage(window)) => Ok(FilterKind::MovingAverage {
+                window: *window as usize,
+            }),
+            Some(filter_spec::Filter::Exponential(alpha)) => {
+                Ok(FilterKind::Exponential { alpha: *alpha })
+            }
+            Some(filter_spec::Filter::Median(window)) => Ok(FilterKind::Median {
+                window: *window as usize,
+            }),
+            Some(filter_spec::Filter::MadSpikeRejection(mad)) => {
+                Ok(FilterKind::MadSpikeRejection {
+                    window: mad.window as usize,
+                    threshold: mad.threshold,
+                })
+            }
+            Some(filter_spec::Filter::MaxDelta(max_delta)) => Ok(FilterKind::MaxDelta {
+                max_delta: *max_delta,
+            }),
+            None => Err("empty filter"),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(FilterConfig {
+        sensor_name: config.sensor_name,
+        value_name: config.value_name,
+        filters,
+    })
+}
+
+fn filter_config_to_proto(config: FilterConfig) -> sigmiot_data::FilterConfig {
+    let mut proto = sigmiot_data::FilterConfig::new();
+    proto.sensor_name = config.sensor_name;
+    proto.value_name = config.value_name;
+    proto.filters = config
+        .filters
+        .into_iter()
+        .map(|kind| {
+            let mut spec = FilterSpec::new();
+            spec.filter = Some(match kind {
+                FilterKind::MovingAverage { window } => {
+                    filter_spec::Filter::MovingAverage(window as u32)
+                }
+                FilterKind::Exponential { alpha } => filter_spec::Filter::Exponential(alpha),
+                FilterKind::Median { window } => filter_spec::Filter::Median(window as u32),
+                FilterKind::MadSpikeRejection { window, threshold } => {
+                    let mut mad = MadFilter::new();
+                    mad.window = window as u32;
+                    mad.threshold = threshold;
+                    filter_spec::Filter::MadSpikeRejection(mad)
+                }
+                FilterKind::MaxDelta { max_delta } => filter_spec::Filter::MaxDelta(max_delta),
+            });
+            spec
+        })
+        .collect();
+    proto
+}
+
+fn get_history(req: sigmiot_data::HistoryRequest) -> MessageResponse {
+    let result = history_query(req.hours, req.max_points as usize);
+
+    let mut history = HistoryResponse::new();
+    history.timestamps = result.timestamps;
+    history.series = result
+        .series
+        .into_iter()
+        .map(|s| {
+            let mut series = HistorySeries::new();
+            series.sensor_name = s.series.sensor_name;
+            series.value_name = s.series.value_name;
+            series.value_unit = s.series.unit;
+            series.values = s.values;
+            series
+        })
+        .collect();
+
+    let mut response = ok_response();
+    response.history = protobuf::MessageField::some(history);
+    response
+}
+
+fn subscribe_logs(
+    req: sigmiot_data::LogSubscribeRequest,
+    session: &mut ClientSession,
+) -> MessageResponse {
+    match LogFilter::new(&req.min_level, req.target_prefixes) {
+        Ok(filter) => {
+            info!("Log subscription changed to {:?}", filter);
+            session.log_filter = filter;
+            if req.replay {
+                session.log_cursor = remote_logger_oldest();
+            }
+            ok_response()
+        }
+        Err(e) => error_response(message_response::Status::ERR, &e.to_string()),
+    }
+}
+
+fn set_time_config(req: sigmiot_data::SetTimeConfigRequest) -> MessageResponse {
+    let config = match req.config.into_option() {
+        Some(config) => TimeConfig {
+            servers: config.servers,
+            timezone: config.timezone,
+            resync_interval_secs: config.resync_interval_secs,
+        },
+        None => return error_response(message_response::Status::ERR, "no time config given"),
+    };
+
+    match time_config_set(config) {
+        Ok(()) => get_time_status(),
+        Err(e) => error_response(message_response::Status::ERR, &e.to_string()),
+    }
+}
+
+fn get_time_status() -> MessageResponse {
+    let info = time_sync_info();
+
+    let mut config = sigmiot_data::TimeConfig::new();
+    config.servers = info.config.servers;
+    config.timezone = info.config.timezone;
+    config.resync_interval_secs = info.config.resync_interval_secs;
+
+    let mut status = TimeSyncStatus::new();
+    status.state = EnumOrUnknown::new(match info.status {
+        TimeStatus::NotSynced => sigmiot_data::time_sync_status::State::NOT_SYNCED,
+        TimeStatus::Synced => sigmiot_data::time_sync_status::State::SYNCED,
+        TimeStatus::Stale => sigmiot_data::time_sync_status::State::STALE,
+    });
+    status.last_sync_ms = info.last_sync_epoch_ms.unwrap_or(0);
+    status.config = protobuf::MessageField::some(config);
+
+    let mut response = ok_response();
+    response.time_status = protobuf::MessageField::some(status);
+    response
+}
+
+fn set_log_levels(req: sigmiot_data::SetLogLevelsRequest) -> MessageResponse {
+    let config = match req.config.into_option() {
+        Some(config) => LogLevelConfig {
+            max_level: config.max_level,
+            targets: config
+                .targets
+                .into_iter()
+                .map(|target| TargetLogLevel {
+                    target: target.target,
+                    level: target.level,
+                })
+                .collect(),
+        },
+        None => return error_response(message_response::Status::ERR, "no log levels given"),
+    };
+
+    match log_levels_set(config) {
+        Ok(()) => get_log_levels(),
+        Err(e) => error_response(message_response::Status::ERR, &e.to_string()),
+    }
+}
+
+fn get_log_levels() -> MessageResponse {
+    let config = log_levels_get();
+
+    let mut levels = sigmiot_data::LogLevelConfig::new();
+    levels.max_level = config.max_level;
+    levels.targets = config
+        .targets
+        .into_iter()
+        .map(|target| {
+            let mut level = sigmiot_data::TargetLogLevel::new();
+            level.target = target.target;
+            level.level = target.level;
+            level
+        })
+        .collect();
+
+    let mut response = ok_response();
+    response.log_levels = protobuf::MessageField::some(levels);
+    response
+}
+
+fn add_wifi_network(req: sigmiot_data::AddWifiNetworkRequest) -> MessageResponse {
+    let network = match req.network.into_option() {
+        Some(network) => KnownNetwork {
+            ssid: network.ssid,
+            password: network.password,
+            priority: network.priority.min(u8::MAX as u32) as u8,
+        },
+        None => return error_response(message_response::Status::ERR, "no network given"),
+    };
+
+    info!("Add Wi-Fi network request for {}", network.ssid);
+
+    match wifi_networks_add(network) {
+        Ok(()) => get_wifi_status(),
+        Err(e) => error_response(message_response::Status::ERR, &e.to_string()),
+    }
+}
+
+fn remove_wifi_network(req: sigmiot_data::RemoveWifiNetworkRequest) -> MessageResponse {
+    match wifi_networks_remove(&req.ssid) {
+        Ok(true) => get_wifi_status(),
+        Ok(false) => error_response(message_response::Status::NOT_FOUND, "no such network"),
+        Err(e) => error_response(message_response::Status::ERR, &e.to_string()),
+    }
+}
+
+fn get_wifi_status() -> MessageResponse {
+    let mut response = ok_response();
+    response.wifi_status = protobuf::MessageField::some(wifi_status_to_proto(&wifi_status()));
+    response.wifi_networks = wifi_networks_load()
+        .into_iter()
+        .map(|network| {
+            let mut known = sigmiot_data::WifiNetwork::new();
+            known.ssid = network.ssid;
+            known.priority = network.priority as u32;
+            known
+        })
+        .collect();
+    response
+}
+
+pub fn wifi_status_to_proto(status: &WifiStatus) -> sigmiot_data::WifiStatus {
+    let mut proto = sigmiot_data::WifiStatus::new();
+    proto.connected = status.connected;
+    proto.ssid = status.ssid.clone();
+    proto.rssi = status.rssi as i32;
+    proto.channel = status.channel as u32;
+    proto.ip = status.ip.map(|ip| ip.to_string()).unwrap_or_default();
+    proto.reason = status.reason.clone();
+    proto
+}
+
+fn set_network_config(req: sigmiot_data::SetNetworkConfigRequest) -> MessageResponse {
+    let config = match req.config.into_option() {
+        Some(config) => config,
+        None => return error_response(message_response::Status::ERR, "no network config given"),
+    };
+
+    let static_ip = match config.static_ip.into_option() {
+        Some(static_ip) => {
+            let dns = if static_ip.dns.is_empty() {
+                None
+            } else {
+                match static_ip.dns.parse() {
+                    Ok(dns) => Some(dns),
+                    Err(_) => {
+                        return error_response(message_response::Status::ERR, "invalid DNS address")
+                    }
+                }
+            };
+
+            match (static_ip.ip.parse(), static_ip.gateway.parse()) {
+                (Ok(ip), Ok(gateway)) => Some(StaticIpConfig {
+                    ip,
+                    prefix_len: static_ip.prefix_len.min(u8::MAX as u32) as u8,
+                    gateway,
+                    dns,
+                }),
+                _ => {
+                    return error_response(
+                        message_response::Status::ERR,
+                        "invalid IP or gateway address",
+                    )
+                }
+            }
+        }
+        None => None,
+    };
+
+    let config = NetworkConfig {
+        hostname: config.hostname,
+        static_ip,
+    };
+
+    info!("Set network config request: {:?}", config);
+
+    match network_config_store(&config) {
+        Ok(()) => get_network_config(),
+        Err(e) => error_response(message_response::Status::ERR, &e.to_string()),
+    }
+}
+
+fn get_network_config() -> MessageResponse {
+    let config = network_config_load();
+
+    let mut proto = sigmiot_data::NetworkConfig::new();
+    proto.hostname = config.hostname;
+    proto.device_id = device_id();
+    if let Some(static_ip) = config.static_ip {
+        let mut ip = sigmiot_data::StaticIpConfig::new();
+        ip.ip = static_ip.ip.to_string();
+        ip.prefix_len = static_ip.prefix_len as u32;
+        ip.gateway = static_ip.gateway.to_string();
+        ip.dns = static_ip.dns.map(|dns| dns.to_string()).unwrap_or_default();
+        proto.static_ip = protobuf::MessageField::some(ip);
+    }
+
+    let mut response = ok_response();
+    response.network_config = protobuf::MessageField::some(proto);
+    response
+}
+
+fn set_auth_config(req: sigmiot_data::SetAuthConfigRequest) -> MessageResponse {
+    let config = AuthConfig {
+        token: req.token,
+        username: req.username,
+        password: req.password,
+    };
+
+    match auth_config_set(config) {
+        Ok(()) => ok_response(),
+        Err(e) => error_response(message_response::Status::ERR, &e.to_string()),
+    }
+}
+
+fn get_device_config() -> MessageResponse {
+    let mut response = ok_response();
+    response.device_config_json = device_config_export();
+    response
+}
+
+fn set_device_config(req: sigmiot_data::SetDeviceConfigRequest) -> MessageResponse {
+    match device_config_import(req.json.as_bytes()) {
+        Ok(_) => get_device_config(),
+        Err(e) => error_response(message_response::Status::ERR, &e.to_string()),
+    }
+}
+
+fn ota_begin_request(req: sigmiot_data::OtaBeginRequest) -> MessageResponse {
+    let sha256 = match <[u8; 32]>::try_from(req.sha256.as_slice()) {
+        Ok(sha256) => sha256,
+        Err(_) => return error_response(message_response::Status::ERR, "SHA-256 must be 32 bytes"),
+    };
+
+    let manifest = OtaManifest {
+        size: req.size,
+        sha256,
+        signature: req.signature,
+    };
+
+    match ota_begin(manifest) {
+        Ok(progress) => ota_status_response(progress, false),
+        Err(e) => error_response(message_response::Status::ERR, &e.to_string()),
+    }
+}
+
+fn ota_chunk_request(req: sigmiot_data::OtaChunkRequest) -> MessageResponse {
+    match ota_write(req.offset, &req.data) {
+        Ok(progress) => ota_status_response(progress, false),
+        Err(e) => error_response(message_response::Status::ERR, &e.to_string()),
+    }
+}
+
+fn ota_end_request() -> MessageResponse {
+    let progress = match ota_progress() {
+        Some(progress) => progress,
+        None => return error_response(message_response::Status::ERR, "no update in progress"),
+    };
+
+    match ota_finish() {
+        Ok(()) => {
+            device_restart();
+            ota_status_response(progress, true)
+        }
+        Err(e) => error_response(message_response::Status::ERR, &e.to_string()),
+    }
+}
+
+fn ota_status_response(progress: OtaProgress, done: bool) -> MessageResponse {
+    let mut status = sigmiot_data::OtaStatus::new();
+    status.received = progress.received;
+    status.size = progress.size;
+    status.done = done;
+
+    let mut response = ok_response();
+    response.ota_status = protobuf::MessageField::some(status);
+    response
+}
+
+fn factory_reset() -> MessageResponse {
+    warn!("Factory reset requested, erasing the settings");
+
+    match storage_erase_all() {
+        Ok(()) => {
+            device_restart();
+            ok_response()
+        }
+        Err(e) => error_response(message_response::Status::ERR, &e.to_string()),
+    }
+}
+
+fn i2c_scan_request() -> MessageResponse {
+    match i2c_scan() {
+        Ok(scan) => {
+            let mut result = sigmiot_data::I2cScanResult::new();
+            result.addresses = scan.addresses.into_iter().map(u32::from).collect();
+
+            let mut response = ok_response();
+            response.i2c_scan = protobuf::MessageField::some(result);
+            response
+        }
+        Err(e) => error_response(message_response::Status::ERR, &e.to_string()),
+    }
+}
+
+async fn force_read() -> MessageResponse {
+    match sensors_force_read().await {
+        Ok(readings) => {
+            let mut response = ok_response();
+            response.sensors_data_response = readings.iter().map(reading_to_proto).collect();
+            response
+        }
+        Err(e) => error_response(message_response::Status::ERR, &e.to_string()),
+    }
+}
+
+fn get_diagnostics() -> MessageResponse {
+    let diagnostics = diagnostics();
+
+    let mut result = sigmiot_data::Diagnostics::new();
+    result.heap = protobuf::MessageField::some(heap_info_to_proto(&diagnostics.heap));
+    result.tasks = diagnostics.tasks.iter().map(task_info_to_proto).collect();
+
+    let mut response = ok_response();
+    response.diagnostics = protobuf::MessageField::some(result);
+    response
+}
+
+fn heap_info_to_proto(heap: &HeapInfo) -> sigmiot_data::HeapInfo {
+    let mut info = sigmiot_data::HeapInfo::new();
+    info.free = heap.free;
+    info.min_free = heap.min_free;
+    info.largest_free_block = heap.largest_free_block;
+    info
+}
+
+fn task_info_to_proto(task: &TaskInfo) -> sigmiot_data::TaskInfo {
+    let mut info = sigmiot_data::TaskInfo::new();
+    info.name = task.name.clone();
+    info.priority = task.priority;
+    info.state = task.state.clone();
+    info.stack_high_water_mark = task.stack_high_water_mark;
+    info
+}
+
+pub fn health_to_proto(health: &Health) -> sigmiot_data::DeviceHealth {
+    let mut device_health = sigmiot_data::DeviceHealth::new();
+    device_health.uptime_ms = health.uptime_ms;
+    device_health.reset_reason = health.reset_reason.clone();
+    device_health.heap = protobuf::MessageField::some(heap_info_to_proto(&health.heap));
+    device_health.tasks = health.tasks.iter().map(task_info_to_proto).collect();
+    device_health.rssi = health.rssi.map(i32::from);
+    device_health.cpu_temperature = health.cpu_temperature;
+    device_health.ws_clients = health.ws_clients;
+    device_health.http_clients = health.http_clients;
+    device_health
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health() -> Health {
+        Health {
+            uptime_ms: 5000,
+            reset_reason: "power-on".into(),
+            heap: HeapInfo {
+                free: 100_000,
+                min_free: 80_000,
+                largest_free_block: 60_000,
+            },
+            tasks: vec![TaskInfo {
+                name: "main".into(),
+                priority: 1,
+                state: "running".into(),
+                stack_high_water_mark: 2048,
+            }],
+            rssi: Some(-61),
+            cpu_temperature: Some(42.5),
+            ws_clients: 2,
+            http_clients: 1,
+        }
+    }
+
+    fn round_trip(health: &Health) -> sigmiot_data::DeviceHealth {
+        let bytes = health_to_proto(health).write_to_bytes().unwrap();
+        sigmiot_data::DeviceHealth::parse_from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn health_survives_the_protobuf_round_trip() {
+        let decoded = round_trip(&health());
+
+        assert_eq!(decoded.uptime_ms, 5000);
+        assert_eq!(decoded.reset_reason, "power-on");
+        assert_eq!(decoded.heap.free, 100_000);
+        assert_eq!(decoded.heap.min_free, 80_000);
+        assert_eq!(decoded.heap.largest_free_block, 60_000);
+        assert_eq!(decoded.tasks.len(), 1);
+        assert_eq!(decoded.tasks[0].name, "main");
+        assert_eq!(decoded.tasks[0].priority, 1);
+        assert_eq!(decoded.tasks[0].state, "running");
+        assert_eq!(decoded.tasks[0].stack_high_water_mark, 2048);
+        assert_eq!(decoded.rssi, Some(-61));
+        assert_eq!(decoded.cpu_temperature, Some(42.5));
+        assert_eq!(decoded.ws_clients, 2);
+        assert_eq!(decoded.http_clients, 1);
+    }
+
+    #[test]
+    fn missing_health_options_are_not_set() {
+        let decoded = round_trip(&Health {
+            rssi: None,
+            cpu_temperature: None,
+            ..health()
+        });
+
+        assert_eq!(decoded.rssi, None);
+        assert_eq!(decoded.cpu_temperature, None);
+    }
+}
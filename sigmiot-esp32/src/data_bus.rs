@@ -0,0 +1,143 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::pubsub::{Error, PubSubChannel, Subscriber};
+
+/// A channel which hands every published message to all of its subscribers,
+/// and keeps the last one for the readers which don't wait for updates
+pub struct DataBus<M: RawMutex, T: Clone, const CAP: usize, const SUBS: usize, const PUBS: usize> {
+    channel: PubSubChannel<M, T, CAP, SUBS, PUBS>,
+    latest: Mutex<Option<T>>,
+    /// Number of messages published since boot
+    published: AtomicU64,
+}
+
+impl<M: RawMutex, T: Clone, const CAP: usize, const SUBS: usize, const PUBS: usize>
+    DataBus<M, T, CAP, SUBS, PUBS>
+{
+    pub const fn new() -> Self {
+        Self {
+            channel: PubSubChannel::new(),
+            latest: Mutex::new(None),
+            published: AtomicU64::new(0),
+        }
+    }
+
+    /// Publish a message without blocking.
+    /// A subscriber which does not keep up loses the oldest messages.
+    pub fn publish(&self, msg: T) {
+        *self.latest.lock().unwrap() = Some(msg.clone());
+        self.published.fetch_add(1, Ordering::Relaxed);
+        self.channel.immediate_publisher().publish_immediate(msg);
+    }
+
+    /// The last published message
+    pub fn latest(&self) -> Option<T> {
+        self.latest.lock().unwrap().clone()
+    }
+
+    pub fn published_count(&self) -> u64 {
+        self.published.load(Ordering::Relaxed)
+    }
+
+    /// Subscribe to the messages published from now on
+    /// # Returns
+    /// * An error if the max number of subscribers is reached
+    pub fn subscribe(&self) -> Result<Subscriber<'_, M, T, CAP, SUBS, PUBS>, Error> {
+        self.channel.subscriber()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embassy_sync::pubsub::WaitResult;
+
+    type TestBus = DataBus<NoopRawMutex, u32, 2, 3, 1>;
+
+    fn received<M: RawMutex, const CAP: usize, const SUBS: usize, const PUBS: usize>(
+        subscriber: &mut Subscriber<'_, M, u32, CAP, SUBS, PUBS>,
+    ) -> Vec<WaitResult<u32>> {
+        std::iter::from_fn(|| subscriber.try_next_message()).collect()
+    }
+
+    #[test]
+    fn every_subscriber_gets_every_message() {
+        let bus = TestBus::new();
+        let mut first = bus.subscribe().unwrap();
+        let mut second = bus.subscribe().unwrap();
+
+        bus.publish(1);
+        bus.publish(2);
+
+        let expected = vec![WaitResult::Message(1), WaitResult::Message(2)];
+        assert_eq!(received(&mut first), expected);
+        assert_eq!(received(&mut second), expected);
+
+        bus.publish(3);
+        assert_eq!(received(&mut first), vec![WaitResult::Message(3)]);
+        assert_eq!(received(&mut second), vec![WaitResult::Message(3)]);
+    }
+
+    #[test]
+    fn a_late_subscriber_only_gets_the_new_messages() {
+        let bus = TestBus::new();
+        bus.publish(1);
+
+        let mut subscriber = bus.subscribe().unwrap();
+        bus.publish(2);
+
+        assert_eq!(received(&mut subscriber), vec![WaitResult::Message(2)]);
+    }
+
+    #[test]
+    fn a_slow_subscriber_lags_without_blocking_the_others() {
+        let bus = TestBus::new();
+        let mut slow = bus.subscribe().unwrap();
+        let mut fast = bus.subscribe().unwrap();
+
+        for value in 1..=4 {
+            bus.publish(value);
+            assert_eq!(received(&mut fast), vec![WaitResult::Message(value)]);
+        }
+
+        // The channel holds 2 messages, the 2 oldest were dropped
+        assert_eq!(
+            received(&mut slow),
+            vec![
+                WaitResult::Lagged(2),
+                WaitResult::Message(3),
+                WaitResult::Message(4)
+            ]
+        );
+    }
+
+    #[test]
+    fn the_number_of_subscribers_is_limited() {
+        let bus = TestBus::new();
+        let subscribers: Vec<_> = (0..3).map(|_| bus.subscribe().unwrap()).collect();
+
+        assert!(bus.subscribe().is_err());
+
+        // Closing a connection frees its slot
+        drop(subscribers);
+        assert!(bus.subscribe().is_ok());
+    }
+
+    #[test]
+    fn latest_is_the_last_published_message() {
+        let bus = TestBus::new();
+        assert_eq!(bus.latest(), None);
+        assert_eq!(bus.published_count(), 0);
+
+        // Kept without any subscriber
+        bus.publish(1);
+        bus.publish(2);
+        bus.publish(3);
+
+        assert_eq!(bus.latest(), Some(3));
+        assert_eq!(bus.published_count(), 3);
+    }
+}
@@ -1,60 +1,139 @@
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::mutex::Mutex as AsyncMutex;
+use embassy_sync::pubsub::Subscriber;
 use esp_idf_hal::task::embassy_sync::EspRawMutex;
-use lazy_static::lazy_static;
-use log::info;
+use log::warn;
 use protobuf::{EnumOrUnknown, Message};
 
 include!(concat!(env!("OUT_DIR"), "/protos/mod.rs"));
 
-use sigmiot_data::{SensorValue, SensorDataResponse, MessageResponse, LogDataResponse};
+use sigmiot_data::{
+    alert_event, AlertEvent, LogDataResponse, MessageResponse, SensorDataResponse, SensorValue,
+};
 
+use crate::alerts::{self, AlertState};
+use crate::api::{SensorReading, ValueReading};
+use crate::commands::{health_to_proto, wifi_status_to_proto, ClientSession};
+use crate::data_bus::DataBus;
+use crate::device::uptime_ms;
+use crate::diagnostics::health;
+use crate::httpd_config::WS_MAX_CON_LIMIT;
+use crate::sntp::time_is_valid;
+use crate::wifi_supervisor::wifi_status;
 use crate::{sensors, sigmiot_log::remote_logger_get_entries};
 
 pub struct DataMessage {
-    pub data: Vec<sensors::SensorData>,
+    pub readings: Vec<SensorReading>,
+    pub alerts: Vec<alerts::AlertEvent>,
 }
 
 const DATA_CHANNEL_SIZE: usize = 2;
-static CHANNEL: embassy_sync::channel::Channel<EspRawMutex, DataMessage, DATA_CHANNEL_SIZE> =
-    embassy_sync::channel::Channel::new();
+/// How often the device health is sent to every client
+const HEALTH_INTERVAL_MS: u64 = 10_000;
+const DATA_CHANNEL_PUBLISHERS: usize = 1;
 
-fn get_data() -> Vec<sensors::SensorData> {
-    lazy_static!{ static ref PREV_DATA: Arc<Mutex<Vec<sensors::SensorData>>> = Arc::new(Mutex::new(Vec::new())); }
+type DataChannel = DataBus<
+    EspRawMutex,
+    Arc<DataMessage>,
+    DATA_CHANNEL_SIZE,
+    WS_MAX_CON_LIMIT,
+    DATA_CHANNEL_PUBLISHERS,
+>;
 
-    if let Ok(msg) = CHANNEL.receiver().try_recv() {
-        *PREV_DATA.lock().unwrap() = msg.data;
-        PREV_DATA.lock().unwrap().clone()
-    }
-    else {
-        PREV_DATA.lock().unwrap().clone()
-    }
+/// Every WebSocket connection holds its own subscriber and sees every published message
+pub type DataSubscriber = Subscriber<
+    'static,
+    EspRawMutex,
+    Arc<DataMessage>,
+    DATA_CHANNEL_SIZE,
+    WS_MAX_CON_LIMIT,
+    DATA_CHANNEL_PUBLISHERS,
+>;
+
+static CHANNEL: DataChannel = DataBus::new();
+
+/// The last published sensor readings
+pub fn latest_readings() -> Vec<SensorReading> {
+    CHANNEL
+        .latest()
+        .map(|msg| msg.readings.clone())
+        .unwrap_or_default()
+}
+
+/// The readings published to the clients, shared by the protobuf and JSON APIs
+/// # Arguments
+/// * `data` - The sensors data
+/// * `timestamp_ms` - The time of the reading in milliseconds
+/// * `wall_clock` - If the timestamp is the wall clock time, not the time since boot
+pub fn sensor_readings(
+    data: &[sensors::SensorData],
+    timestamp_ms: u64,
+    wall_clock: bool,
+) -> Vec<SensorReading> {
+    data.iter()
+        .map(|sensor| {
+            SensorReading::new(
+                sensor.get_name(),
+                sensor.get_sensor_type(),
+                sensor.get_location(),
+                timestamp_ms,
+                wall_clock,
+                sensor.get_values().into_iter().map(|value| ValueReading {
+                    name: value.value_name.clone(),
+                    value: value.value,
+                    raw_value: value.raw_value,
+                    unit: value.unit.clone(),
+                    filtered: value.filtered,
+                }),
+            )
+        })
+        .collect()
+}
+
+/// Publish the sensors data to all subscribers without blocking.
+/// A subscriber which does not keep up loses the oldest messages.
+pub fn publish(data: &[sensors::SensorData], timestamp_ms: u64, alerts: Vec<alerts::AlertEvent>) {
+    let msg = Arc::new(DataMessage {
+        readings: sensor_readings(data, timestamp_ms, time_is_valid()),
+        alerts,
+    });
+
+    CHANNEL.publish(msg);
 }
 
-pub async fn publish_async(data: Vec<sensors::SensorData>) {
-    let msg = DataMessage { data };
-    CHANNEL.sender().send(msg).await;
+/// Number of messages published since boot, tells if new readings were published
+pub fn published_count() -> u64 {
+    CHANNEL.published_count()
 }
 
-async fn get_data_async() -> Vec<sensors::SensorData> {
-    let msg = CHANNEL.receiver().recv().await;
-    msg.data
+/// Subscribe to the published sensors data
+/// # Returns
+/// * None if the max number of subscribers is reached
+pub fn subscribe() -> Option<DataSubscriber> {
+    match CHANNEL.subscribe() {
+        Ok(subscriber) => Some(subscriber),
+        Err(e) => {
+            warn!("Cannot subscribe to the data channel: {:?}", e);
+            None
+        }
+    }
 }
 
 pub fn get_http_data() -> String {
-    let sensors_data = get_data();
+    let readings = latest_readings();
     let mut buf = String::new();
 
-    for sensor in sensors_data.iter() {
-        buf.push_str(&format!("<h2>{}</h2>\n", sensor.get_name()));
+    for sensor in readings.iter() {
+        buf.push_str(&format!("<h2>{}</h2>\n", sensor.name));
         buf.push_str("<ul>\n");
 
-        let sensor_values = sensor.get_values();
-        for val_ref in sensor_values {
-            let value_name = val_ref.value_name.as_str();
-            let value = val_ref.value;
-            let unit = val_ref.unit.as_str();
-            buf.push_str(&format!("<li>{}: {} {}</li>\n", value_name, value as i32, unit));
+        for value in sensor.values.iter() {
+            buf.push_str(&format!(
+                "<li>{}: {} {}</li>\n",
+                value.name, value.value as i32, value.unit
+            ));
         }
 
         buf.push_str("</ul>\n");
@@ -63,45 +142,157 @@ pub fn get_http_data() -> String {
     buf
 }
 
-pub async fn get_protobuf_data_async() -> Vec<u8> {
-    let sensors_data = get_data_async().await;
+pub async fn get_protobuf_data_async(
+    subscriber: &mut DataSubscriber,
+    session: &AsyncMutex<impl RawMutex, ClientSession>,
+) -> Vec<u8> {
+    let msg = subscriber.next_message_pure().await;
+    let DataMessage { readings, alerts } = msg.as_ref();
 
     let mut msg_response = MessageResponse::new();
     msg_response.status = EnumOrUnknown::new(sigmiot_data::message_response::Status::OK);
+    msg_response.time_valid = time_is_valid();
+
+    msg_response.sensors_data_response = readings.iter().map(reading_to_proto).collect();
 
-    for sensor in sensors_data.iter() {
-        let mut sensor_data_resp = SensorDataResponse::new();
+    for alert in alerts.iter() {
+        let mut alert_event = AlertEvent::new();
+        alert_event.rule_id = alert.rule_id.clone();
+        alert_event.sensor_name = alert.sensor_name.clone();
+        alert_event.value_name = alert.value_name.clone();
+        alert_event.value = alert.value;
+        alert_event.state = EnumOrUnknown::new(match alert.state {
+            AlertState::Raised => alert_event::State::RAISED,
+            AlertState::Cleared => alert_event::State::CLEARED,
+        });
+        alert_event.timestamp = alert.timestamp;
+        alert_event.message = alert.message.clone();
 
-        sensor_data_resp.sensor_name = sensor.get_name().to_owned();
-        // TODO: add sensor type and location
-        sensor_data_resp.sensor_type = "thp".to_string();
-        sensor_data_resp.sensor_location = "inside".to_string();
+        msg_response.alert_events.push(alert_event);
+    }
+
+    let (log_entries, wifi_status, health_due) = {
+        let mut session = session.lock().await;
+        let session = &mut *session;
 
-        let sensor_values = sensor.get_values();
-        for val_ref in sensor_values {
-            let mut sensor_value = SensorValue::new();
-            sensor_value.value_name = val_ref.value_name.clone();
-            sensor_value.value_data = val_ref.value;
-            sensor_value.value_unit = val_ref.unit.clone();
+        // The Wi-Fi status is sent once to every client after each change
+        let status = wifi_status();
+        let wifi_status = if status.seq != session.wifi_status_seq {
+            session.wifi_status_seq = status.seq;
+            Some(status)
+        } else {
+            None
+        };
 
-            sensor_data_resp.sensor_values.push(sensor_value);
+        // The health is sent with the first message, then every HEALTH_INTERVAL_MS
+        let now = uptime_ms();
+        let health_due = session
+            .health_sent_ms
+            .map_or(true, |sent| now.saturating_sub(sent) >= HEALTH_INTERVAL_MS);
+        if health_due {
+            session.health_sent_ms = Some(now);
         }
 
-        msg_response.sensors_data_response.push(sensor_data_resp);
+        (
+            remote_logger_get_entries(&mut session.log_cursor, &session.log_filter),
+            wifi_status,
+            health_due,
+        )
+    };
+
+    if let Some(status) = wifi_status {
+        msg_response.wifi_status = protobuf::MessageField::some(wifi_status_to_proto(&status));
+    }
+
+    if health_due {
+        msg_response.health = protobuf::MessageField::some(health_to_proto(&health()));
     }
 
-    let log_entries = remote_logger_get_entries();
-    
     for entry in log_entries {
         let mut log_entry = LogDataResponse::new();
-        log_entry.log_level = entry.level;
-        log_entry.log_message = entry.message;
-        log_entry.log_timestamp = entry.timestamp;
+        log_entry.log_level = entry.level.as_str().to_string();
+        log_entry.log_message = entry.message.clone();
+        log_entry.log_timestamp = entry.timestamp_ms / 1000;
+        log_entry.log_timestamp_ms = entry.timestamp_ms;
+        log_entry.wall_clock = entry.wall_clock;
+        log_entry.target = entry.target.clone();
+        log_entry.file = entry.file.clone().unwrap_or_default();
+        log_entry.line = entry.line.unwrap_or_default();
+        log_entry.task_name = entry.task_name.clone();
 
         msg_response.log_data_response.push(log_entry);
     }
 
-    info!("Log data response size: {}", msg_response.log_data_response.len());
-
     msg_response.write_to_bytes().unwrap()
 }
+
+pub fn reading_to_proto(reading: &SensorReading) -> SensorDataResponse {
+    let mut sensor_data_resp = SensorDataResponse::new();
+    sensor_data_resp.sensor_name = reading.name.clone();
+    sensor_data_resp.sensor_type = reading.sensor_type_label();
+    sensor_data_resp.sensor_location = reading.location.clone();
+    sensor_data_resp.timestamp_ms = reading.timestamp_ms;
+    sensor_data_resp.wall_clock = reading.wall_clock;
+
+    for value in reading.values.iter() {
+        let mut sensor_value = SensorValue::new();
+        sensor_value.value_name = value.name.clone();
+        sensor_value.value_data = value.value;
+        sensor_value.value_unit = value.unit.clone();
+        sensor_value.value_raw = value.raw_value;
+        sensor_value.filtered = value.filtered;
+
+        sensor_data_resp.sensor_values.push(sensor_value);
+    }
+
+    sensor_data_resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reading_survives_the_protobuf_round_trip() {
+        let value = |name: &str, value: f32, raw_value: f32, unit: &str| ValueReading {
+            name: name.into(),
+            value,
+            raw_value,
+            unit: unit.into(),
+            filtered: value != raw_value,
+        };
+        let reading = SensorReading::new(
+            "BME280",
+            &["temperature".into(), "humidity".into()],
+            "room1",
+            1_700_000_000_000,
+            true,
+            vec![
+                value("temperature", 21.5, 21.25, "°C"),
+                value("humidity", 40.0, 40.0, "%"),
+            ],
+        );
+
+        let bytes = reading_to_proto(&reading).write_to_bytes().unwrap();
+        let decoded = SensorDataResponse::parse_from_bytes(&bytes).unwrap();
+
+        assert_eq!(decoded.sensor_name, "BME280");
+        assert_eq!(decoded.sensor_type, "temperature,humidity");
+        assert_eq!(decoded.sensor_location, "room1");
+        assert_eq!(decoded.timestamp_ms, 1_700_000_000_000);
+        assert!(decoded.wall_clock);
+
+        let values: Vec<ValueReading> = decoded
+            .sensor_values
+            .iter()
+            .map(|v| ValueReading {
+                name: v.value_name.clone(),
+                value: v.value_data,
+                raw_value: v.value_raw,
+                unit: v.value_unit.clone(),
+                filtered: v.filtered,
+            })
+            .collect();
+        assert_eq!(values, reading.values);
+    }
+}
@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use crate::api::{DeviceInfo, WifiInfo};
+use crate::network::network_config_load;
+use crate::sntp::time_is_valid;
+use crate::wifi_supervisor::wifi_status;
+
+/// The unique id of the device, built from the station MAC address,
+/// e.g. "sigmiot-a1b2c3"
+pub fn device_id() -> String {
+    let mut mac = [0_u8; 6];
+    unsafe {
+        esp_idf_sys::esp_read_mac(
+            mac.as_mut_ptr(),
+            esp_idf_sys::esp_mac_type_t_ESP_MAC_WIFI_STA,
+        );
+    }
+
+    format!("sigmiot-{:02x}{:02x}{:02x}", mac[3], mac[4], mac[5])
+}
+
+/// Leaves the client the time to get the reply before a restart
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Restart the device once the client got the reply
+pub fn device_restart() {
+    std::thread::spawn(|| {
+        std::thread::sleep(RESTART_DELAY);
+        esp_idf_hal::reset::restart();
+    });
+}
+
+/// Milliseconds since boot
+pub fn uptime_ms() -> u64 {
+    unsafe { esp_idf_sys::esp_timer_get_time() as u64 / 1000 }
+}
+
+/// The identity and state of the device
+pub fn device_info() -> DeviceInfo {
+    let status = wifi_status();
+
+    DeviceInfo {
+        id: device_id(),
+        firmware_version: env!("CARGO_PKG_VERSION").to_string(),
+        hostname: network_config_load().effective_hostname(),
+        ip: status.ip.map(|ip| ip.to_string()),
+        uptime_ms: uptime_ms(),
+        time_valid: time_is_valid(),
+        free_heap: unsafe { esp_idf_sys::esp_get_free_heap_size() },
+        wifi: WifiInfo {
+            connected: status.connected,
+            ssid: status.ssid,
+            rssi: status.rssi,
+            channel: status.channel,
+        },
+    }
+}
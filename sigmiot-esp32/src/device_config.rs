@@ -0,0 +1,278 @@
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Error};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::httpd_config::HttpdConfig;
+use crate::network::NetworkConfig;
+use crate::sleep_schedule::PowerConfig;
+use crate::sntp::time_config_apply;
+use crate::storage::{storage_load, storage_store};
+use crate::time_sync::TimeConfig;
+
+const DEVICE_CONFIG_STORAGE_KEY: &str = "device";
+
+/// Version of the config layout, raised when a field is removed or changes meaning
+pub const DEVICE_CONFIG_VERSION: u32 = 1;
+
+const MIN_POLL_INTERVAL_MS: u64 = 100;
+const MAX_POLL_INTERVAL_MS: u64 = 3_600_000;
+const MAX_SENSOR_NAME_LEN: usize = 32;
+/// The lowest and highest sea level pressures ever recorded, in hPa
+const MIN_SEA_LEVEL_PRESSURE: f32 = 870.0;
+const MAX_SEA_LEVEL_PRESSURE: f32 = 1085.0;
+/// Above the illuminance of a cloudy day, in lx
+const MAX_DAYLIGHT_THRESHOLD: f32 = 10_000.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SensorNaming {
+    pub name: String,
+    pub location: String,
+}
+
+impl SensorNaming {
+    fn new(name: &str, location: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            location: location.to_string(),
+        }
+    }
+}
+
+/// The day/night sensor, its state changes when the illuminance leaves the
+/// hysteresis band around the threshold
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DaylightConfig {
+    #[serde(flatten)]
+    pub naming: SensorNaming,
+    /// The illuminance in lx separating day and night
+    pub threshold: f32,
+    /// The width of the band in lx
+    pub hysteresis: f32,
+}
+
+impl Default for DaylightConfig {
+    fn default() -> Self {
+        Self {
+            naming: SensorNaming::new("GY30-daylight", "room1"),
+            threshold: 50.0,
+            hysteresis: 20.0,
+        }
+    }
+}
+
+/// The sensors of the board. The names are also the keys of the calibrations,
+/// filters and alert rules, these are not renamed with the sensors.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SensorsConfig {
+    pub poll_interval_ms: u64,
+    pub bme280: SensorNaming,
+    pub gy30: SensorNaming,
+    /// Computed from the BME280 values
+    pub comfort: SensorNaming,
+    /// Computed from the BME280 values
+    pub altitude: SensorNaming,
+    /// The reference pressure of the altitude in hPa, the local pressure at sea level
+    pub sea_level_pressure: f32,
+    /// Computed from the GY30 values
+    pub daylight: DaylightConfig,
+}
+
+impl Default for SensorsConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_ms: 1000,
+            bme280: SensorNaming::new("BME280", "room1"),
+            gy30: SensorNaming::new("GY30", "room1"),
+            comfort: SensorNaming::new("BME280-comfort", "room1"),
+            altitude: SensorNaming::new("BME280-altitude", "room1"),
+            sea_level_pressure: 1013.25,
+            daylight: DaylightConfig::default(),
+        }
+    }
+}
+
+impl SensorsConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if !(MIN_POLL_INTERVAL_MS..=MAX_POLL_INTERVAL_MS).contains(&self.poll_interval_ms) {
+            return Err(anyhow!(
+                "poll interval must be {} to {} ms",
+                MIN_POLL_INTERVAL_MS,
+                MAX_POLL_INTERVAL_MS
+            ));
+        }
+
+        if !(MIN_SEA_LEVEL_PRESSURE..=MAX_SEA_LEVEL_PRESSURE).contains(&self.sea_level_pressure) {
+            return Err(anyhow!(
+                "sea level pressure must be {} to {} hPa",
+                MIN_SEA_LEVEL_PRESSURE,
+                MAX_SEA_LEVEL_PRESSURE
+            ));
+        }
+
+        let daylight = &self.daylight;
+        if !(daylight.threshold > 0.0 && daylight.threshold <= MAX_DAYLIGHT_THRESHOLD) {
+            return Err(anyhow!(
+                "daylight threshold must be above 0 and at most {} lx",
+                MAX_DAYLIGHT_THRESHOLD
+            ));
+        }
+
+        if !(daylight.hysteresis >= 0.0 && daylight.hysteresis < 2.0 * daylight.threshold) {
+            return Err(anyhow!(
+                "daylight hysteresis must be at least 0 and below twice the threshold"
+            ));
+        }
+
+        let sensors = [
+            &self.bme280,
+            &self.gy30,
+            &self.comfort,
+            &self.altitude,
+            &daylight.naming,
+        ];
+        for (i, sensor) in sensors.iter().enumerate() {
+            if sensor.name.is_empty()
+                || sensor.name.len() > MAX_SENSOR_NAME_LEN
+                || sensor.location.len() > MAX_SENSOR_NAME_LEN
+            {
+                return Err(anyhow!(
+                    "sensor names must be 1 to {} bytes long and locations at most {}",
+                    MAX_SENSOR_NAME_LEN,
+                    MAX_SENSOR_NAME_LEN
+                ));
+            }
+
+            if sensors[..i].iter().any(|other| other.name == sensor.name) {
+                return Err(anyhow!("duplicate sensor name '{}'", sensor.name));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The settings of the device, stored as a single JSON document.
+/// The Wi-Fi networks and the credentials are stored on their own and never exported.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceConfig {
+    pub version: u32,
+    /// Applied at the next boot
+    pub sensors: SensorsConfig,
+    /// Applied at the next boot
+    pub network: NetworkConfig,
+    /// Applied at the next boot
+    pub httpd: HttpdConfig,
+    pub time: TimeConfig,
+    /// Applied at the next boot
+    pub power: PowerConfig,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            version: DEVICE_CONFIG_VERSION,
+            sensors: SensorsConfig::default(),
+            network: NetworkConfig::default(),
+            httpd: HttpdConfig::default(),
+            time: TimeConfig::default(),
+            power: PowerConfig::default(),
+        }
+    }
+}
+
+impl DeviceConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.version == 0 || self.version > DEVICE_CONFIG_VERSION {
+            return Err(anyhow!(
+                "config version {} is not supported, the firmware reads up to {}",
+                self.version,
+                DEVICE_CONFIG_VERSION
+            ));
+        }
+
+        self.sensors
+            .validate()
+            .map_err(|e| anyhow!("sensors: {}", e))?;
+        self.network
+            .validate()
+            .map_err(|e| anyhow!("network: {}", e))?;
+        self.httpd.validate().map_err(|e| anyhow!("httpd: {}", e))?;
+        self.time.validate().map_err(|e| anyhow!("time: {}", e))?;
+        self.power.validate().map_err(|e| anyhow!("power: {}", e))?;
+
+        Ok(())
+    }
+}
+
+lazy_static! {
+    static ref DEVICE_CONFIG: Mutex<DeviceConfig> = Mutex::new(DeviceConfig::default());
+}
+
+/// Load the device config from NVS, must be called before the modules reading their section
+pub fn device_config_load() {
+    let config = storage_load::<DeviceConfig>(DEVICE_CONFIG_STORAGE_KEY).unwrap_or_default();
+
+    let config = match config.validate() {
+        Ok(()) => DeviceConfig {
+            // An older layout was read with the defaults of the new fields
+            version: DEVICE_CONFIG_VERSION,
+            ..config
+        },
+        Err(e) => {
+            warn!("Ignoring the stored device config: {:?}", e);
+            DeviceConfig::default()
+        }
+    };
+
+    info!("Device config: {:?}", config);
+    *DEVICE_CONFIG.lock().unwrap() = config;
+}
+
+/// The stored device config, the sections applied at boot may differ until the next boot
+pub fn device_config_get() -> DeviceConfig {
+    DEVICE_CONFIG.lock().unwrap().clone()
+}
+
+/// Change the device config, it is validated and stored as a whole
+/// # Arguments
+/// * `update` - Changes a copy of the current config
+/// # Returns
+/// * The new config
+pub fn device_config_update(update: impl FnOnce(&mut DeviceConfig)) -> Result<DeviceConfig, Error> {
+    let mut current = DEVICE_CONFIG.lock().unwrap();
+
+    let mut config = current.clone();
+    update(&mut config);
+    config.validate()?;
+    storage_store(DEVICE_CONFIG_STORAGE_KEY, &config)?;
+
+    *current = config.clone();
+
+    Ok(config)
+}
+
+/// The device config as a JSON document, for a backup or another device
+pub fn device_config_export() -> String {
+    serde_json::to_string_pretty(&device_config_get()).unwrap()
+}
+
+/// Replace the device config with a JSON document from `device_config_export`.
+/// The missing sections and fields get their defaults.
+/// # Returns
+/// * The new config
+pub fn device_config_import(json: &[u8]) -> Result<DeviceConfig, Error> {
+    let imported = serde_json::from_slice::<DeviceConfig>(json)?;
+
+    let config = device_config_update(|config| *config = imported)?;
+    info!("Device config imported: {:?}", config);
+
+    time_config_apply(config.time.clone())?;
+
+    Ok(config)
+}
@@ -0,0 +1,163 @@
+use std::ffi::CStr;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Error};
+use embedded_hal::blocking::i2c::Read;
+use esp_idf_hal::i2c::I2cDriver;
+use lazy_static::lazy_static;
+use log::info;
+use shared_bus::I2cProxy;
+
+use crate::api::{Diagnostics, Health, HeapInfo, I2cScan, TaskInfo};
+use crate::device::uptime_ms;
+use crate::httpd::{http_client_count, ws_client_count};
+use crate::wifi_supervisor::wifi_status;
+
+/// The range of the 7-bit addresses not reserved by the I2C specification
+const I2C_FIRST_ADDRESS: u8 = 0x08;
+const I2C_LAST_ADDRESS: u8 = 0x77;
+
+/// A handle on the sensors bus, the sensors keep using it between the scans
+type I2cBus = I2cProxy<'static, std::sync::Mutex<I2cDriver<'static>>>;
+
+lazy_static! {
+    static ref I2C_BUS: Mutex<Option<I2cBus>> = Mutex::new(None);
+}
+
+/// Give the I2C bus to the scans
+pub fn diagnostics_init(bus: I2cBus) {
+    *I2C_BUS.lock().unwrap() = Some(bus);
+}
+
+/// Probe every address of the I2C bus with a one byte read
+pub fn i2c_scan() -> Result<I2cScan, Error> {
+    let mut bus = I2C_BUS.lock().unwrap();
+    let bus = bus
+        .as_mut()
+        .ok_or_else(|| anyhow!("the I2C bus is not initialized"))?;
+
+    let mut buffer = [0_u8; 1];
+    let addresses: Vec<u8> = (I2C_FIRST_ADDRESS..=I2C_LAST_ADDRESS)
+        .filter(|address| bus.read(*address, &mut buffer).is_ok())
+        .collect();
+
+    info!("I2C devices found at {:02x?}", addresses);
+
+    Ok(I2cScan { addresses })
+}
+
+pub fn heap_info() -> HeapInfo {
+    unsafe {
+        HeapInfo {
+            free: esp_idf_sys::heap_caps_get_free_size(esp_idf_sys::MALLOC_CAP_DEFAULT) as u32,
+            min_free: esp_idf_sys::heap_caps_get_minimum_free_size(esp_idf_sys::MALLOC_CAP_DEFAULT)
+                as u32,
+            largest_free_block: esp_idf_sys::heap_caps_get_largest_free_block(
+                esp_idf_sys::MALLOC_CAP_DEFAULT,
+            ) as u32,
+        }
+    }
+}
+
+/// The FreeRTOS tasks, needs CONFIG_FREERTOS_USE_TRACE_FACILITY
+pub fn task_info() -> Vec<TaskInfo> {
+    let count = unsafe { esp_idf_sys::uxTaskGetNumberOfTasks() } as usize;
+    // Room for the tasks created meanwhile
+    let mut status: Vec<esp_idf_sys::TaskStatus_t> = Vec::with_capacity(count + 4);
+
+    let filled = unsafe {
+        esp_idf_sys::uxTaskGetSystemState(
+            status.as_mut_ptr(),
+            status.capacity() as _,
+            std::ptr::null_mut(),
+        )
+    };
+    unsafe { status.set_len(filled as usize) };
+
+    let mut tasks: Vec<TaskInfo> = status
+        .iter()
+        .map(|task| TaskInfo {
+            name: unsafe { CStr::from_ptr(task.pcTaskName) }
+                .to_string_lossy()
+                .into_owned(),
+            priority: task.uxCurrentPriority as u32,
+            state: task_state_name(task.eCurrentState).to_string(),
+            stack_high_water_mark: task.usStackHighWaterMark as u32,
+        })
+        .collect();
+    tasks.sort_by(|a, b| a.name.cmp(&b.name));
+
+    tasks
+}
+
+fn task_state_name(state: esp_idf_sys::eTaskState) -> &'static str {
+    match state {
+        esp_idf_sys::eTaskState_eRunning => "running",
+        esp_idf_sys::eTaskState_eReady => "ready",
+        esp_idf_sys::eTaskState_eBlocked => "blocked",
+        esp_idf_sys::eTaskState_eSuspended => "suspended",
+        esp_idf_sys::eTaskState_eDeleted => "deleted",
+        _ => "unknown",
+    }
+}
+
+/// The heap and the tasks with their stack use
+pub fn diagnostics() -> Diagnostics {
+    Diagnostics {
+        heap: heap_info(),
+        tasks: task_info(),
+    }
+}
+
+/// Why the device last restarted
+pub fn reset_reason() -> &'static str {
+    match unsafe { esp_idf_sys::esp_reset_reason() } {
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_POWERON => "power-on",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_EXT => "external pin",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_SW => "software",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt watchdog",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_TASK_WDT => "task watchdog",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep sleep",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_SDIO => "SDIO",
+        _ => "unknown",
+    }
+}
+
+/// The chip temperature in °C, the ESP32 has no usable sensor
+#[cfg(any(esp32s2, esp32s3, esp32c3))]
+pub fn cpu_temperature() -> Option<f32> {
+    static START: std::sync::Once = std::sync::Once::new();
+    START.call_once(|| unsafe {
+        esp_idf_sys::temp_sensor_start();
+    });
+
+    let mut celsius = 0_f32;
+    let read = unsafe { esp_idf_sys::temp_sensor_read_celsius(&mut celsius) };
+
+    (read == esp_idf_sys::ESP_OK).then_some(celsius)
+}
+
+/// The chip temperature in °C, the ESP32 has no usable sensor
+#[cfg(not(any(esp32s2, esp32s3, esp32c3)))]
+pub fn cpu_temperature() -> Option<f32> {
+    None
+}
+
+/// The state of the device, sent periodically to the clients
+pub fn health() -> Health {
+    let wifi = wifi_status();
+
+    Health {
+        uptime_ms: uptime_ms(),
+        reset_reason: reset_reason().to_string(),
+        heap: heap_info(),
+        tasks: task_info(),
+        rssi: wifi.connected.then_some(wifi.rssi),
+        cpu_temperature: cpu_temperature(),
+        ws_clients: ws_client_count(),
+        http_clients: http_client_count(),
+    }
+}
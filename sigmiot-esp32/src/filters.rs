@@ -0,0 +1,437 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Error};
+use lazy_static::lazy_static;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::sensors::SensorData;
+use crate::storage::{storage_load, storage_store};
+
+const FILTERS_STORAGE_KEY: &str = "filters";
+const MAX_WINDOW: usize = 32;
+const MAX_FILTERS_PER_VALUE: usize = 4;
+/// A max-delta filter accepts the new level after this many rejected samples in a row
+const MAX_CONSECUTIVE_REJECTIONS: u32 = 3;
+/// Scale factor making the MAD a consistent estimator of the standard deviation
+const MAD_SCALE: f32 = 1.4826;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum FilterKind {
+    /// Mean of the last `window` samples
+    MovingAverage { window: usize },
+    /// Exponential smoothing, `alpha` is the weight of the new sample
+    Exponential { alpha: f32 },
+    /// Median of the last `window` samples
+    Median { window: usize },
+    /// Hampel filter: a sample further than `threshold` scaled MADs from the
+    /// median of the last `window` samples is replaced by the median
+    MadSpikeRejection { window: usize, threshold: f32 },
+    /// A sample differing from the previous output by more than `max_delta` is rejected
+    MaxDelta { max_delta: f32 },
+}
+
+impl FilterKind {
+    fn validate(&self) -> Result<(), Error> {
+        let valid = match *self {
+            FilterKind::MovingAverage { window } | FilterKind::Median { window } => {
+                (1..=MAX_WINDOW).contains(&window)
+            }
+            FilterKind::Exponential { alpha } => alpha > 0.0 && alpha <= 1.0,
+            FilterKind::MadSpikeRejection { window, threshold } => {
+                (3..=MAX_WINDOW).contains(&window) && threshold > 0.0
+            }
+            FilterKind::MaxDelta { max_delta } => max_delta > 0.0,
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(anyhow!("invalid filter parameters: {:?}", self))
+        }
+    }
+}
+
+/// A filter with its running state
+#[derive(Debug)]
+pub struct Filter {
+    kind: FilterKind,
+    history: VecDeque<f32>,
+    last_output: Option<f32>,
+    rejections: u32,
+}
+
+impl Filter {
+    pub fn new(kind: FilterKind) -> Self {
+        Self {
+            kind,
+            history: VecDeque::new(),
+            last_output: None,
+            rejections: 0,
+        }
+    }
+
+    fn push_history(&mut self, value: f32, window: usize) {
+        self.history.push_back(value);
+        while self.history.len() > window {
+            self.history.pop_front();
+        }
+    }
+
+    /// Feed a new sample into the filter
+    /// # Returns
+    /// * The filtered value
+    pub fn apply(&mut self, value: f32) -> f32 {
+        let output = match self.kind {
+            FilterKind::MovingAverage { window } => {
+                self.push_history(value, window);
+                self.history.iter().sum::<f32>() / self.history.len() as f32
+            }
+            FilterKind::Exponential { alpha } => match self.last_output {
+                Some(last) => last + alpha * (value - last),
+                None => value,
+            },
+            FilterKind::Median { window } => {
+                self.push_history(value, window);
+                median(self.history.iter().copied().collect())
+            }
+            FilterKind::MadSpikeRejection { window, threshold } => {
+                self.push_history(value, window);
+                let samples: Vec<f32> = self.history.iter().copied().collect();
+                let med = median(samples.clone());
+                let mad = median(samples.iter().map(|s| (s - med).abs()).collect());
+
+                if mad > 0.0 && (value - med).abs() > threshold * MAD_SCALE * mad {
+                    med
+                } else {
+                    value
+                }
+            }
+            FilterKind::MaxDelta { max_delta } => match self.last_output {
+                Some(last)
+                    if (value - last).abs() > max_delta
+                        && self.rejections < MAX_CONSECUTIVE_REJECTIONS =>
+                {
+                    self.rejections += 1;
+                    last
+                }
+                _ => {
+                    self.rejections = 0;
+                    value
+                }
+            },
+        };
+
+        self.last_output = Some(output);
+        output
+    }
+}
+
+/// Median of the samples, the samples must not be empty
+pub fn median(mut samples: Vec<f32>) -> f32 {
+    samples.sort_by(|a, b| a.total_cmp(b));
+    let mid = samples.len() / 2;
+
+    if samples.len() % 2 == 0 {
+        (samples[mid - 1] + samples[mid]) / 2.0
+    } else {
+        samples[mid]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FilterConfig {
+    pub sensor_name: String,
+    pub value_name: String,
+    /// The filters applied in order, spike rejection should come before smoothing
+    pub filters: Vec<FilterKind>,
+}
+
+/// All the configured filter chains with their running state
+#[derive(Debug, Default)]
+pub struct FilterBank {
+    configs: Vec<FilterConfig>,
+    chains: HashMap<(String, String), Vec<Filter>>,
+}
+
+impl FilterBank {
+    pub fn new(configs: Vec<FilterConfig>) -> Self {
+        let mut bank = Self::default();
+        for config in configs {
+            bank.chains.insert(
+                (config.sensor_name.clone(), config.value_name.clone()),
+                config.filters.iter().cloned().map(Filter::new).collect(),
+            );
+            bank.configs.push(config);
+        }
+        bank
+    }
+
+    pub fn get_configs(&self) -> &Vec<FilterConfig> {
+        &self.configs
+    }
+
+    /// Set the filter chain of a sensor value, an empty chain removes the filtering.
+    /// The running state of the value is reset.
+    pub fn set(&mut self, config: FilterConfig) -> Result<(), Error> {
+        if config.filters.len() > MAX_FILTERS_PER_VALUE {
+            return Err(anyhow!(
+                "too many filters, max is {}",
+                MAX_FILTERS_PER_VALUE
+            ));
+        }
+
+        for filter in config.filters.iter() {
+            filter.validate()?;
+        }
+
+        let key = (config.sensor_name.clone(), config.value_name.clone());
+        self.configs.retain(|c| {
+            !(c.sensor_name == config.sensor_name && c.value_name == config.value_name)
+        });
+        self.chains.remove(&key);
+
+        if !config.filters.is_empty() {
+            self.chains.insert(
+                key,
+                config.filters.iter().cloned().map(Filter::new).collect(),
+            );
+            self.configs.push(config);
+        }
+
+        Ok(())
+    }
+
+    /// Run the values of the sensor data through their filter chains
+    pub fn apply(&mut self, data: &mut SensorData) {
+        let sensor_name = data.get_name().clone();
+
+        for value in data.get_values_mut() {
+            value.filtered = false;
+
+            if let Some(chain) = self
+                .chains
+                .get_mut(&(sensor_name.clone(), value.value_name.clone()))
+            {
+                value.value = chain.iter_mut().fold(value.value, |v, f| f.apply(v));
+                value.filtered = true;
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref FILTERS: Mutex<FilterBank> = Mutex::new(FilterBank::default());
+}
+
+/// Load the filter configuration from NVS
+pub fn filters_load() {
+    if let Some(configs) = storage_load::<Vec<FilterConfig>>(FILTERS_STORAGE_KEY) {
+        info!("Loaded filters for {} values", configs.len());
+        *FILTERS.lock().unwrap() = FilterBank::new(configs);
+    }
+}
+
+/// Set the filter chain of a sensor value and persist the configuration
+pub fn filters_set(config: FilterConfig) -> Result<(), Error> {
+    let mut bank = FILTERS.lock().unwrap();
+    info!(
+        "Filters of {}/{} set to {:?}",
+        config.sensor_name, config.value_name, config.filters
+    );
+    bank.set(config)?;
+    storage_store(FILTERS_STORAGE_KEY, bank.get_configs())
+}
+
+pub fn filters_get_all() -> Vec<FilterConfig> {
+    FILTERS.lock().unwrap().get_configs().clone()
+}
+
+/// Filter the values of the sensor data
+pub fn filters_apply(data: &mut SensorData) {
+    FILTERS.lock().unwrap().apply(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(kind: FilterKind, series: &[f32]) -> Vec<f32> {
+        let mut filter = Filter::new(kind);
+        series.iter().map(|v| filter.apply(*v)).collect()
+    }
+
+    #[test]
+    fn moving_average_over_a_sliding_window() {
+        let output = run(
+            FilterKind::MovingAverage { window: 3 },
+            &[3.0, 6.0, 9.0, 12.0, 0.0],
+        );
+
+        assert_eq!(output, vec![3.0, 4.5, 6.0, 9.0, 7.0]);
+    }
+
+    #[test]
+    fn median_over_a_sliding_window() {
+        let output = run(FilterKind::Median { window: 3 }, &[5.0, 1.0, 9.0, 2.0, 8.0]);
+
+        assert_eq!(output, vec![5.0, 3.0, 5.0, 2.0, 8.0]);
+    }
+
+    #[test]
+    fn median_of_even_and_odd_counts() {
+        assert_eq!(median(vec![4.0, 1.0, 3.0]), 3.0);
+        assert_eq!(median(vec![4.0, 1.0, 3.0, 2.0]), 2.5);
+    }
+
+    #[test]
+    fn exponential_starts_at_the_first_sample() {
+        let output = run(
+            FilterKind::Exponential { alpha: 0.5 },
+            &[10.0, 20.0, 20.0, 0.0],
+        );
+
+        assert_eq!(output, vec![10.0, 15.0, 17.5, 8.75]);
+    }
+
+    #[test]
+    fn mad_spike_rejection_replaces_outliers_by_the_median() {
+        let kind = FilterKind::MadSpikeRejection {
+            window: 5,
+            threshold: 3.0,
+        };
+        let output = run(kind, &[20.0, 21.0, 20.0, 21.0, 90.0, 20.5]);
+
+        // The history holds 20, 21, 20, 21, 90: median 21, MAD 1
+        assert_eq!(output, vec![20.0, 21.0, 20.0, 21.0, 21.0, 20.5]);
+    }
+
+    #[test]
+    fn mad_spike_rejection_keeps_a_flat_series() {
+        let kind = FilterKind::MadSpikeRejection {
+            window: 3,
+            threshold: 3.0,
+        };
+
+        // A zero MAD rejects nothing
+        assert_eq!(run(kind, &[5.0, 5.0, 5.0, 6.0]), vec![5.0, 5.0, 5.0, 6.0]);
+    }
+
+    #[test]
+    fn max_delta_accepts_a_new_level_after_repeated_rejections() {
+        let output = run(
+            FilterKind::MaxDelta { max_delta: 2.0 },
+            &[10.0, 11.0, 30.0, 30.0, 30.0, 30.0, 31.0],
+        );
+
+        assert_eq!(output, vec![10.0, 11.0, 11.0, 11.0, 11.0, 30.0, 31.0]);
+    }
+
+    #[test]
+    fn invalid_parameters_are_rejected() {
+        let mut bank = FilterBank::default();
+        let config = |kind| FilterConfig {
+            sensor_name: "BME280".into(),
+            value_name: "temperature".into(),
+            filters: vec![kind],
+        };
+
+        assert!(bank
+            .set(config(FilterKind::MovingAverage { window: 0 }))
+            .is_err());
+        assert!(bank
+            .set(config(FilterKind::Median {
+                window: MAX_WINDOW + 1
+            }))
+            .is_err());
+        assert!(bank
+            .set(config(FilterKind::Exponential { alpha: 0.0 }))
+            .is_err());
+        assert!(bank
+            .set(config(FilterKind::Exponential { alpha: 1.5 }))
+            .is_err());
+        assert!(bank
+            .set(config(FilterKind::MadSpikeRejection {
+                window: 2,
+                threshold: 3.0
+            }))
+            .is_err());
+        assert!(bank
+            .set(config(FilterKind::MaxDelta { max_delta: 0.0 }))
+            .is_err());
+        assert!(bank.get_configs().is_empty());
+
+        let too_many = FilterConfig {
+            filters: vec![FilterKind::Exponential { alpha: 0.5 }; MAX_FILTERS_PER_VALUE + 1],
+            ..config(FilterKind::Exponential { alpha: 0.5 })
+        };
+        assert!(bank.set(too_many).is_err());
+    }
+
+    fn temperature(value: f32) -> SensorData {
+        let mut data = SensorData::new("BME280", vec!["temperature".into()], "room1".into());
+        data.push_value("temperature", value, "°C");
+        data.push_value("humidity", 40.0, "%");
+        data
+    }
+
+    fn filtered_temperature(bank: &mut FilterBank, value: f32) -> f32 {
+        let mut data = temperature(value);
+        bank.apply(&mut data);
+
+        let humidity = data.get_value("humidity").unwrap();
+        assert!(!humidity.filtered);
+        assert_eq!(humidity.value, 40.0);
+
+        let temperature = data.get_value("temperature").unwrap();
+        assert!(temperature.filtered);
+        assert_eq!(temperature.raw_value, value);
+        temperature.value
+    }
+
+    #[test]
+    fn bank_runs_the_chain_in_order_and_resets_it_when_set() {
+        let config = FilterConfig {
+            sensor_name: "BME280".into(),
+            value_name: "temperature".into(),
+            filters: vec![
+                FilterKind::Median { window: 3 },
+                FilterKind::MovingAverage { window: 2 },
+            ],
+        };
+        let mut bank = FilterBank::new(vec![config.clone()]);
+
+        assert_eq!(filtered_temperature(&mut bank, 10.0), 10.0);
+        // Median 15, average of 10 and 15
+        assert_eq!(filtered_temperature(&mut bank, 20.0), 12.5);
+        // Median 20, average of 15 and 20
+        assert_eq!(filtered_temperature(&mut bank, 30.0), 17.5);
+
+        // Setting the chain again starts from an empty window
+        bank.set(config).unwrap();
+        assert_eq!(filtered_temperature(&mut bank, 50.0), 50.0);
+        assert_eq!(bank.get_configs().len(), 1);
+    }
+
+    #[test]
+    fn an_empty_chain_removes_the_filtering() {
+        let mut bank = FilterBank::new(vec![FilterConfig {
+            sensor_name: "BME280".into(),
+            value_name: "temperature".into(),
+            filters: vec![FilterKind::Exponential { alpha: 0.5 }],
+        }]);
+
+        bank.set(FilterConfig {
+            sensor_name: "BME280".into(),
+            value_name: "temperature".into(),
+            filters: vec![],
+        })
+        .unwrap();
+
+        let mut data = temperature(21.0);
+        bank.apply(&mut data);
+        assert!(!data.get_value("temperature").unwrap().filtered);
+        assert!(bank.get_configs().is_empty());
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use anyhow::Error;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::sensors::SensorData;
+use crate::storage::{
+    storage_load, storage_load_raw, storage_remove, storage_store, storage_store_raw, MAX_BLOB_SIZE,
+};
+
+/// Length of a history bucket, the readings within a bucket are averaged
+const DEFAULT_BUCKET_SECS: u64 = 300;
+/// 24 hours of 5 minute buckets
+const DEFAULT_CAPACITY: usize = 288;
+/// The buffer is saved to flash every this many committed buckets
+const FLASH_SAVE_EVERY: u32 = 6;
+/// The nvs partition is 24 KiB, the history keeps to 8 KiB of it so that the config,
+/// the credentials and the NVS garbage collection have room
+const FLASH_MAX_CHUNKS: usize = 2;
+const FLASH_MAGIC: u32 = 0x5349_4831;
+
+const HISTORY_CHUNKS_KEY: &str = "hist_chunks";
+
+/// A sensor value tracked by the history
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistorySeries {
+    pub sensor_name: String,
+    pub value_name: String,
+    pub unit: String,
+}
+
+/// The averaged readings of one bucket. The values are indexed like the series,
+/// a NaN value means the series had no reading in the bucket.
+#[derive(Debug, Clone)]
+struct HistorySample {
+    timestamp: u64,
+    values: Vec<f32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryQueryResult {
+    pub timestamps: Vec<u64>,
+    pub series: Vec<HistorySeriesValues>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistorySeriesValues {
+    #[serde(flatten)]
+    pub series: HistorySeries,
+    /// NaN values are serialized as null
+    pub values: Vec<f32>,
+}
+
+/// Fixed size ring buffer of downsampled sensor readings
+pub struct HistoryBuffer {
+    bucket_secs: u64,
+    capacity: usize,
+    series: Vec<HistorySeries>,
+    samples: VecDeque<HistorySample>,
+    /// Start of the bucket being accumulated with the sums and counts per series
+    bucket: Option<(u64, Vec<f32>, Vec<u32>)>,
+}
+
+impl HistoryBuffer {
+    /// Create a new HistoryBuffer
+    /// # Arguments
+    /// * `bucket_secs` - The length of a bucket in seconds
+    /// * `capacity` - The max number of buckets kept
+    pub fn new(bucket_secs: u64, capacity: usize) -> Self {
+        assert!(bucket_secs > 0 && capacity > 0);
+        Self {
+            bucket_secs,
+            capacity,
+            series: vec![],
+            samples: VecDeque::with_capacity(capacity),
+            bucket: None,
+        }
+    }
+
+    fn series_index(&mut self, sensor_name: &str, value_name: &str, unit: &str) -> usize {
+        if let Some(index) = self
+            .series
+            .iter()
+            .position(|s| s.sensor_name == sensor_name && s.value_name == value_name)
+        {
+            return index;
+        }
+
+        self.series.push(HistorySeries {
+            sensor_name: sensor_name.into(),
+            value_name: value_name.into(),
+            unit: unit.into(),
+        });
+
+        // Older samples have no values for the new series
+        for sample in self.samples.iter_mut() {
+            sample.values.push(f32::NAN);
+        }
+
+        if let Some((_, sums, counts)) = self.bucket.as_mut() {
+            sums.push(0.0);
+            counts.push(0);
+        }
+
+        self.series.len() - 1
+    }
+
+    /// Add a reading of all sensors
+    /// # Returns
+    /// * true if a bucket was completed by this reading
+    pub fn record(&mut self, sensors_data: &[SensorData], timestamp: u64) -> bool {
+        let bucket_start = timestamp - timestamp % self.bucket_secs;
+
+        let completed = match &self.bucket {
+            Some((start, _, _)) if *start != bucket_start => {
+                self.commit_bucket();
+                true
+            }
+            _ => false,
+        };
+
+        if self.bucket.is_none() {
+            self.bucket = Some((
+                bucket_start,
+                vec![0.0; self.series.len()],
+                vec![0; self.series.len()],
+            ));
+        }
+
+        for sensor in sensors_data {
+            for value in sensor.get_values() {
+                let index = self.series_index(sensor.get_name(), &value.value_name, &value.unit);
+                let (_, sums, counts) = self.bucket.as_mut().unwrap();
+                sums[index] += value.value;
+                counts[index] += 1;
+            }
+        }
+
+        completed
+    }
+
+    fn commit_bucket(&mut self) {
+        if let Some((start, sums, counts)) = self.bucket.take() {
+            let values = sums
+                .iter()
+                .zip(counts.iter())
+                .map(|(sum, count)| {
+                    if *count > 0 {
+                        sum / *count as f32
+                    } else {
+                        f32::NAN
+                    }
+                })
+                .collect();
+
+            self.push_sample(HistorySample {
+                timestamp: start,
+                values,
+            });
+        }
+    }
+
+    fn push_sample(&mut self, sample: HistorySample) {
+        if self.samples.len() >= self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// Get the samples not older than `since`, averaged down to at most `max_points`
+    /// # Arguments
+    /// * `since` - The timestamp of the oldest sample to return
+    /// * `max_points` - The max number of returned points, 0 means no limit
+    pub fn query(&self, since: u64, max_points: usize) -> HistoryQueryResult {
+        let samples: Vec<&HistorySample> = self
+            .samples
+            .iter()
+            .filter(|s| s.timestamp >= since)
+            .collect();
+
+        let group = if max_points > 0 && samples.len() > max_points {
+            (samples.len() + max_points - 1) / max_points
+        } else {
+            1
+        };
+
+        let mut timestamps = vec![];
+        let mut values: Vec<Vec<f32>> = vec![vec![]; self.series.len()];
+
+        for chunk in samples.chunks(group) {
+            timestamps.push(chunk[0].timestamp);
+
+            for (index, series_values) in values.iter_mut().enumerate() {
+                let (sum, count) = chunk
+                    .iter()
+                    .map(|s| s.values[index])
+                    .filter(|v| !v.is_nan())
+                    .fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+
+                series_values.push(if count > 0 {
+                    sum / count as f32
+                } else {
+                    f32::NAN
+                });
+            }
+        }
+
+        HistoryQueryResult {
+            timestamps,
+            series: self
+                .series
+                .iter()
+                .cloned()
+                .zip(values)
+                .map(|(series, values)| HistorySeriesValues { series, values })
+                .collect(),
+        }
+    }
+
+    /// Timestamp of the newest sample
+    pub fn latest_timestamp(&self) -> Option<u64> {
+        self.samples.back().map(|s| s.timestamp)
+    }
+
+    /// Serialize the newest samples which fit into `max_size` bytes
+    fn to_bytes(&self, max_size: usize) -> Vec<u8> {
+        let mut header = vec![];
+        header.extend_from_slice(&FLASH_MAGIC.to_le_bytes());
+        header.extend_from_slice(&(self.series.len() as u16).to_le_bytes());
+        for series in self.series.iter() {
+            for s in [&series.sensor_name, &series.value_name, &series.unit] {
+                header.push(s.len().min(255) as u8);
+                header.extend_from_slice(&s.as_bytes()[..s.len().min(255)]);
+            }
+        }
+
+        let sample_size = 8 + 4 * self.series.len();
+        let fit = max_size.saturating_sub(header.len() + 4) / sample_size;
+        let count = self.samples.len().min(fit);
+
+        let mut bytes = header;
+        bytes.extend_from_slice(&(count as u32).to_le_bytes());
+        for sample in self.samples.iter().skip(self.samples.len() - count) {
+            bytes.extend_from_slice(&sample.timestamp.to_le_bytes());
+            for value in sample.values.iter() {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+
+        bytes
+    }
+
+    /// Restore the samples serialized by `to_bytes`
+    fn load_bytes(&mut self, bytes: &[u8]) -> Option<()> {
+        let mut reader = ByteReader { bytes, pos: 0 };
+
+        if u32::from_le_bytes(reader.take()?) != FLASH_MAGIC {
+            return None;
+        }
+
+        let series_count = u16::from_le_bytes(reader.take()?) as usize;
+        let mut series = Vec::with_capacity(series_count);
+        for _ in 0..series_count {
+            let mut strings = vec![];
+            for _ in 0..3 {
+                let len = reader.take::<1>()?[0] as usize;
+                strings.push(String::from_utf8(reader.take_slice(len)?.to_vec()).ok()?);
+            }
+            series.push(HistorySeries {
+                unit: strings.pop()?,
+                value_name: strings.pop()?,
+                sensor_name: strings.pop()?,
+            });
+        }
+
+        let count = u32::from_le_bytes(reader.take()?) as usize;
+        let mut samples = VecDeque::with_capacity(self.capacity);
+        for _ in 0..count {
+            let timestamp = u64::from_le_bytes(reader.take()?);
+            let mut values = Vec::with_capacity(series_count);
+            for _ in 0..series_count {
+                values.push(f32::from_le_bytes(reader.take()?));
+            }
+            samples.push_back(HistorySample { timestamp, values });
+        }
+
+        while samples.len() > self.capacity {
+            samples.pop_front();
+        }
+
+        self.series = series;
+        self.samples = samples;
+        self.bucket = None;
+
+        Some(())
+    }
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take_slice(&mut self, len: usize) -> Option<&'a [u8]> {
+        let slice = self.bytes.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(slice)
+    }
+
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.take_slice(N)?.try_into().ok()
+    }
+}
+
+struct History {
+    buffer: HistoryBuffer,
+    flash_backed: bool,
+    buckets_since_save: u32,
+}
+
+lazy_static! {
+    static ref HISTORY: Mutex<History> = Mutex::new(History {
+        buffer: HistoryBuffer::new(DEFAULT_BUCKET_SECS, DEFAULT_CAPACITY),
+        flash_backed: false,
+        buckets_since_save: 0,
+    });
+}
+
+/// Set up the history buffer
+/// # Arguments
+/// * `flash_backed` - Periodically save the buffer to NVS and restore it at boot
+pub fn history_init(flash_backed: bool) {
+    let mut history = HISTORY.lock().unwrap();
+    history.flash_backed = flash_backed;
+
+    if !flash_backed {
+        return;
+    }
+
+    let chunks = storage_load::<usize>(HISTORY_CHUNKS_KEY).unwrap_or(0);
+    let mut bytes = vec![];
+    for chunk in 0..chunks {
+        match storage_load_raw(&format!("hist{}", chunk)) {
+            Some(raw) => bytes.extend_from_slice(&raw),
+            None => return,
+        }
+    }
+
+    if !bytes.is_empty() {
+        match history.buffer.load_bytes(&bytes) {
+            Some(()) => info!(
+                "Restored history from flash, latest at {:?}",
+                history.buffer.latest_timestamp()
+            ),
+            None => warn!("Cannot restore history from flash"),
+        }
+    }
+}
+
+fn history_save(buffer: &HistoryBuffer) -> Result<(), Error> {
+    let bytes = buffer.to_bytes(MAX_BLOB_SIZE * FLASH_MAX_CHUNKS);
+
+    let previous_chunks = storage_load::<usize>(HISTORY_CHUNKS_KEY).unwrap_or(0);
+
+    let chunks: Vec<&[u8]> = bytes.chunks(MAX_BLOB_SIZE).collect();
+    for (index, chunk) in chunks.iter().enumerate() {
+        storage_store_raw(&format!("hist{}", index), chunk)?;
+    }
+
+    storage_store(HISTORY_CHUNKS_KEY, &chunks.len())?;
+
+    // The chunks of a longer history are no longer read, free their space
+    for index in chunks.len()..previous_chunks {
+        if let Err(e) = storage_remove(&format!("hist{}", index)) {
+            warn!("Cannot remove history chunk {}: {:?}", index, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Add a reading of all sensors to the history
+/// # Arguments
+/// * `sensors_data` - The data of all sensors
+/// * `timestamp` - The time of the reading in seconds
+pub fn history_record(sensors_data: &[SensorData], timestamp: u64) {
+    let mut history = HISTORY.lock().unwrap();
+
+    if !history.buffer.record(sensors_data, timestamp) || !history.flash_backed {
+        return;
+    }
+
+    history.buckets_since_save += 1;
+    if history.buckets_since_save >= FLASH_SAVE_EVERY {
+        history.buckets_since_save = 0;
+        if let Err(e) = history_save(&history.buffer) {
+            warn!("Cannot save history to flash: {:?}", e);
+        }
+    }
+}
+
+/// Get the history of the last hours
+/// # Arguments
+/// * `hours` - How many hours back from the newest sample
+/// * `max_points` - The max number of returned points, 0 means no limit
+pub fn history_query(hours: u32, max_points: usize) -> HistoryQueryResult {
+    let history = HISTORY.lock().unwrap();
+    let since = history
+        .buffer
+        .latest_timestamp()
+        .map(|latest| latest.saturating_sub(hours as u64 * 3600))
+        .unwrap_or(0);
+
+    history.buffer.query(since, max_points)
+}
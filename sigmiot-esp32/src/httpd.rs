@@ -1,84 +1,721 @@
-
 use std::cell::RefCell;
+use std::ffi::{c_void, CStr, CString};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Mutex;
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 
 use embassy_sync::blocking_mutex;
-use embedded_svc::http::server::Method;
-use embedded_svc::io::Write;
+use embedded_svc::http::server::{HandlerResult, Method, Request};
+use embedded_svc::http::Headers;
+use embedded_svc::io::{Read, Write};
 
-use esp_idf_svc::http::server::{EspHttpServer, ws::EspHttpWsProcessor};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use embedded_svc::ws::asynch::server::Acceptor;
 use esp_idf_hal::task::embassy_sync::EspRawMutex;
+use esp_idf_svc::handle::RawHandle;
+use esp_idf_svc::http::server::ws::{EspHttpWsConnection, EspHttpWsProcessor};
+use esp_idf_svc::http::server::{self as esp_server, EspHttpConnection, EspHttpServer};
+use esp_idf_svc::tls::X509;
+use esp_idf_sys::EspError;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::api::{find_reading, LogPage};
+use crate::auth::{auth_check, auth_config_set, auth_status};
+use crate::auth_check::AuthConfig;
+use crate::data_channel::{get_http_data, latest_readings};
+use crate::device::{device_info, device_restart};
+use crate::device_config::{device_config_get, device_config_import, device_config_update};
+use crate::diagnostics::{diagnostics, health, i2c_scan};
+use crate::history::history_query;
+use crate::httpd_config::{HttpdConfig, WS_MAX_CON_LIMIT, WS_MAX_FRAME_SIZE};
+use crate::log_buffer::LogCursor;
+use crate::network::{network_config_load, network_config_store, NetworkConfig};
+use crate::ota::{ota_abort, ota_begin, ota_finish, ota_progress, ota_write};
+use crate::ota_image::{parse_sha256_hex, OtaManifest, OtaProgress};
+use crate::sensors::sensors_force_read_blocking;
+use crate::sigmiot_log::{
+    log_levels_get, log_levels_set, remote_logger_get_entries, remote_logger_oldest,
+    remote_logger_overwritten, LogFilter, LogLevelConfig,
+};
+use crate::storage::{storage_erase_all, MAX_BLOB_SIZE};
+use crate::tls::{tls_config_set, tls_credentials, TlsConfig};
+use crate::web::web_register;
+use crate::wifi::{wifi_networks_add, wifi_networks_load, wifi_networks_remove};
+use crate::wifi_policy::KnownNetwork;
 
-use crate::data_channel::get_http_data;
+/// Max size of a JSON request body
+const MAX_BODY_SIZE: usize = 2048;
+/// Max size of the TLS request body, with a PEM certificate and key
+const MAX_TLS_BODY_SIZE: usize = 8192;
+/// Size of the parts of an uploaded firmware image written at once
+const OTA_CHUNK_SIZE: usize = 4096;
+/// Prefix of the sensor URIs, followed by the sensor name
+const API_SENSORS_URI: &str = "/api/v1/sensors";
+/// Prefix of the Wi-Fi network URIs, followed by the SSID
+const API_WIFI_NETWORKS_URI: &str = "/api/v1/wifi/networks";
 
-pub struct Config {
-    ws_max_con: usize,
-    ws_max_frame_size: usize,
+lazy_static! {
+    static ref HTTPD_CONFIG: Mutex<HttpdConfig> = Mutex::new(HttpdConfig::default());
+    /// The sockets of the open WebSocket connections, the processor is sized for WS_MAX_CON_LIMIT
+    static ref WS_SOCKETS: Mutex<Vec<i32>> = Mutex::new(Vec::new());
 }
 
-const CONFIG: Config = Config {
-    ws_max_con: 2,
-    ws_max_frame_size: 4096,
-};
+/// The running server, null until it is started
+static HTTPD_HANDLE: AtomicPtr<c_void> = AtomicPtr::new(std::ptr::null_mut());
+
+/// Load the server configuration from the device config
+pub fn httpd_config_load() {
+    let config = device_config_get().httpd;
+
+    info!("HTTP server config: {:?}", config);
+    *HTTPD_CONFIG.lock().unwrap() = config;
+}
+
+/// Validate and store the server configuration, it is applied at the next boot
+pub fn httpd_config_store(config: &HttpdConfig) -> Result<(), Error> {
+    device_config_update(|device| device.httpd = config.clone()).map(|_| ())
+}
+
+/// The configuration the server was started with
+pub fn httpd_config_get() -> HttpdConfig {
+    HTTPD_CONFIG.lock().unwrap().clone()
+}
+
+/// Number of open WebSocket connections
+pub fn ws_client_count() -> u32 {
+    WS_SOCKETS.lock().unwrap().len() as u32
+}
+
+/// Number of open HTTP connections, the WebSocket connections excluded
+pub fn http_client_count() -> u32 {
+    let handle = HTTPD_HANDLE.load(Ordering::Relaxed);
+    if handle.is_null() {
+        return 0;
+    }
+
+    let mut sockets = vec![0_i32; httpd_config_get().max_open_sockets];
+    let mut count = sockets.len();
+    let listed =
+        unsafe { esp_idf_sys::httpd_get_client_list(handle, &mut count, sockets.as_mut_ptr()) };
+    if listed != esp_idf_sys::ESP_OK {
+        return 0;
+    }
+
+    (count as u32).saturating_sub(ws_client_count())
+}
 
 pub fn httpd() -> Result<(EspHttpServer, impl Acceptor), Error> {
+    let config = httpd_config_get();
 
     let (ws_processor, ws_acceptor) =
-        EspHttpWsProcessor::<{ CONFIG.ws_max_con}, { CONFIG.ws_max_frame_size }>::new(());
+        EspHttpWsProcessor::<WS_MAX_CON_LIMIT, WS_MAX_FRAME_SIZE>::new(());
 
     let ws_processor = blocking_mutex::Mutex::<EspRawMutex, _>::new(RefCell::new(ws_processor));
 
-    let mut server =  EspHttpServer::new(&Default::default())?;
+    let mut server_config = esp_server::Configuration {
+        http_port: config.http_port,
+        https_port: config.https_port,
+        max_open_sockets: config.max_open_sockets,
+        max_uri_handlers: config.max_uri_handlers,
+        stack_size: config.stack_size,
+        // For the URIs with a parameter in the path like /api/v1/sensors/{name}
+        uri_match_wildcard: true,
+        ..Default::default()
+    };
 
-    server
-        .fn_handler("/sensors", Method::Get, move|req| {
+    if let Some(tls) = tls_credentials() {
+        server_config.server_certificate = Some(X509::pem_until_nul(tls.cert));
+        server_config.private_key = Some(X509::pem_until_nul(tls.key));
+    }
+
+    let mut server = EspHttpServer::new(&server_config)?;
+    HTTPD_HANDLE.store(server.handle() as *mut c_void, Ordering::Relaxed);
+
+    if config.json_enabled {
+        register_json_routes(&mut server)?;
+    }
+
+    if config.html_enabled {
+        server.auth_fn_handler("/sensors", Method::Get, move |req| {
             let raw_data = get_http_data();
-            req.into_ok_response()?
-                .write_all(raw_data.as_bytes())?;
-
-            Ok(())
-        })?
-        .fn_handler("/", Method::Get, move|req| {
-            let response =
-                r#"
-                <!DOCTYPE html>
-                <html>
-                <head>
-                    <title>Auto-Update Example</title>
-                </head>
-                <body>
-                    <h1>Sensor Data</h1>
-                    <div id="sensor-data"></div>
-                    <script>
-                        function updateSensorData() {{
-                            var xhr = new XMLHttpRequest();
-                            xhr.onreadystatechange = function() {{
-                                if (this.readyState == 4 && this.status == 200) {{
-                                    var sensorDataDiv = document.getElementById("sensor-data");
-                                    sensorDataDiv.innerHTML = this.responseText;
-                                }}
-                            }};
-                            xhr.open("GET", "/sensors", true);
-                            xhr.send();
-                        }}
-                        setInterval(updateSensorData, 1000);
-                    </script>
-                </body>
-                </html>
-                "#.to_string();
-            req.into_ok_response()?
-                .write_all(response.as_bytes())?;
-
-            println!("Client connected");
+            req.into_ok_response()?.write_all(raw_data.as_bytes())?;
 
             Ok(())
         })?;
 
-    server.ws_handler("/ws", move |connection| {
-        ws_processor.lock(|ws_processor| ws_processor.borrow_mut().process(connection))
-    })?;
+        // The dashboard files hold no device data and stay public,
+        // the page sends the credentials with its API and WebSocket requests
+        web_register(&mut server)?;
+    }
+
+    if config.ws_enabled {
+        let ws_max_connections = config.ws_max_connections;
+
+        server.ws_handler("/ws", move |connection| {
+            match connection {
+                // ESP-IDF has already answered the upgrade when the handler sees the new
+                // connection, so a rejected client is disconnected instead of getting
+                // an error status
+                EspHttpWsConnection::New(_, raw_req) => {
+                    if !ws_authorized(*raw_req) {
+                        warn!("Unauthorized WebSocket client disconnected");
+                        return Err(EspError::from(esp_idf_sys::ESP_FAIL).unwrap());
+                    }
+
+                    let socket = unsafe { esp_idf_sys::httpd_req_to_sockfd(*raw_req) };
+                    let mut sockets = WS_SOCKETS.lock().unwrap();
+                    if sockets.len() >= ws_max_connections {
+                        drop(sockets);
+                        warn!(
+                            "WebSocket client disconnected, max is {} connections",
+                            ws_max_connections
+                        );
+                        return Err(EspError::from(esp_idf_sys::ESP_FAIL).unwrap());
+                    }
+                    sockets.push(socket);
+                }
+                EspHttpWsConnection::Closed(socket) => {
+                    WS_SOCKETS.lock().unwrap().retain(|open| *open != *socket);
+                }
+                _ => (),
+            }
+
+            ws_processor.lock(|ws_processor| ws_processor.borrow_mut().process(connection))
+        })?;
+    }
 
     Ok((server, ws_acceptor))
 }
+
+fn register_json_routes(server: &mut EspHttpServer) -> Result<(), Error> {
+    server
+        .auth_fn_handler(API_SENSORS_URI, Method::Get, move |req| {
+            json_response(req, 200, &latest_readings())?;
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/sensors/*", Method::Get, move |req| {
+            let name = url_decode(path_param(req.uri(), API_SENSORS_URI));
+            let readings = latest_readings();
+
+            match find_reading(&readings, &name) {
+                Some(reading) => json_response(req, 200, reading)?,
+                None => json_error(req, 404, &format!("no sensor named {}", name))?,
+            }
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/device", Method::Get, move |req| {
+            json_response(req, 200, &device_info())?;
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/logs", Method::Get, move |req| {
+            let mut cursor = query_param(req.uri(), "since")
+                .and_then(|since| since.parse::<u64>().ok())
+                .map(LogCursor::at)
+                .unwrap_or_else(remote_logger_oldest);
+            let level = query_param(req.uri(), "level").unwrap_or_default();
+            let targets = query_param(req.uri(), "target")
+                .map(|target| vec![target.to_string()])
+                .unwrap_or_default();
+
+            let filter = match LogFilter::new(level, targets) {
+                Ok(filter) => filter,
+                Err(e) => {
+                    json_error(req, 400, &e.to_string())?;
+                    return Ok(());
+                }
+            };
+
+            let entries = remote_logger_get_entries(&mut cursor, &filter)
+                .iter()
+                .map(|entry| entry.to_record())
+                .collect();
+
+            json_response(
+                req,
+                200,
+                &LogPage {
+                    entries,
+                    next_seq: cursor.seq(),
+                    overwritten: remote_logger_overwritten(),
+                },
+            )?;
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/history", Method::Get, move |req| {
+            let hours = query_param(req.uri(), "hours")
+                .and_then(|h| h.parse::<u32>().ok())
+                .unwrap_or(1);
+            let max_points = query_param(req.uri(), "max_points")
+                .and_then(|p| p.parse::<usize>().ok())
+                .unwrap_or(0);
+
+            let history = serde_json::to_vec(&history_query(hours, max_points))?;
+
+            req.into_response(200, None, &[("Content-Type", "application/json")])?
+                .write_all(&history)?;
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/log_levels", Method::Get, move |req| {
+            let levels = serde_json::to_vec(&log_levels_get())?;
+
+            req.into_response(200, None, &[("Content-Type", "application/json")])?
+                .write_all(&levels)?;
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/log_levels", Method::Post, move |mut req| {
+            let body = read_body(&mut req)?;
+
+            let result = serde_json::from_slice::<LogLevelConfig>(&body)
+                .map_err(|e| e.to_string())
+                .and_then(|config| log_levels_set(config).map_err(|e| e.to_string()));
+
+            match result {
+                Ok(()) => {
+                    let levels = serde_json::to_vec(&log_levels_get())?;
+                    req.into_response(200, None, &[("Content-Type", "application/json")])?
+                        .write_all(&levels)?;
+                }
+                Err(e) => {
+                    req.into_response(400, Some("Bad Request"), &[])?
+                        .write_all(e.as_bytes())?;
+                }
+            }
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/network", Method::Get, move |req| {
+            json_response(req, 200, &network_config_load())?;
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/network", Method::Post, move |mut req| {
+            let body = read_body(&mut req)?;
+
+            let result = serde_json::from_slice::<NetworkConfig>(&body)
+                .map_err(Error::from)
+                .and_then(|config| network_config_store(&config));
+
+            match result {
+                Ok(()) => json_response(req, 200, &network_config_load())?,
+                Err(e) => json_error(req, 400, &e.to_string())?,
+            }
+
+            Ok(())
+        })?
+        .auth_fn_handler(API_WIFI_NETWORKS_URI, Method::Get, move |req| {
+            // The passwords are never sent
+            let networks: Vec<_> = wifi_networks_load()
+                .into_iter()
+                .map(|network| {
+                    serde_json::json!({
+                        "ssid": network.ssid,
+                        "priority": network.priority,
+                    })
+                })
+                .collect();
+
+            json_response(req, 200, &networks)?;
+
+            Ok(())
+        })?
+        .auth_fn_handler(API_WIFI_NETWORKS_URI, Method::Post, move |mut req| {
+            let body = read_body(&mut req)?;
+
+            let result = serde_json::from_slice::<KnownNetwork>(&body)
+                .map_err(Error::from)
+                .and_then(wifi_networks_add);
+
+            match result {
+                Ok(()) => json_response(req, 200, &serde_json::json!({}))?,
+                Err(e) => json_error(req, 400, &e.to_string())?,
+            }
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/wifi/networks/*", Method::Delete, move |req| {
+            let ssid = url_decode(path_param(req.uri(), API_WIFI_NETWORKS_URI));
+
+            match wifi_networks_remove(&ssid) {
+                Ok(true) => json_response(req, 200, &serde_json::json!({}))?,
+                Ok(false) => json_error(req, 404, &format!("no network named {}", ssid))?,
+                Err(e) => json_error(req, 500, &e.to_string())?,
+            }
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/auth", Method::Get, move |req| {
+            let (token_set, username) = auth_status();
+
+            let status = serde_json::json!({
+                "token_set": token_set,
+                "username": username,
+            });
+            json_response(req, 200, &status)?;
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/auth", Method::Post, move |mut req| {
+            let body = read_body(&mut req)?;
+
+            let result = serde_json::from_slice::<AuthConfig>(&body)
+                .map_err(Error::from)
+                .and_then(auth_config_set);
+
+            match result {
+                Ok(()) => json_response(req, 200, &serde_json::json!({}))?,
+                Err(e) => json_error(req, 400, &e.to_string())?,
+            }
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/tls", Method::Post, move |mut req| {
+            let body = read_body_max(&mut req, MAX_TLS_BODY_SIZE)?;
+
+            let result = serde_json::from_slice::<TlsRequest>(&body)
+                .map_err(Error::from)
+                .and_then(|tls| {
+                    let cert_key = tls.cert_pem.as_deref().zip(tls.key_pem.as_deref());
+                    tls_config_set(
+                        TlsConfig {
+                            enabled: tls.enabled,
+                        },
+                        cert_key,
+                    )
+                });
+
+            match result {
+                Ok(()) => json_response(req, 200, &serde_json::json!({}))?,
+                Err(e) => json_error(req, 400, &e.to_string())?,
+            }
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/httpd", Method::Get, move |req| {
+            json_response(req, 200, &device_config_get().httpd)?;
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/httpd", Method::Post, move |mut req| {
+            let body = read_body(&mut req)?;
+
+            let result = serde_json::from_slice::<HttpdConfig>(&body)
+                .map_err(Error::from)
+                .and_then(|config| httpd_config_store(&config).map(|()| config));
+
+            match result {
+                Ok(config) => json_response(req, 200, &config)?,
+                Err(e) => json_error(req, 400, &e.to_string())?,
+            }
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/config", Method::Get, move |req| {
+            json_response(req, 200, &device_config_get())?;
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/ota", Method::Post, move |mut req| {
+            // The image is the body, its digest and signature are in the headers
+            let result = ota_manifest(&req)
+                .and_then(ota_begin)
+                .and_then(|_| ota_receive(&mut req))
+                .and_then(|progress| ota_finish().map(|()| progress));
+
+            match result {
+                Ok(progress) => {
+                    json_response(req, 200, &progress)?;
+                    device_restart();
+                }
+                Err(e) => {
+                    ota_abort();
+                    json_error(req, 400, &e.to_string())?;
+                }
+            }
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/config", Method::Post, move |mut req| {
+            // A whole exported config, it replaces the current one
+            let body = read_body_max(&mut req, MAX_BLOB_SIZE)?;
+
+            match device_config_import(&body) {
+                Ok(config) => json_response(req, 200, &config)?,
+                Err(e) => json_error(req, 400, &e.to_string())?,
+            }
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/sensors/read", Method::Post, move |req| {
+            // Waits for the sensors loop to read them
+            match sensors_force_read_blocking() {
+                Ok(readings) => json_response(req, 200, &readings)?,
+                Err(e) => json_error(req, 503, &e.to_string())?,
+            }
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/device/reboot", Method::Post, move |req| {
+            info!("Reboot requested");
+            json_response(req, 200, &serde_json::json!({}))?;
+            device_restart();
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/device/factory-reset", Method::Post, move |req| {
+            warn!("Factory reset requested, erasing the settings");
+
+            match storage_erase_all() {
+                Ok(()) => {
+                    json_response(req, 200, &serde_json::json!({}))?;
+                    device_restart();
+                }
+                Err(e) => json_error(req, 500, &e.to_string())?,
+            }
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/diagnostics", Method::Get, move |req| {
+            json_response(req, 200, &diagnostics())?;
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/health", Method::Get, move |req| {
+            json_response(req, 200, &health())?;
+
+            Ok(())
+        })?
+        .auth_fn_handler("/api/v1/diagnostics/i2c", Method::Get, move |req| {
+            match i2c_scan() {
+                Ok(scan) => json_response(req, 200, &scan)?,
+                Err(e) => json_error(req, 500, &e.to_string())?,
+            }
+
+            Ok(())
+        })?;
+
+    Ok(())
+}
+
+/// Registration of the handlers behind the client authentication
+trait AuthHandler {
+    fn auth_fn_handler<F>(
+        &mut self,
+        uri: &str,
+        method: Method,
+        f: F,
+    ) -> Result<&mut Self, EspError>
+    where
+        F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> HandlerResult + Send + 'static;
+}
+
+impl AuthHandler for EspHttpServer {
+    fn auth_fn_handler<F>(&mut self, uri: &str, method: Method, f: F) -> Result<&mut Self, EspError>
+    where
+        F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> HandlerResult + Send + 'static,
+    {
+        self.fn_handler(uri, method, move |req| {
+            if !auth_check(req.header("Authorization"), query_param(req.uri(), "token")) {
+                req.into_response(
+                    401,
+                    Some("Unauthorized"),
+                    &[("WWW-Authenticate", "Basic realm=\"sigmiot\"")],
+                )?;
+                return Ok(());
+            }
+
+            f(req)
+        })
+    }
+}
+
+/// Check the credentials of a WebSocket upgrade request
+fn ws_authorized(raw_req: *mut esp_idf_sys::httpd_req_t) -> bool {
+    let authorization = raw_header(raw_req, "Authorization");
+    let uri = unsafe { CStr::from_ptr((*raw_req).uri.as_ptr()) }.to_string_lossy();
+
+    auth_check(authorization.as_deref(), query_param(&uri, "token"))
+}
+
+/// Get a header of a request from the ESP-IDF server
+fn raw_header(raw_req: *mut esp_idf_sys::httpd_req_t, name: &str) -> Option<String> {
+    let name = CString::new(name).ok()?;
+
+    let len = unsafe { esp_idf_sys::httpd_req_get_hdr_value_len(raw_req, name.as_ptr()) } as usize;
+    if len == 0 {
+        return None;
+    }
+
+    let mut value = vec![0_u8; len + 1];
+    let result = unsafe {
+        esp_idf_sys::httpd_req_get_hdr_value_str(
+            raw_req,
+            name.as_ptr(),
+            value.as_mut_ptr() as *mut _,
+            value.len() as _,
+        )
+    };
+    if result != esp_idf_sys::ESP_OK {
+        return None;
+    }
+
+    value.truncate(len);
+    String::from_utf8(value).ok()
+}
+
+/// Reply with a value serialized to JSON
+fn json_response<T: Serialize + ?Sized>(
+    req: Request<&mut EspHttpConnection>,
+    status: u16,
+    value: &T,
+) -> Result<(), Error> {
+    let body = serde_json::to_vec(value)?;
+
+    req.into_response(status, None, &[("Content-Type", "application/json")])?
+        .write_all(&body)
+        .map_err(|e| anyhow!("{:?}", e))
+}
+
+/// Reply with a JSON error object like `{"error": "no sensor named x"}`
+fn json_error(
+    req: Request<&mut EspHttpConnection>,
+    status: u16,
+    message: &str,
+) -> Result<(), Error> {
+    json_response(req, status, &serde_json::json!({ "error": message }))
+}
+
+/// Get the part of the request URI path after a prefix, e.g. the sensor name
+/// of /api/v1/sensors/{name}
+fn path_param<'a>(uri: &'a str, prefix: &str) -> &'a str {
+    let path = uri.split_once('?').map(|(path, _)| path).unwrap_or(uri);
+
+    path.strip_prefix(prefix)
+        .unwrap_or_default()
+        .trim_start_matches('/')
+}
+
+/// Get the value of a query parameter from the request URI
+fn query_param<'a>(uri: &'a str, name: &str) -> Option<&'a str> {
+    let (_, query) = uri.split_once('?')?;
+
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// The image announced by the headers of POST /api/v1/ota: its size as Content-Length,
+/// its digest as `X-Firmware-SHA256` in hex and its optional signature as
+/// `X-Firmware-Signature` in base64
+fn ota_manifest(req: &impl Headers) -> Result<OtaManifest, Error> {
+    let size = req
+        .content_len()
+        .ok_or_else(|| anyhow!("Content-Length is required"))?;
+
+    let sha256 = req
+        .header("X-Firmware-SHA256")
+        .ok_or_else(|| anyhow!("X-Firmware-SHA256 is required"))
+        .and_then(parse_sha256_hex)?;
+
+    let signature = match req.header("X-Firmware-Signature") {
+        Some(signature) => BASE64
+            .decode(signature.trim())
+            .map_err(|_| anyhow!("X-Firmware-Signature must be base64"))?,
+        None => vec![],
+    };
+
+    Ok(OtaManifest {
+        size: size.min(u32::MAX as u64) as u32,
+        sha256,
+        signature,
+    })
+}
+
+/// Write the request body to the update started by `ota_begin`
+fn ota_receive<R: Read>(req: &mut R) -> Result<OtaProgress, Error> {
+    let mut buf = vec![0_u8; OTA_CHUNK_SIZE];
+    let mut progress = ota_progress().ok_or_else(|| anyhow!("no update in progress"))?;
+
+    loop {
+        let size = req.read(&mut buf).map_err(|e| anyhow!("{:?}", e))?;
+        if size == 0 {
+            break;
+        }
+
+        progress = ota_write(progress.received, &buf[..size])?;
+    }
+
+    Ok(progress)
+}
+
+/// The body of POST /api/v1/tls
+#[derive(Deserialize)]
+struct TlsRequest {
+    enabled: bool,
+    /// Keep the stored certificate if not given
+    cert_pem: Option<String>,
+    key_pem: Option<String>,
+}
+
+/// Read the request body, at most MAX_BODY_SIZE bytes
+pub fn read_body<R: Read>(req: &mut R) -> Result<Vec<u8>, Error> {
+    read_body_max(req, MAX_BODY_SIZE)
+}
+
+/// Read the request body, at most `max_size` bytes
+pub fn read_body_max<R: Read>(req: &mut R, max_size: usize) -> Result<Vec<u8>, Error> {
+    let mut body = vec![];
+    let mut buf = [0_u8; 256];
+
+    loop {
+        let size = req.read(&mut buf).map_err(|e| anyhow!("{:?}", e))?;
+        if size == 0 {
+            break;
+        }
+
+        if body.len() + size > max_size {
+            return Err(anyhow!("request body is too big"));
+        }
+        body.extend_from_slice(&buf[..size]);
+    }
+
+    Ok(body)
+}
+
+/// Decode a percent-encoded URI component or form value
+pub fn url_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
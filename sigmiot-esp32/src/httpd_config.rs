@@ -0,0 +1,189 @@
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+/// Max number of simultaneous WebSocket connections the firmware is built for,
+/// it sizes the WebSocket processor and the data channel
+pub const WS_MAX_CON_LIMIT: usize = 4;
+/// Max size of a received WebSocket frame
+pub const WS_MAX_FRAME_SIZE: usize = 4096;
+/// lwIP allows 16 sockets and the server keeps 3 for itself
+const MAX_OPEN_SOCKETS_LIMIT: usize = 13;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpdConfig {
+    pub http_port: u16,
+    /// Used instead of http_port when TLS is enabled
+    pub https_port: u16,
+    pub max_open_sockets: usize,
+    pub max_uri_handlers: usize,
+    /// Stack size of the server task in bytes
+    pub stack_size: usize,
+    /// At most WS_MAX_CON_LIMIT
+    pub ws_max_connections: usize,
+    /// The dashboard and /sensors
+    pub html_enabled: bool,
+    /// The /api routes
+    pub json_enabled: bool,
+    /// The /ws route
+    pub ws_enabled: bool,
+}
+
+impl Default for HttpdConfig {
+    fn default() -> Self {
+        Self {
+            http_port: 80,
+            https_port: 443,
+            max_open_sockets: 7,
+            max_uri_handlers: 40,
+            stack_size: 10240,
+            ws_max_connections: 2,
+            html_enabled: true,
+            json_enabled: true,
+            ws_enabled: true,
+        }
+    }
+}
+
+impl HttpdConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.http_port == 0 || self.https_port == 0 {
+            return Err(anyhow!("ports must not be 0"));
+        }
+
+        if self.ws_max_connections == 0 || self.ws_max_connections > WS_MAX_CON_LIMIT {
+            return Err(anyhow!(
+                "WebSocket connections must be 1 to {}",
+                WS_MAX_CON_LIMIT
+            ));
+        }
+
+        // The WebSocket connections keep their sockets, the pages need one more
+        if self.max_open_sockets <= self.ws_max_connections
+            || self.max_open_sockets > MAX_OPEN_SOCKETS_LIMIT
+        {
+            return Err(anyhow!(
+                "open sockets must be more than the WebSocket connections and at most {}",
+                MAX_OPEN_SOCKETS_LIMIT
+            ));
+        }
+
+        if !(8..=64).contains(&self.max_uri_handlers) {
+            return Err(anyhow!("URI handlers must be 8 to 64"));
+        }
+
+        if !(4096..=32768).contains(&self.stack_size) {
+            return Err(anyhow!("stack size must be 4096 to 32768 bytes"));
+        }
+
+        if !self.html_enabled && !self.json_enabled && !self.ws_enabled {
+            return Err(anyhow!(
+                "at least one of the HTML, JSON and WebSocket routes must be enabled"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The port of the server
+    /// # Arguments
+    /// * `secure` - TLS is enabled
+    pub fn port(&self, secure: bool) -> u16 {
+        if secure {
+            self.https_port
+        } else {
+            self.http_port
+        }
+    }
+
+    /// The number of WebSocket connection handlers to run
+    pub fn ws_handlers(&self) -> usize {
+        if self.ws_enabled {
+            self.ws_max_connections
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid(update: impl FnOnce(&mut HttpdConfig)) -> bool {
+        let mut config = HttpdConfig::default();
+        update(&mut config);
+        config.validate().is_ok()
+    }
+
+    #[test]
+    fn the_default_is_valid() {
+        assert!(HttpdConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn ports_must_be_set() {
+        assert!(!valid(|c| c.http_port = 0));
+        assert!(!valid(|c| c.https_port = 0));
+        assert!(valid(|c| c.http_port = u16::MAX));
+    }
+
+    #[test]
+    fn websocket_connections_bounds() {
+        assert!(!valid(|c| c.ws_max_connections = 0));
+        assert!(valid(|c| c.ws_max_connections = 1));
+        assert!(valid(|c| c.ws_max_connections = WS_MAX_CON_LIMIT));
+        assert!(!valid(|c| c.ws_max_connections = WS_MAX_CON_LIMIT + 1));
+    }
+
+    #[test]
+    fn open_sockets_leave_one_for_the_pages() {
+        assert!(!valid(|c| {
+            c.ws_max_connections = 4;
+            c.max_open_sockets = 4;
+        }));
+        assert!(valid(|c| {
+            c.ws_max_connections = 4;
+            c.max_open_sockets = 5;
+        }));
+        assert!(valid(|c| c.max_open_sockets = MAX_OPEN_SOCKETS_LIMIT));
+        assert!(!valid(|c| c.max_open_sockets = MAX_OPEN_SOCKETS_LIMIT + 1));
+    }
+
+    #[test]
+    fn uri_handlers_and_stack_size_bounds() {
+        assert!(!valid(|c| c.max_uri_handlers = 7));
+        assert!(valid(|c| c.max_uri_handlers = 8));
+        assert!(valid(|c| c.max_uri_handlers = 64));
+        assert!(!valid(|c| c.max_uri_handlers = 65));
+
+        assert!(!valid(|c| c.stack_size = 4095));
+        assert!(valid(|c| c.stack_size = 4096));
+        assert!(valid(|c| c.stack_size = 32768));
+        assert!(!valid(|c| c.stack_size = 32769));
+    }
+
+    #[test]
+    fn one_kind_of_route_must_be_enabled() {
+        assert!(valid(|c| {
+            c.html_enabled = false;
+            c.json_enabled = false;
+        }));
+        assert!(!valid(|c| {
+            c.html_enabled = false;
+            c.json_enabled = false;
+            c.ws_enabled = false;
+        }));
+    }
+
+    #[test]
+    fn no_websocket_handlers_when_disabled() {
+        let mut config = HttpdConfig::default();
+        assert_eq!(config.ws_handlers(), config.ws_max_connections);
+
+        config.ws_enabled = false;
+        assert_eq!(config.ws_handlers(), 0);
+        assert_eq!(config.port(true), 443);
+        assert_eq!(config.port(false), 80);
+    }
+}
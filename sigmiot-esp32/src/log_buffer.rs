@@ -0,0 +1,221 @@
+use std::collections::VecDeque;
+
+/// Position of a reader in a LogRing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LogCursor {
+    next_seq: u64,
+}
+
+impl LogCursor {
+    /// A cursor at a sequence number returned by `seq`,
+    /// for the clients which keep their position themselves
+    pub fn at(seq: u64) -> Self {
+        Self { next_seq: seq }
+    }
+
+    /// The sequence number of the next entry to read
+    pub fn seq(&self) -> u64 {
+        self.next_seq
+    }
+}
+
+/// The result of a read from a LogRing
+#[derive(Debug)]
+pub struct LogRead<T> {
+    pub entries: Vec<T>,
+    /// Number of entries overwritten before the reader got to them
+    pub dropped: u64,
+}
+
+/// Bounded ring buffer which overwrites the oldest entries when it is full.
+/// Every entry gets a sequence number so readers can detect the overwritten ones.
+pub struct LogRing<T> {
+    capacity: usize,
+    entries: VecDeque<T>,
+    /// Sequence number of the oldest entry in the buffer
+    first_seq: u64,
+    /// Total number of entries overwritten since boot
+    overwritten: u64,
+}
+
+impl<T: Clone> LogRing<T> {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
+        Self {
+            capacity,
+            entries: VecDeque::new(),
+            first_seq: 0,
+            overwritten: 0,
+        }
+    }
+
+    pub fn push(&mut self, entry: T) {
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+            self.first_seq += 1;
+            self.overwritten += 1;
+        }
+
+        self.entries.push_back(entry);
+    }
+
+    /// A cursor at the oldest entry still in the buffer
+    pub fn cursor_at_start(&self) -> LogCursor {
+        LogCursor {
+            next_seq: self.first_seq,
+        }
+    }
+
+    /// Total number of entries overwritten since boot
+    pub fn overwritten(&self) -> u64 {
+        self.overwritten
+    }
+
+    /// Read the entries after the cursor and advance it
+    /// # Arguments
+    /// * `cursor` - The position of the reader
+    /// * `filter` - Only the matching entries are returned, the others are skipped
+    /// * `max_entries` - The max number of returned entries, the rest is left for the next read
+    pub fn read(
+        &self,
+        cursor: &mut LogCursor,
+        filter: impl Fn(&T) -> bool,
+        max_entries: usize,
+    ) -> LogRead<T> {
+        let mut dropped = 0;
+        if cursor.next_seq < self.first_seq {
+            dropped = self.first_seq - cursor.next_seq;
+            cursor.next_seq = self.first_seq;
+        }
+
+        // A cursor ahead of the buffer comes from before a reboot,
+        // all the entries are new to this reader
+        if cursor.next_seq > self.first_seq + self.entries.len() as u64 {
+            cursor.next_seq = self.first_seq;
+        }
+
+        let mut entries = vec![];
+        let start = (cursor.next_seq - self.first_seq) as usize;
+
+        for entry in self.entries.iter().skip(start) {
+            if entries.len() >= max_entries {
+                break;
+            }
+
+            cursor.next_seq += 1;
+            if filter(entry) {
+                entries.push(entry.clone());
+            }
+        }
+
+        LogRead { entries, dropped }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring_with(capacity: usize, entries: std::ops::Range<u32>) -> LogRing<u32> {
+        let mut ring = LogRing::new(capacity);
+        for entry in entries {
+            ring.push(entry);
+        }
+        ring
+    }
+
+    #[test]
+    fn read_everything_then_only_the_new_entries() {
+        let mut ring = ring_with(4, 0..3);
+        let mut cursor = ring.cursor_at_start();
+
+        let read = ring.read(&mut cursor, |_| true, 10);
+        assert_eq!(read.entries, vec![0, 1, 2]);
+        assert_eq!(read.dropped, 0);
+        assert_eq!(cursor.seq(), 3);
+
+        assert!(ring.read(&mut cursor, |_| true, 10).entries.is_empty());
+
+        ring.push(3);
+        assert_eq!(ring.read(&mut cursor, |_| true, 10).entries, vec![3]);
+    }
+
+    #[test]
+    fn wraparound_overwrites_the_oldest_entries() {
+        let ring = ring_with(3, 0..5);
+
+        let mut cursor = ring.cursor_at_start();
+        assert_eq!(cursor.seq(), 2);
+        assert_eq!(ring.read(&mut cursor, |_| true, 10).entries, vec![2, 3, 4]);
+        assert_eq!(ring.overwritten(), 2);
+    }
+
+    #[test]
+    fn a_slow_reader_gets_the_dropped_count() {
+        let mut ring = ring_with(3, 0..2);
+        let mut cursor = ring.cursor_at_start();
+        assert_eq!(ring.read(&mut cursor, |_| true, 1).entries, vec![0]);
+
+        for entry in 2..7 {
+            ring.push(entry);
+        }
+
+        // 1, 2 and 3 were overwritten before the reader got to them
+        let read = ring.read(&mut cursor, |_| true, 10);
+        assert_eq!(read.dropped, 3);
+        assert_eq!(read.entries, vec![4, 5, 6]);
+
+        ring.push(7);
+        let read = ring.read(&mut cursor, |_| true, 10);
+        assert_eq!(read.dropped, 0);
+        assert_eq!(read.entries, vec![7]);
+        assert_eq!(ring.overwritten(), 5);
+    }
+
+    #[test]
+    fn readers_have_their_own_cursors() {
+        let ring = ring_with(8, 0..4);
+        let mut first = ring.cursor_at_start();
+        let mut second = ring.cursor_at_start();
+
+        assert_eq!(
+            ring.read(&mut first, |_| true, 10).entries,
+            vec![0, 1, 2, 3]
+        );
+        assert_eq!(ring.read(&mut second, |_| true, 2).entries, vec![0, 1]);
+        assert_eq!(ring.read(&mut second, |_| true, 2).entries, vec![2, 3]);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn filtered_entries_are_skipped_but_consumed() {
+        let ring = ring_with(8, 0..6);
+        let mut cursor = ring.cursor_at_start();
+
+        let read = ring.read(&mut cursor, |entry| entry % 2 == 1, 10);
+        assert_eq!(read.entries, vec![1, 3, 5]);
+        assert_eq!(cursor.seq(), 6);
+    }
+
+    #[test]
+    fn a_stale_cursor_restarts_at_the_oldest_entry() {
+        let ring = ring_with(3, 0..5);
+
+        // Behind the buffer, the missing entries are counted
+        let mut cursor = LogCursor::at(0);
+        let read = ring.read(&mut cursor, |_| true, 10);
+        assert_eq!(read.dropped, 2);
+        assert_eq!(read.entries, vec![2, 3, 4]);
+
+        // Ahead of the buffer, e.g. from before a reboot
+        let mut cursor = LogCursor::at(100);
+        let read = ring.read(&mut cursor, |_| true, 10);
+        assert_eq!(read.dropped, 0);
+        assert_eq!(read.entries, vec![2, 3, 4]);
+        assert_eq!(cursor.seq(), 5);
+
+        // At the end of the buffer, nothing new
+        let mut cursor = LogCursor::at(5);
+        assert!(ring.read(&mut cursor, |_| true, 10).entries.is_empty());
+    }
+}
@@ -1,9 +1,38 @@
+mod alerts;
+mod api;
+mod auth;
+mod auth_check;
+mod battery;
+mod calibration;
+mod captive_dns;
+mod commands;
+mod data_bus;
 mod data_channel;
+mod device;
+mod device_config;
+mod diagnostics;
+mod filters;
+mod history;
 mod httpd;
+mod httpd_config;
+mod log_buffer;
+mod network;
+mod ota;
+mod ota_image;
+mod provisioning;
 mod sensors;
 mod sigmiot_log;
+mod sleep_schedule;
+mod sntp;
 mod spawn;
+mod storage;
+mod time_sync;
+mod tls;
+mod virtual_sensors;
+mod web;
 mod wifi;
+mod wifi_policy;
+mod wifi_supervisor;
 mod ws;
 
 use esp_idf_hal::task::executor::EspExecutor;
@@ -16,8 +45,10 @@ use esp_idf_hal::delay;
 use esp_idf_hal::i2c::{self};
 use esp_idf_hal::peripherals::Peripherals;
 use esp_idf_hal::units::FromValueType;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
 
 use sensors::{BME280Sensor, GY30Sensor, Sensor};
+use virtual_sensors::{AltitudeSensor, ComfortSensor, DayNightSensor};
 use wifi::Wifi;
 
 use crate::sigmiot_log::sigmiot_log_init;
@@ -44,51 +75,119 @@ fn main() {
 
     let i2c0 = peripherals.i2c0;
 
-    let mut wifi = Wifi::new(peripherals.modem);
-
-    wifi.connect("test_ssid", "test_psk").unwrap();
-
-    // for i in 0..127 {
-    //     let res = i2c_inst.read(i, &mut buffer, 10);
-    //     match res {
-    //         Ok(_) => println!("Device found at address {i}"),
-    //         Err(e) => println!("not found at address {i}, i2c error {e:?}"),
-    //     }
-    // }
+    let nvs_partition = EspDefaultNvsPartition::take().unwrap();
+
+    storage::storage_init(nvs_partition.clone()).unwrap();
+    device_config::device_config_load();
+    sigmiot_log::log_levels_load();
+    auth::auth_config_load();
+    tls::tls_load();
+    httpd::httpd_config_load();
+    calibration::calibration_load();
+    filters::filters_load();
+    history::history_init(true);
+    sntp::time_config_load();
+    alerts::alerts_load(vec![alerts::AlertRule {
+        rule_id: "low_light".into(),
+        sensor_name: device_config::device_config_get().sensors.gy30.name,
+        value_name: "illuminance".into(),
+        condition: alerts::Condition::Below,
+        threshold: 100.0,
+        samples: 1,
+        hysteresis: 10.0,
+    }]);
 
     let config = i2c::config::Config::new().baudrate(400.kHz().into());
     let i2c_inst = i2c::I2cDriver::new(i2c0, sda, scl, &config).unwrap();
 
     let bus: &'static _ = shared_bus::new_std!(i2c::I2cDriver = i2c_inst).unwrap();
 
+    let sensors_config = device_config::device_config_get().sensors;
+
     let mut bme280 = Box::new(BME280Sensor::new(
-        "BME280",
-        "room1",
+        &sensors_config.bme280.name,
+        &sensors_config.bme280.location,
         bus.acquire_i2c(),
         delay::Ets,
     ));
     bme280.init().unwrap();
 
     let gy30 = Box::new(GY30Sensor::new(
-        "GY30",
-        "room1",
+        &sensors_config.gy30.name,
+        &sensors_config.gy30.location,
         bus.acquire_i2c(),
         delay::Ets,
     ));
 
+    diagnostics::diagnostics_init(bus.acquire_i2c());
+
+    let mut sensor_manager = sensors::SensorManager::new(sensors_config.poll_interval_ms);
+    sensor_manager.add_sensor(bme280);
+    sensor_manager.add_sensor(gy30);
+    sensor_manager.add_virtual_sensor(Box::new(ComfortSensor::new(
+        &sensors_config.comfort.name,
+        &sensors_config.comfort.location,
+        &sensors_config.bme280.name,
+    )));
+    sensor_manager.add_virtual_sensor(Box::new(AltitudeSensor::new(
+        &sensors_config.altitude.name,
+        &sensors_config.altitude.location,
+        &sensors_config.bme280.name,
+        sensors_config.sea_level_pressure,
+    )));
+    sensor_manager.add_virtual_sensor(Box::new(DayNightSensor::new(
+        &sensors_config.daylight.naming.name,
+        &sensors_config.daylight.naming.location,
+        &sensors_config.gy30.name,
+        sensors_config.daylight.threshold,
+        sensors_config.daylight.hysteresis,
+    )));
+
+    let mut wifi = Wifi::new(peripherals.modem, nvs_partition);
+
+    if battery::battery_mode() {
+        battery::run_battery_cycle(sensor_manager, wifi);
+    }
+
+    let network_config = network::network_config_load();
+    let hostname = network_config.effective_hostname();
+    if let Err(e) = wifi.configure_network(&hostname, network_config.static_ip.as_ref()) {
+        log::warn!("Cannot apply the network config, using DHCP: {:?}", e);
+    }
+
+    let networks = wifi::wifi_networks_load();
+    if let Err(e) = wifi_supervisor::wifi_connect_best(&mut wifi, &networks) {
+        log::warn!("Cannot connect to Wi-Fi: {:?}", e);
+        provisioning::run_provisioning(&mut wifi, !networks.is_empty());
+    }
+
+    wifi_supervisor::wifi_supervisor_start(wifi).unwrap();
+
+    if let Err(e) = sntp::sntp_start() {
+        log::warn!(
+            "Cannot start SNTP, timestamps stay relative to boot: {:?}",
+            e
+        );
+    }
+
     let (_http, ws_acceptor) = httpd().unwrap();
 
+    let secure = tls::tls_credentials().is_some();
+    let port = httpd::httpd_config_get().port(secure);
+    if let Err(e) = network::mdns_start(&hostname, port, "/ws", secure) {
+        log::warn!(
+            "Cannot start mDNS, the device cannot be discovered: {:?}",
+            e
+        );
+    }
+
     let mut tasks_high_prio = heapless::Vec::<_, 16>::new();
     let mut executor_high_prio = EspExecutor::<16, _>::new();
 
-    let mut sensor_manager = sensors::SensorManager::new(1000);
-    sensor_manager.add_sensor(bme280);
-    sensor_manager.add_sensor(gy30);
-
     spawn::collect_high_prio(
         &mut executor_high_prio,
         &mut tasks_high_prio,
-        ws_acceptor,
+        &ws_acceptor,
         sensor_manager,
     )
     .unwrap();
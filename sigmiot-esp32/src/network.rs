@@ -0,0 +1,122 @@
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Error};
+use esp_idf_svc::mdns::EspMdns;
+use lazy_static::lazy_static;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::device::device_id;
+use crate::device_config::{device_config_get, device_config_update};
+
+const MAX_HOSTNAME_LEN: usize = 32;
+
+pub const MDNS_SERVICE_TYPE: &str = "_sigmiot";
+pub const MDNS_SERVICE_PROTO: &str = "_tcp";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StaticIpConfig {
+    pub ip: Ipv4Addr,
+    /// Length of the network prefix, e.g. 24 for 255.255.255.0
+    pub prefix_len: u8,
+    pub gateway: Ipv4Addr,
+    pub dns: Option<Ipv4Addr>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    /// The DHCP and mDNS host name, the device id if empty
+    #[serde(default)]
+    pub hostname: String,
+    /// Use DHCP if None
+    pub static_ip: Option<StaticIpConfig>,
+}
+
+impl NetworkConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        let valid_hostname = self.hostname.len() <= MAX_HOSTNAME_LEN
+            && self
+                .hostname
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+            && !self.hostname.starts_with('-')
+            && !self.hostname.ends_with('-');
+
+        if !valid_hostname {
+            return Err(anyhow!(
+                "host name must be at most {} letters, digits and inner dashes",
+                MAX_HOSTNAME_LEN
+            ));
+        }
+
+        if let Some(static_ip) = self.static_ip.as_ref() {
+            if static_ip.ip.is_unspecified()
+                || static_ip.prefix_len == 0
+                || static_ip.prefix_len > 30
+            {
+                return Err(anyhow!("invalid static IP or prefix length"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The configured host name or the device id
+    pub fn effective_hostname(&self) -> String {
+        if self.hostname.is_empty() {
+            device_id()
+        } else {
+            self.hostname.clone()
+        }
+    }
+}
+
+lazy_static! {
+    static ref MDNS: Mutex<Option<EspMdns>> = Mutex::new(None);
+}
+
+/// Load the network configuration from the device config
+pub fn network_config_load() -> NetworkConfig {
+    device_config_get().network
+}
+
+/// Validate and store the network configuration, it is applied at the next boot
+pub fn network_config_store(config: &NetworkConfig) -> Result<(), Error> {
+    device_config_update(|device| device.network = config.clone()).map(|_| ())
+}
+
+/// Advertise the WebSocket endpoint of the device as a `_sigmiot._tcp` mDNS service
+/// # Arguments
+/// * `hostname` - The mDNS host name, the device answers to `<hostname>.local`
+/// * `port` - The HTTP server port
+/// * `ws_path` - The path of the WebSocket endpoint
+/// * `secure` - The server uses TLS, the clients connect with wss://
+pub fn mdns_start(hostname: &str, port: u16, ws_path: &str, secure: bool) -> Result<(), Error> {
+    let id = device_id();
+
+    let mut mdns = EspMdns::take()?;
+    mdns.set_hostname(hostname)?;
+    mdns.set_instance_name(&id)?;
+    mdns.add_service(
+        Some(&id),
+        MDNS_SERVICE_TYPE,
+        MDNS_SERVICE_PROTO,
+        port,
+        &[
+            ("path", ws_path),
+            ("scheme", if secure { "wss" } else { "ws" }),
+            ("id", &id),
+            ("version", env!("CARGO_PKG_VERSION")),
+        ],
+    )?;
+
+    info!(
+        "mDNS: {}.local advertises {}.{} on port {}",
+        hostname, MDNS_SERVICE_TYPE, MDNS_SERVICE_PROTO, port
+    );
+
+    *MDNS.lock().unwrap() = Some(mdns);
+
+    Ok(())
+}
@@ -0,0 +1,222 @@
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Error};
+use embassy_time::{Duration, Timer};
+use esp_idf_sys::esp;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+
+use crate::data_channel::latest_readings;
+use crate::device::uptime_ms;
+use crate::ota_image::{HealthCheck, HealthVerdict, OtaImage, OtaManifest, OtaProgress};
+use crate::wifi_supervisor::wifi_status;
+
+// OTA_PUBLIC_KEY, from the PEM file given at build time through SIGMIOT_OTA_PUBLIC_KEY.
+// The images must be signed when it is set.
+include!(concat!(env!("OUT_DIR"), "/ota_key.rs"));
+
+/// Time a new image has since boot to connect and read the sensors before it is rolled back
+const HEALTH_CHECK_TIMEOUT_MS: u64 = 120_000;
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// An image being written to the next OTA partition
+struct OtaUpdate {
+    handle: esp_idf_sys::esp_ota_handle_t,
+    image: OtaImage,
+}
+
+lazy_static! {
+    static ref OTA_UPDATE: Mutex<Option<OtaUpdate>> = Mutex::new(None);
+}
+
+fn next_partition() -> Result<&'static esp_idf_sys::esp_partition_t, Error> {
+    unsafe { esp_idf_sys::esp_ota_get_next_update_partition(std::ptr::null()).as_ref() }
+        .ok_or_else(|| anyhow!("no OTA partition, check the partition table"))
+}
+
+/// Start an update, an update in progress is aborted
+/// # Returns
+/// * The progress, nothing is received yet
+pub fn ota_begin(manifest: OtaManifest) -> Result<OtaProgress, Error> {
+    if OTA_PUBLIC_KEY.is_some() && manifest.signature.is_empty() {
+        return Err(anyhow!("the firmware only accepts signed images"));
+    }
+
+    let mut update = OTA_UPDATE.lock().unwrap();
+    if let Some(previous) = update.take() {
+        warn!("Aborting the update in progress");
+        unsafe { esp_idf_sys::esp_ota_abort(previous.handle) };
+    }
+
+    let partition = next_partition()?;
+    let image = OtaImage::new(manifest, partition.size)?;
+
+    let mut handle: esp_idf_sys::esp_ota_handle_t = 0;
+    // Given the size, only the sectors of the image are erased
+    esp!(unsafe {
+        esp_idf_sys::esp_ota_begin(partition, image.manifest().size as usize, &mut handle)
+    })?;
+
+    info!("Update of {} bytes started", image.manifest().size);
+    let progress = image.progress();
+    *update = Some(OtaUpdate { handle, image });
+
+    Ok(progress)
+}
+
+/// Write the next chunk of the image
+/// # Arguments
+/// * `offset` - The offset of the chunk in the image, the chunks must come in order
+pub fn ota_write(offset: u32, chunk: &[u8]) -> Result<OtaProgress, Error> {
+    let mut update = OTA_UPDATE.lock().unwrap();
+    let current = update
+        .as_mut()
+        .ok_or_else(|| anyhow!("no update in progress"))?;
+
+    let progress = current.image.accept(offset, chunk)?;
+
+    let written = esp!(unsafe {
+        esp_idf_sys::esp_ota_write(current.handle, chunk.as_ptr() as *const _, chunk.len())
+    });
+    if let Err(e) = written {
+        unsafe { esp_idf_sys::esp_ota_abort(current.handle) };
+        update.take();
+        return Err(anyhow!("cannot write the image, update aborted: {}", e));
+    }
+
+    Ok(progress)
+}
+
+/// Verify the received image and make it the boot image, it runs after the next restart
+pub fn ota_finish() -> Result<(), Error> {
+    let update = OTA_UPDATE
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or_else(|| anyhow!("no update in progress"))?;
+
+    let signature = update.image.manifest().signature.clone();
+    let verified = update
+        .image
+        .finish()
+        .and_then(|digest| verify_signature(&digest, &signature));
+    if let Err(e) = verified {
+        unsafe { esp_idf_sys::esp_ota_abort(update.handle) };
+        return Err(e);
+    }
+
+    // Also checks the image header
+    esp!(unsafe { esp_idf_sys::esp_ota_end(update.handle) })?;
+    esp!(unsafe { esp_idf_sys::esp_ota_set_boot_partition(next_partition()?) })?;
+
+    info!("Update verified, the new image runs after the restart");
+
+    Ok(())
+}
+
+/// Abort the update in progress, if any
+pub fn ota_abort() {
+    if let Some(update) = OTA_UPDATE.lock().unwrap().take() {
+        unsafe { esp_idf_sys::esp_ota_abort(update.handle) };
+        info!("Update aborted");
+    }
+}
+
+/// The progress of the update in progress
+pub fn ota_progress() -> Option<OtaProgress> {
+    OTA_UPDATE
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|update| update.image.progress())
+}
+
+/// Check the signature of the image digest with the embedded public key,
+/// RSA PKCS#1 v1.5 and ECDSA signatures as made by `openssl dgst -sha256 -sign`
+fn verify_signature(digest: &[u8; 32], signature: &[u8]) -> Result<(), Error> {
+    let key = match OTA_PUBLIC_KEY {
+        Some(key) => key,
+        None => return Ok(()),
+    };
+
+    let ret = unsafe {
+        let mut pk: esp_idf_sys::mbedtls_pk_context = std::mem::zeroed();
+        esp_idf_sys::mbedtls_pk_init(&mut pk);
+
+        // The PEM key ends with a nul, mbedtls counts it in the length
+        let mut ret = esp_idf_sys::mbedtls_pk_parse_public_key(&mut pk, key.as_ptr(), key.len());
+        if ret == 0 {
+            ret = esp_idf_sys::mbedtls_pk_verify(
+                &mut pk,
+                esp_idf_sys::mbedtls_md_type_t_MBEDTLS_MD_SHA256,
+                digest.as_ptr(),
+                digest.len(),
+                signature.as_ptr(),
+                signature.len(),
+            );
+        }
+
+        esp_idf_sys::mbedtls_pk_free(&mut pk);
+        ret
+    };
+
+    if ret != 0 {
+        return Err(anyhow!(
+            "invalid image signature, mbedtls error -0x{:04x}",
+            -ret
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check if the running image was just installed and waits for its health check
+fn running_image_pending_verify() -> bool {
+    let mut state: esp_idf_sys::esp_ota_img_states_t = 0;
+
+    let read = esp!(unsafe {
+        esp_idf_sys::esp_ota_get_state_partition(
+            esp_idf_sys::esp_ota_get_running_partition(),
+            &mut state,
+        )
+    });
+
+    read.is_ok() && state == esp_idf_sys::esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY
+}
+
+/// Keep a new image once it connects and reads the sensors, roll back to the previous
+/// image otherwise. Returns at once when the running image is not new.
+pub async fn run_ota_health_check() {
+    if !running_image_pending_verify() {
+        return;
+    }
+
+    info!("First boot of a new image, checking its health");
+    let check = HealthCheck::new(HEALTH_CHECK_TIMEOUT_MS);
+
+    loop {
+        let verdict = check.evaluate(
+            uptime_ms(),
+            wifi_status().connected,
+            !latest_readings().is_empty(),
+        );
+
+        match verdict {
+            HealthVerdict::Pending => Timer::after(HEALTH_CHECK_INTERVAL).await,
+            HealthVerdict::Healthy => {
+                info!("The new image is healthy, keeping it");
+                if let Err(e) =
+                    esp!(unsafe { esp_idf_sys::esp_ota_mark_app_valid_cancel_rollback() })
+                {
+                    error!("Cannot mark the image valid: {:?}", e);
+                }
+                return;
+            }
+            HealthVerdict::Failed => {
+                error!("The new image failed its health check, rolling back");
+                unsafe { esp_idf_sys::esp_ota_mark_app_invalid_rollback_and_reboot() };
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// The firmware image announced by the client before sending it
+#[derive(Debug, Clone, PartialEq)]
+pub struct OtaManifest {
+    pub size: u32,
+    pub sha256: [u8; 32],
+    /// Signature of the SHA-256 digest, required when the firmware embeds a public key
+    pub signature: Vec<u8>,
+}
+
+/// Progress of an update, reported after every chunk
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct OtaProgress {
+    pub received: u32,
+    pub size: u32,
+}
+
+/// Checks the chunks of an image as they are received, they must come in order
+pub struct OtaImage {
+    manifest: OtaManifest,
+    received: u32,
+    hasher: Sha256,
+}
+
+impl OtaImage {
+    /// # Arguments
+    /// * `manifest` - The announced image
+    /// * `max_size` - The size of the partition the image is written to
+    pub fn new(manifest: OtaManifest, max_size: u32) -> Result<Self, Error> {
+        if manifest.size == 0 || manifest.size > max_size {
+            return Err(anyhow!("image size must be 1 to {} bytes", max_size));
+        }
+
+        Ok(Self {
+            manifest,
+            received: 0,
+            hasher: Sha256::new(),
+        })
+    }
+
+    pub fn manifest(&self) -> &OtaManifest {
+        &self.manifest
+    }
+
+    pub fn progress(&self) -> OtaProgress {
+        OtaProgress {
+            received: self.received,
+            size: self.manifest.size,
+        }
+    }
+
+    /// Check a chunk and add it to the digest, it must be written to flash only if accepted
+    /// # Arguments
+    /// * `offset` - The offset of the chunk in the image, chunks cannot be skipped nor repeated
+    pub fn accept(&mut self, offset: u32, chunk: &[u8]) -> Result<OtaProgress, Error> {
+        if offset != self.received {
+            return Err(anyhow!("expected offset {}, got {}", self.received, offset));
+        }
+
+        if chunk.len() as u64 > (self.manifest.size - self.received) as u64 {
+            return Err(anyhow!(
+                "chunk goes past the image size of {} bytes",
+                self.manifest.size
+            ));
+        }
+
+        self.hasher.update(chunk);
+        self.received += chunk.len() as u32;
+
+        Ok(self.progress())
+    }
+
+    /// Check that the whole image was received with the announced digest
+    /// # Returns
+    /// * The SHA-256 digest, the signature is verified against it
+    pub fn finish(self) -> Result<[u8; 32], Error> {
+        if self.received != self.manifest.size {
+            return Err(anyhow!(
+                "image is incomplete, {} of {} bytes received",
+                self.received,
+                self.manifest.size
+            ));
+        }
+
+        let digest: [u8; 32] = self.hasher.finalize().into();
+        if digest != self.manifest.sha256 {
+            return Err(anyhow!("SHA-256 mismatch, the image is corrupted"));
+        }
+
+        Ok(digest)
+    }
+}
+
+/// Parse a SHA-256 digest written as 64 hex digits
+pub fn parse_sha256_hex(hex: &str) -> Result<[u8; 32], Error> {
+    let hex = hex.trim();
+    // from_str_radix would also take a '+' sign
+    if hex.len() != 64 || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("SHA-256 must be 64 hex digits"));
+    }
+
+    let mut digest = [0_u8; 32];
+    for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair).unwrap();
+        *byte =
+            u8::from_str_radix(pair, 16).map_err(|_| anyhow!("SHA-256 must be 64 hex digits"))?;
+    }
+
+    Ok(digest)
+}
+
+/// The outcome of the health check of a new image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthVerdict {
+    /// Keep checking
+    Pending,
+    /// Keep the new image
+    Healthy,
+    /// Roll back to the previous image
+    Failed,
+}
+
+/// Decides if a freshly updated image works well enough to be kept
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    deadline_ms: u64,
+}
+
+impl HealthCheck {
+    /// # Arguments
+    /// * `timeout_ms` - Time since boot the image has to become healthy
+    pub fn new(timeout_ms: u64) -> Self {
+        Self {
+            deadline_ms: timeout_ms,
+        }
+    }
+
+    /// # Arguments
+    /// * `uptime_ms` - Time since boot
+    /// * `network_up` - Wi-Fi is connected
+    /// * `sensors_read` - The sensors were read at least once
+    pub fn evaluate(&self, uptime_ms: u64, network_up: bool, sensors_read: bool) -> HealthVerdict {
+        if network_up && sensors_read {
+            HealthVerdict::Healthy
+        } else if uptime_ms >= self.deadline_ms {
+            HealthVerdict::Failed
+        } else {
+            HealthVerdict::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IMAGE: &[u8] = b"firmware image";
+
+    fn manifest(data: &[u8]) -> OtaManifest {
+        OtaManifest {
+            size: data.len() as u32,
+            sha256: Sha256::digest(data).into(),
+            signature: vec![],
+        }
+    }
+
+    #[test]
+    fn the_size_must_fit_the_partition() {
+        assert!(OtaImage::new(manifest(b""), 1024).is_err());
+        assert!(OtaImage::new(manifest(IMAGE), IMAGE.len() as u32 - 1).is_err());
+        assert!(OtaImage::new(manifest(IMAGE), IMAGE.len() as u32).is_ok());
+    }
+
+    #[test]
+    fn chunks_in_order_give_the_digest() {
+        let mut image = OtaImage::new(manifest(IMAGE), 1024).unwrap();
+
+        let progress = image.accept(0, &IMAGE[..8]).unwrap();
+        assert_eq!(
+            progress,
+            OtaProgress {
+                received: 8,
+                size: IMAGE.len() as u32
+            }
+        );
+        image.accept(8, &IMAGE[8..]).unwrap();
+
+        assert_eq!(image.finish().unwrap(), manifest(IMAGE).sha256);
+    }
+
+    #[test]
+    fn chunks_cannot_be_skipped_nor_repeated() {
+        let mut image = OtaImage::new(manifest(IMAGE), 1024).unwrap();
+        image.accept(0, &IMAGE[..4]).unwrap();
+
+        assert!(image.accept(0, &IMAGE[..4]).is_err());
+        assert!(image.accept(8, &IMAGE[8..]).is_err());
+        assert_eq!(image.progress().received, 4);
+    }
+
+    #[test]
+    fn chunks_cannot_go_past_the_size() {
+        let mut image = OtaImage::new(manifest(IMAGE), 1024).unwrap();
+        image.accept(0, &IMAGE[..4]).unwrap();
+
+        let too_long = [IMAGE, b"!"].concat();
+        assert!(image.accept(4, &too_long[4..]).is_err());
+        assert_eq!(image.progress().received, 4);
+    }
+
+    #[test]
+    fn an_incomplete_image_is_refused() {
+        let mut image = OtaImage::new(manifest(IMAGE), 1024).unwrap();
+        image.accept(0, &IMAGE[..4]).unwrap();
+
+        assert!(image.finish().is_err());
+    }
+
+    #[test]
+    fn a_corrupted_image_is_refused() {
+        let mut corrupted = IMAGE.to_vec();
+        corrupted[0] ^= 1;
+
+        let mut image = OtaImage::new(manifest(IMAGE), 1024).unwrap();
+        image.accept(0, &corrupted).unwrap();
+
+        let error = image.finish().unwrap_err();
+        assert!(error.to_string().contains("SHA-256 mismatch"));
+    }
+
+    #[test]
+    fn parse_sha256_hex_digits() {
+        let digest =
+            parse_sha256_hex(&format!(" {}{}\n", "00ff".repeat(8), "A5".repeat(16))).unwrap();
+        assert_eq!(digest[..4], [0x00, 0xff, 0x00, 0xff]);
+        assert_eq!(digest[31], 0xa5);
+
+        // Too short, too long, not hex, signed
+        assert!(parse_sha256_hex(&"0".repeat(62)).is_err());
+        assert!(parse_sha256_hex(&"0".repeat(66)).is_err());
+        assert!(parse_sha256_hex(&format!("zz{}", "0".repeat(62))).is_err());
+        assert!(parse_sha256_hex(&format!("+f{}", "0".repeat(62))).is_err());
+        assert!(parse_sha256_hex(&format!("é{}", "0".repeat(62))).is_err());
+    }
+
+    #[test]
+    fn health_check_waits_until_the_deadline() {
+        let check = HealthCheck::new(60_000);
+
+        assert_eq!(check.evaluate(1_000, true, false), HealthVerdict::Pending);
+        assert_eq!(check.evaluate(1_000, true, true), HealthVerdict::Healthy);
+        assert_eq!(check.evaluate(60_000, false, true), HealthVerdict::Failed);
+        assert_eq!(check.evaluate(90_000, true, true), HealthVerdict::Healthy);
+    }
+}
@@ -0,0 +1,149 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use embedded_svc::http::server::Method;
+use embedded_svc::io::Write;
+use esp_idf_svc::http::server::EspHttpServer;
+use log::{info, warn};
+
+use crate::captive_dns::captive_dns_start;
+use crate::httpd::{read_body, url_decode};
+use crate::wifi::{wifi_networks_add, Wifi};
+use crate::wifi_policy::KnownNetwork;
+
+/// With stored credentials the device reboots after this time to try them again,
+/// the network may only have been down temporarily
+const PROVISIONING_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The URLs requested by the phones and laptops to detect a captive portal
+const CAPTIVE_CHECK_URLS: [&str; 5] = [
+    "/generate_204",
+    "/gen_204",
+    "/hotspot-detect.html",
+    "/connecttest.txt",
+    "/ncsi.txt",
+];
+
+const PROVISIONING_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+    <meta name="viewport" content="width=device-width, initial-scale=1">
+    <title>SigmIoT Wi-Fi setup</title>
+</head>
+<body>
+    <h1>SigmIoT Wi-Fi setup</h1>
+    <form method="post" action="/provision">
+        <p><label>SSID <input name="ssid" maxlength="32" required></label></p>
+        <p><label>Password <input name="password" type="password" maxlength="64"></label></p>
+        <p><button type="submit">Save and reboot</button></p>
+    </form>
+</body>
+</html>
+"#;
+
+static CREDENTIALS_SAVED: AtomicBool = AtomicBool::new(false);
+
+/// Serve the Wi-Fi provisioning page on an open access point until credentials
+/// are entered, then reboot to connect with them
+/// # Arguments
+/// * `wifi` - The Wi-Fi driver, switched to access point mode
+/// * `retry_stored` - Credentials are stored but did not work, reboot after a
+///   timeout to try them again
+pub fn run_provisioning(wifi: &mut Wifi, retry_stored: bool) -> ! {
+    if let Err(e) = serve_provisioning(wifi) {
+        warn!("Cannot start Wi-Fi provisioning: {:?}", e);
+        restart_after(Duration::from_secs(10));
+    }
+
+    let started = Instant::now();
+    loop {
+        std::thread::sleep(Duration::from_secs(1));
+
+        if CREDENTIALS_SAVED.load(Ordering::Relaxed) {
+            info!("Wi-Fi credentials saved, rebooting");
+            restart_after(Duration::from_secs(2));
+        }
+
+        if retry_stored && started.elapsed() > PROVISIONING_TIMEOUT {
+            info!("No Wi-Fi credentials entered, rebooting to retry the stored ones");
+            restart_after(Duration::ZERO);
+        }
+    }
+}
+
+fn restart_after(delay: Duration) -> ! {
+    std::thread::sleep(delay);
+    esp_idf_hal::reset::restart();
+    unreachable!()
+}
+
+fn serve_provisioning(wifi: &mut Wifi) -> Result<(), anyhow::Error> {
+    let mac = wifi.mac()?;
+    let ssid = format!("sigmiot-{:02x}{:02x}", mac[4], mac[5]);
+    let ip = wifi.start_access_point(&ssid)?;
+
+    captive_dns_start(ip)?;
+
+    let mut server = EspHttpServer::new(&Default::default())?;
+
+    server
+        .fn_handler("/", Method::Get, move |req| {
+            req.into_response(200, None, &[("Content-Type", "text/html")])?
+                .write_all(PROVISIONING_PAGE.as_bytes())?;
+
+            Ok(())
+        })?
+        .fn_handler("/provision", Method::Post, move |mut req| {
+            let body = read_body(&mut req)?;
+            let body = String::from_utf8_lossy(&body);
+
+            let network = KnownNetwork {
+                ssid: form_param(&body, "ssid").unwrap_or_default(),
+                password: form_param(&body, "password").unwrap_or_default(),
+                priority: 0,
+            };
+
+            match wifi_networks_add(network.clone()) {
+                Ok(()) => {
+                    info!("Wi-Fi credentials for {} stored", network.ssid);
+                    req.into_response(200, None, &[("Content-Type", "text/plain")])?
+                        .write_all(b"Saved, the device reboots and connects to the network")?;
+                    CREDENTIALS_SAVED.store(true, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    req.into_response(400, Some("Bad Request"), &[("Content-Type", "text/plain")])?
+                        .write_all(e.to_string().as_bytes())?;
+                }
+            }
+
+            Ok(())
+        })?;
+
+    let portal_url = format!("http://{}/", ip);
+    for url in CAPTIVE_CHECK_URLS {
+        let portal_url = portal_url.clone();
+        server.fn_handler(url, Method::Get, move |req| {
+            req.into_response(302, Some("Found"), &[("Location", portal_url.as_str())])?;
+
+            Ok(())
+        })?;
+    }
+
+    info!(
+        "Wi-Fi provisioning page served at {} on access point {}",
+        portal_url, ssid
+    );
+
+    // The server must live until the reboot
+    std::mem::forget(server);
+
+    Ok(())
+}
+
+/// Get a decoded value from an application/x-www-form-urlencoded body
+fn form_param(body: &str, name: &str) -> Option<String> {
+    body.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| url_decode(value))
+}
@@ -1,19 +1,42 @@
 use std::collections::HashMap;
-use std::ops::ControlFlow;
+use std::future::{ready, Future};
 
+use anyhow::{anyhow, Error};
 use bme280::i2c::BME280;
-use embassy_time::{Duration, Timer};
+use embassy_futures::block_on;
+use embassy_futures::select::select;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant, Timer};
 use embedded_hal::blocking::delay::DelayMs;
 use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
-use log::{info, warn};
+use esp_idf_hal::task::embassy_sync::EspRawMutex;
+use log::info;
 
+use crate::alerts;
+use crate::api::SensorReading;
+use crate::calibration;
 use crate::data_channel;
+use crate::filters;
+use crate::history;
+use crate::sntp::time_is_valid;
+use crate::virtual_sensors::VirtualSensor;
+
+/// How long a forced read waits for the sensors, a read in progress finishes first
+const FORCE_READ_TIMEOUT_SECS: u64 = 5;
+const FORCE_READ_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Wakes the sensor manager before the end of its poll interval
+static FORCE_READ: Channel<EspRawMutex, (), 1> = Channel::new();
 
 #[derive(Debug, Clone)]
 pub struct SensorValue {
     pub value_name: String,
     pub value: f32,
     pub unit: String,
+    /// The value as read from the sensor, before calibration
+    pub raw_value: f32,
+    /// Whether the value went through a filter chain
+    pub filtered: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -39,18 +62,38 @@ impl SensorData {
             value_name: name.into(),
             value,
             unit: unit.into(),
+            raw_value: value,
+            filtered: false,
         });
 
         sensor_value.value = value;
+        sensor_value.raw_value = value;
+        sensor_value.filtered = false;
     }
 
     pub fn get_values(&self) -> Vec<&SensorValue> {
         self.values.values().collect()
     }
 
+    pub fn get_values_mut(&mut self) -> impl Iterator<Item = &mut SensorValue> {
+        self.values.values_mut()
+    }
+
+    pub fn get_value(&self, name: &str) -> Option<&SensorValue> {
+        self.values.get(name)
+    }
+
     pub fn get_name(&self) -> &String {
         &self.name
     }
+
+    pub fn get_sensor_type(&self) -> &Vec<String> {
+        &self.sensor_type
+    }
+
+    pub fn get_location(&self) -> &String {
+        &self.location
+    }
 }
 
 pub trait Sensor {
@@ -68,6 +111,9 @@ pub trait Sensor {
     /// Get the sensor data that was read
     fn get_data(&self) -> &SensorData;
 
+    /// Get the sensor data that was read for modification
+    fn get_data_mut(&mut self) -> &mut SensorData;
+
     /// Get the sensor name
     /// # Returns
     /// * The sensor name
@@ -145,6 +191,10 @@ where
         &self.data
     }
 
+    fn get_data_mut(&mut self) -> &mut SensorData {
+        &mut self.data
+    }
+
     fn get_name(&self) -> &String {
         &self.data.name
     }
@@ -201,13 +251,18 @@ where
 
         self.data.push_value("temperature", temperature, "°C");
         self.data.push_value("humidity", humidity, "%");
-        self.data.push_value("pressure", pressure, "hPa");
+        // The driver reports pressure in Pa
+        self.data.push_value("pressure", pressure / 100.0, "hPa");
     }
 
     fn get_data(&self) -> &SensorData {
         &self.data
     }
 
+    fn get_data_mut(&mut self) -> &mut SensorData {
+        &mut self.data
+    }
+
     fn get_name(&self) -> &String {
         &self.data.name
     }
@@ -219,6 +274,7 @@ where
 
 pub struct SensorManager {
     sensors: Vec<Box<dyn Sensor>>,
+    virtual_sensors: Vec<Box<dyn VirtualSensor>>,
     poll_interval: Duration,
 }
 
@@ -230,6 +286,7 @@ impl SensorManager {
         assert!(poll_interval_ms > 0);
         Self {
             sensors: vec![],
+            virtual_sensors: vec![],
             poll_interval: Duration::from_millis(poll_interval_ms),
         }
     }
@@ -241,6 +298,14 @@ impl SensorManager {
         self.sensors.push(sensor);
     }
 
+    /// Add a virtual sensor to the SensorManager.
+    /// Virtual sensors are updated after every read of the real sensors.
+    /// # Arguments
+    /// * `sensor` - The virtual sensor to add
+    pub fn add_virtual_sensor(&mut self, sensor: Box<dyn VirtualSensor>) {
+        self.virtual_sensors.push(sensor);
+    }
+
     /// Get the list of sensors
     /// # Returns
     /// * The list of sensors
@@ -248,16 +313,6 @@ impl SensorManager {
         &self.sensors
     }
 
-    /// Get a sensors list by a sensor type
-    /// # Arguments
-    /// * `sensor_type` - The sensor type as string to filter on
-    fn get_sensors_by_type(&self, sensor_type: &str) -> Vec<&Box<dyn Sensor>> {
-        self.get_sensors()
-            .iter()
-            .filter(|s| s.get_data().sensor_type.iter().any(|t| t == &sensor_type))
-            .collect()
-    }
-
     #[allow(dead_code)]
     fn get_sensor(&self, sensor_name: &str) -> Option<&Box<dyn Sensor>> {
         self.get_sensors()
@@ -284,7 +339,28 @@ impl SensorManager {
     pub fn read(&mut self) {
         for sensor in self.sensors.iter_mut() {
             sensor.read();
+            calibration::calibration_apply(sensor.get_data_mut());
+            filters::filters_apply(sensor.get_data_mut());
         }
+
+        self.update_virtual_sensors();
+    }
+
+    fn update_virtual_sensors(&mut self) {
+        let sensors_data: Vec<&SensorData> = self.sensors.iter().map(|s| s.get_data()).collect();
+
+        for virtual_sensor in self.virtual_sensors.iter_mut() {
+            virtual_sensor.update(&sensors_data);
+        }
+    }
+
+    /// Get a copy of the data of all real and virtual sensors
+    pub fn collect_data(&self) -> Vec<SensorData> {
+        self.sensors
+            .iter()
+            .map(|s| s.get_data().clone())
+            .chain(self.virtual_sensors.iter().map(|s| s.get_data().clone()))
+            .collect()
     }
 
     #[allow(dead_code)]
@@ -295,54 +371,86 @@ impl SensorManager {
                 info!("{}: {} {}", value.value_name, value.value, value.unit);
             }
         }
+
+        for sensor in self.virtual_sensors.iter() {
+            info!("{} (virtual):", sensor.get_name());
+            for value in sensor.get_data().get_values() {
+                info!("{}: {} {}", value.value_name, value.value, value.unit);
+            }
+        }
     }
 }
 
 pub async fn run_sensor_manager(mut sensor_manager: SensorManager) {
     loop {
-        log::debug!(
-            "SensorManager: read sensors at {} sec",
-            std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs()
-        );
+        let timestamp_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        log::debug!("SensorManager: read sensors at {} sec", timestamp_ms / 1000);
 
         sensor_manager.measure();
         sensor_manager.read();
 
-        let mut data = Vec::with_capacity(sensor_manager.get_sensors().len());
+        let data = sensor_manager.collect_data();
 
-        for sensor in sensor_manager.get_sensors().iter() {
-            data.push(sensor.get_data().clone());
-        }
+        let alerts = alerts::alerts_evaluate(&data, timestamp_ms);
 
-        if let ControlFlow::Break(_) = check_illuminance(&sensor_manager) {
-            continue;
+        // The history is kept in wall clock time, the readings before the first sync are left out
+        if time_is_valid() {
+            history::history_record(&data, timestamp_ms / 1000);
         }
 
-        data_channel::publish_async(data).await;
+        data_channel::publish(&data, timestamp_ms, alerts);
 
-        Timer::after(sensor_manager.poll_interval).await;
+        select(
+            Timer::after(sensor_manager.poll_interval),
+            FORCE_READ.recv(),
+        )
+        .await;
     }
 }
 
-fn check_illuminance(sensor_manager: &SensorManager) -> ControlFlow<()> {
-    let illum_sensors = sensor_manager.get_sensors_by_type("illuminance");
-    if illum_sensors.len() == 0 {
-        log::error!("no illuminance sensor found");
-        return ControlFlow::Break(());
-    }
+/// Read the sensors now instead of at the end of the poll interval, the readings are
+/// also published to every client
+/// # Returns
+/// * The new readings
+pub async fn sensors_force_read() -> Result<Vec<SensorReading>, Error> {
+    force_read_with(|| Timer::after(FORCE_READ_POLL_INTERVAL)).await
+}
 
-    let average_illum = illum_sensors
-        .iter()
-        .map(|s| s.get_data().get_values()[0].value)
-        .sum::<f32>()
-        / illum_sensors.len() as f32;
+/// `sensors_force_read` for the callers outside of the executor, like the HTTP handlers
+pub fn sensors_force_read_blocking() -> Result<Vec<SensorReading>, Error> {
+    // The thread sleeps between the polls, so the future is ready every time it is polled
+    block_on(force_read_with(|| {
+        std::thread::sleep(std::time::Duration::from_millis(
+            FORCE_READ_POLL_INTERVAL.as_millis(),
+        ));
+        ready(())
+    }))
+}
 
-    if average_illum < 100.0 {
-        log::warn!("illuminance is too low: {} lx", average_illum);
+/// Request a read and wait until it is published
+/// # Arguments
+/// * `wait` - Waits for one poll interval
+async fn force_read_with<F: Future<Output = ()>>(
+    wait: impl Fn() -> F,
+) -> Result<Vec<SensorReading>, Error> {
+    let published = data_channel::published_count();
+    // A pending request is served by the same read
+    let _ = FORCE_READ.try_send(());
+
+    let deadline = Instant::now() + Duration::from_secs(FORCE_READ_TIMEOUT_SECS);
+    while data_channel::published_count() == published {
+        if Instant::now() >= deadline {
+            return Err(anyhow!(
+                "the sensors were not read within {} s",
+                FORCE_READ_TIMEOUT_SECS
+            ));
+        }
+        wait().await;
     }
 
-    ControlFlow::Continue(())
+    Ok(data_channel::latest_readings())
 }
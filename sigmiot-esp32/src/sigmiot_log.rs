@@ -1,124 +1,396 @@
-use std::sync::Mutex;
-use esp_idf_hal::task::embassy_sync::EspRawMutex;
-use log::{error, Log, Record, Metadata, info};
+use anyhow::{anyhow, Error};
 use esp_idf_svc::log::EspLogger;
 use lazy_static::lazy_static;
+use log::{info, warn, Level, LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::api::LogRecord;
+use crate::device::uptime_ms;
+use crate::log_buffer::{LogCursor, LogRing};
+use crate::sntp::time_is_valid;
+use crate::storage::{storage_load, storage_store};
+
+const LOG_LEVELS_STORAGE_KEY: &str = "log_levels";
+const MAX_TARGET_LEVELS: usize = 16;
 
 #[derive(Debug)]
 pub struct RemoteLoggerEntry {
-    pub level: String,
+    pub level: Level,
     pub target: String,
     pub message: String,
-    pub timestamp: u64,
+    /// Milliseconds since the Unix epoch if `wall_clock` is set, since boot otherwise
+    pub timestamp_ms: u64,
+    pub wall_clock: bool,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    /// Name of the FreeRTOS task which logged the entry
+    pub task_name: String,
+}
+
+impl RemoteLoggerEntry {
+    fn new(level: Level, target: &str, message: String) -> Self {
+        let (timestamp_ms, wall_clock) = log_timestamp_ms();
+
+        Self {
+            level,
+            target: target.to_string(),
+            message,
+            timestamp_ms,
+            wall_clock,
+            file: None,
+            line: None,
+            task_name: current_task_name(),
+        }
+    }
+
+    pub fn to_record(&self) -> LogRecord {
+        LogRecord {
+            level: self.level.as_str().to_string(),
+            target: self.target.clone(),
+            message: self.message.clone(),
+            timestamp_ms: self.timestamp_ms,
+            wall_clock: self.wall_clock,
+            file: self.file.clone(),
+            line: self.line,
+            task_name: self.task_name.clone(),
+        }
+    }
+}
+
+/// Get the current time for a log entry
+/// # Returns
+/// * The milliseconds since the Unix epoch and true once the wall clock is synced,
+///   the milliseconds since boot and false before
+fn log_timestamp_ms() -> (u64, bool) {
+    if time_is_valid() {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        (now_ms, true)
+    } else {
+        (uptime_ms(), false)
+    }
+}
+
+fn current_task_name() -> String {
+    unsafe {
+        let name = esp_idf_sys::pcTaskGetName(std::ptr::null_mut());
+        if name.is_null() {
+            return String::new();
+        }
+        CStr::from_ptr(name).to_string_lossy().into_owned()
+    }
+}
+
+/// The entries a client wants to receive
+#[derive(Debug, Clone)]
+pub struct LogFilter {
+    pub min_level: LevelFilter,
+    /// Only the entries with a target starting with one of the prefixes are sent.
+    /// No prefixes means all targets.
+    pub target_prefixes: Vec<String>,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        Self {
+            min_level: LevelFilter::Trace,
+            target_prefixes: vec![],
+        }
+    }
+}
+
+impl LogFilter {
+    /// Create a filter from a level name like "warn" and the target prefixes
+    pub fn new(min_level: &str, target_prefixes: Vec<String>) -> Result<Self, Error> {
+        let min_level = if min_level.is_empty() {
+            LevelFilter::Trace
+        } else {
+            LevelFilter::from_str(min_level)
+                .map_err(|_| anyhow!("unknown log level '{}'", min_level))?
+        };
+
+        Ok(Self {
+            min_level,
+            target_prefixes,
+        })
+    }
+
+    pub fn matches(&self, entry: &RemoteLoggerEntry) -> bool {
+        entry.level <= self.min_level
+            && (self.target_prefixes.is_empty()
+                || self
+                    .target_prefixes
+                    .iter()
+                    .any(|p| entry.target.starts_with(p.as_str())))
+    }
+}
+
+/// The log levels as set by the clients, levels are names like "info"
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogLevelConfig {
+    pub max_level: String,
+    pub targets: Vec<TargetLogLevel>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TargetLogLevel {
+    /// Target prefix, e.g. "sigmiot_esp32::sensors"
+    pub target: String,
+    pub level: String,
+}
+
+impl Default for LogLevelConfig {
+    fn default() -> Self {
+        Self {
+            max_level: LevelFilter::Debug.as_str().to_lowercase(),
+            targets: vec![],
+        }
+    }
+}
+
+/// The parsed log levels checked for every record
+#[derive(Debug)]
+struct LogLevels {
+    max_level: LevelFilter,
+    /// Target prefixes with their level, the longest matching prefix wins
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl LogLevels {
+    fn parse(config: &LogLevelConfig) -> Result<Self, Error> {
+        if config.targets.len() > MAX_TARGET_LEVELS {
+            return Err(anyhow!(
+                "too many target levels, max is {}",
+                MAX_TARGET_LEVELS
+            ));
+        }
+
+        let parse_level = |level: &str| {
+            LevelFilter::from_str(level).map_err(|_| anyhow!("unknown log level '{}'", level))
+        };
+
+        let mut targets = vec![];
+        for target in config.targets.iter() {
+            if target.target.is_empty() {
+                return Err(anyhow!("empty log target"));
+            }
+            targets.push((target.target.clone(), parse_level(&target.level)?));
+        }
+
+        Ok(Self {
+            max_level: parse_level(&config.max_level)?,
+            targets,
+        })
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(prefix, _)| target.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.max_level)
+    }
+
+    /// The most verbose level of any target, the `log` crate filters above it
+    fn ceiling(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.max_level, Ord::max)
+    }
 }
 
 pub struct RemoteLogger {
-    enabled: bool,
+    /// Every entry since boot is kept until it is overwritten by newer ones,
+    /// so a client connecting later still gets the older entries
+    ring: LogRing<Arc<RemoteLoggerEntry>>,
+    /// Number of connected clients consuming the remote log
+    clients: usize,
 }
 
 pub struct MultiLogger {
     esp_logger: &'static EspLogger,
     remote_logger: &'static Mutex<RemoteLogger>,
+    levels: &'static Mutex<LogLevels>,
 }
 
-const LOG_CHANNEL_SIZE: usize = 21;
-static LOG_CHANNEL: embassy_sync::channel::Channel<EspRawMutex, RemoteLoggerEntry, LOG_CHANNEL_SIZE> =
-    embassy_sync::channel::Channel::new();
+/// Number of entries kept in the remote log ring buffer
+const LOG_RING_SIZE: usize = 128;
+/// Max number of entries returned by a read, the rest is returned by the next reads
+const LOG_MAX_ENTRIES_PER_READ: usize = 32;
 
 impl Log for MultiLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        self.esp_logger.enabled(metadata) || self.remote_logger.lock().unwrap().enabled(metadata)
+        metadata.level() <= self.levels.lock().unwrap().level_for(metadata.target())
     }
 
     fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
         if self.esp_logger.enabled(record.metadata()) {
             self.esp_logger.log(record);
         }
 
-        if self.remote_logger.lock().unwrap().enabled(record.metadata()) {
-            // Only log info and above to remote logger
-            //if record.level() <= Level::Warn {
-                self.remote_logger.lock().unwrap().log(record);
-            //}
-        }
+        // Nothing must be logged while the lock is held, the logger is not reentrant
+        self.remote_logger.lock().unwrap().log(record);
     }
 
     fn flush(&self) {
         self.esp_logger.flush();
-        self.remote_logger.lock().unwrap().flush();
     }
 }
 
 impl RemoteLogger {
     pub fn new() -> Self {
-        Self { enabled: false, }
+        Self {
+            ring: LogRing::new(LOG_RING_SIZE),
+            clients: 0,
+        }
     }
 
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        self.enabled
+    fn acquire(&mut self) -> usize {
+        self.clients += 1;
+        self.clients
     }
 
-    pub fn set_enable(&mut self, enabled: bool) {
-        self.enabled = enabled;
+    fn release(&mut self) -> usize {
+        self.clients = self.clients.saturating_sub(1);
+        self.clients
     }
 
-    fn log(&self, record: &Record) {
-        let entry = RemoteLoggerEntry {
-            level: record.level().as_str().to_string(),
-            target: record.target().to_string(),
-            message: record.args().to_string(),
-            timestamp: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs(),
-        };
-
-        remote_logger_publish_entry(entry);
-    }
+    fn log(&mut self, record: &Record) {
+        let mut entry =
+            RemoteLoggerEntry::new(record.level(), record.target(), record.args().to_string());
+        entry.file = record.file().map(|file| file.to_string());
+        entry.line = record.line();
 
-    fn flush(&self) {
-        while LOG_CHANNEL.try_recv().is_ok() {
-            // Drain the channel
-        }
+        // Never blocks, when the ring is full the oldest entry is overwritten
+        self.ring.push(Arc::new(entry));
     }
 }
 
-fn remote_logger_publish_entry(entry: RemoteLoggerEntry) {
-    if let Err(e) = LOG_CHANNEL.try_send(entry) {
-        error!("Failed to send log entry to remote logger: {:?}", e);
-    }
-}
-
-pub fn remote_logger_get_entries() -> Vec<RemoteLoggerEntry> {
-    let mut entries = Vec::new();
+/// Get the pending entries of a client matching its filter and advance its cursor.
+/// When entries were overwritten before the client read them, a marker entry
+/// with the number of dropped entries comes first.
+pub fn remote_logger_get_entries(
+    cursor: &mut LogCursor,
+    filter: &LogFilter,
+) -> Vec<Arc<RemoteLoggerEntry>> {
+    let read = REMOTE_LOGGER.lock().unwrap().ring.read(
+        cursor,
+        |entry| filter.matches(entry),
+        LOG_MAX_ENTRIES_PER_READ,
+    );
 
-    while let Ok(entry) = LOG_CHANNEL.try_recv() {
-        entries.push(entry);
+    let mut entries = Vec::with_capacity(read.entries.len() + 1);
+    if read.dropped > 0 {
+        entries.push(Arc::new(RemoteLoggerEntry::new(
+            Level::Warn,
+            module_path!(),
+            format!("{} messages dropped", read.dropped),
+        )));
     }
+    entries.extend(read.entries);
 
     entries
 }
 
+/// Total number of entries the remote logger overwrote since boot
+pub fn remote_logger_overwritten() -> u64 {
+    REMOTE_LOGGER.lock().unwrap().ring.overwritten()
+}
+
+/// A cursor at the oldest entry still kept by the remote logger
+pub fn remote_logger_oldest() -> LogCursor {
+    REMOTE_LOGGER.lock().unwrap().ring.cursor_at_start()
+}
+
 static LOGGER: EspLogger = EspLogger;
 
-lazy_static!{
+lazy_static! {
     static ref REMOTE_LOGGER: Mutex<RemoteLogger> = Mutex::new(RemoteLogger::new());
+    static ref LOG_LEVELS: Mutex<LogLevels> =
+        Mutex::new(LogLevels::parse(&LogLevelConfig::default()).unwrap());
+    static ref LOG_LEVEL_CONFIG: Mutex<LogLevelConfig> = Mutex::new(LogLevelConfig::default());
 }
 
 pub fn sigmiot_log_init() {
     let multi_log = Box::new(MultiLogger {
         esp_logger: &LOGGER,
         remote_logger: &REMOTE_LOGGER,
+        levels: &LOG_LEVELS,
     });
 
     log::set_boxed_logger(multi_log).unwrap();
-    log::set_max_level(log::LevelFilter::Debug);
+    log::set_max_level(LOG_LEVELS.lock().unwrap().ceiling());
 }
 
-pub fn remote_logger_set_enable(enabled: bool) {
-    if enabled {
-        info!("Remote logger enabled");
-    } else {
-        info!("Remote logger disabled");
-        REMOTE_LOGGER.lock().unwrap().flush();
+fn log_levels_apply(config: LogLevelConfig, levels: LogLevels) {
+    let ceiling = levels.ceiling();
+    *LOG_LEVELS.lock().unwrap() = levels;
+    *LOG_LEVEL_CONFIG.lock().unwrap() = config;
+    log::set_max_level(ceiling);
+}
+
+/// Load the log levels from NVS, must be called after the storage is initialized
+pub fn log_levels_load() {
+    let config = match storage_load::<LogLevelConfig>(LOG_LEVELS_STORAGE_KEY) {
+        Some(config) => config,
+        None => return,
+    };
+
+    match LogLevels::parse(&config) {
+        Ok(levels) => {
+            log_levels_apply(config.clone(), levels);
+            info!("Loaded log levels {:?}", config);
+        }
+        Err(e) => warn!("Ignoring the stored log levels: {:?}", e),
     }
+}
+
+/// Set the global and per-target log levels and persist them
+pub fn log_levels_set(config: LogLevelConfig) -> Result<(), Error> {
+    let levels = LogLevels::parse(&config)?;
+    storage_store(LOG_LEVELS_STORAGE_KEY, &config)?;
+
+    log_levels_apply(config.clone(), levels);
+    info!("Log levels set to {:?}", config);
+
+    Ok(())
+}
+
+pub fn log_levels_get() -> LogLevelConfig {
+    LOG_LEVEL_CONFIG.lock().unwrap().clone()
+}
+
+/// Register a client of the remote logger
+/// # Returns
+/// * The cursor of the client, at the oldest entry kept so the client gets
+///   the entries logged before it connected
+pub fn remote_logger_acquire() -> LogCursor {
+    let (cursor, clients) = {
+        let mut remote_logger = REMOTE_LOGGER.lock().unwrap();
+        (
+            remote_logger.ring.cursor_at_start(),
+            remote_logger.acquire(),
+        )
+    };
+    info!("Remote log client connected, {} client(s)", clients);
+
+    cursor
+}
 
-    REMOTE_LOGGER.lock().unwrap().set_enable(enabled);
+/// Unregister a client of the remote logger
+pub fn remote_logger_release() {
+    let clients = REMOTE_LOGGER.lock().unwrap().release();
+    info!("Remote log client released, {} client(s) left", clients);
 }
@@ -0,0 +1,438 @@
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+const MIN_WAKE_INTERVAL_SECS: u32 = 10;
+const MAX_WAKE_INTERVAL_SECS: u32 = 86_400;
+const MAX_PUSH_EVERY: u32 = 100;
+/// The shortest sleep, when a cycle ran longer than the wake interval
+const MIN_SLEEP_MS: u64 = 1000;
+/// The most wakes skipped before retrying a failed push
+const MAX_PUSH_BACKOFF_CYCLES: u32 = 32;
+/// Size of the length prefix of a queued record
+const RECORD_HEADER_SIZE: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerMode {
+    /// Wi-Fi, the servers and the sensors loop run all the time
+    AlwaysOn,
+    /// Wake on a timer, measure once, push to the sink and deep-sleep again.
+    /// The HTTP and WebSocket APIs are off, erasing the NVS leaves the mode.
+    DeepSleep,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PowerConfig {
+    pub mode: PowerMode,
+    /// Time from one wake to the next
+    pub wake_interval_secs: u32,
+    /// Connect every this many wakes, the samples are queued in between
+    pub push_every: u32,
+    /// The samples are posted to this URL as a JSON array
+    pub sink_url: String,
+}
+
+impl Default for PowerConfig {
+    fn default() -> Self {
+        Self {
+            mode: PowerMode::AlwaysOn,
+            wake_interval_secs: 300,
+            push_every: 1,
+            sink_url: String::new(),
+        }
+    }
+}
+
+impl PowerConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if !(MIN_WAKE_INTERVAL_SECS..=MAX_WAKE_INTERVAL_SECS).contains(&self.wake_interval_secs) {
+            return Err(anyhow!(
+                "wake interval must be {} to {} seconds",
+                MIN_WAKE_INTERVAL_SECS,
+                MAX_WAKE_INTERVAL_SECS
+            ));
+        }
+
+        if !(1..=MAX_PUSH_EVERY).contains(&self.push_every) {
+            return Err(anyhow!("push every must be 1 to {} wakes", MAX_PUSH_EVERY));
+        }
+
+        let url_valid =
+            self.sink_url.starts_with("http://") || self.sink_url.starts_with("https://");
+        if self.mode == PowerMode::DeepSleep && !url_valid {
+            return Err(anyhow!("deep sleep needs an http:// or https:// sink URL"));
+        }
+
+        Ok(())
+    }
+}
+
+/// The length prefixed records of a fixed buffer, the oldest are dropped when it is full.
+/// Plain data which can live in RTC memory through the deep sleep.
+#[repr(C)]
+pub struct SampleQueue<const N: usize> {
+    buffer: [u8; N],
+    used: usize,
+    count: usize,
+}
+
+impl<const N: usize> Default for SampleQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> SampleQueue<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; N],
+            used: 0,
+            count: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Three quarters of the buffer are used
+    pub fn is_nearly_full(&self) -> bool {
+        self.used * 4 >= N * 3
+    }
+
+    /// Append a record, dropping the oldest ones to make room
+    /// # Returns
+    /// * The number of records dropped
+    pub fn push(&mut self, record: &[u8]) -> Result<usize, Error> {
+        let max_len = N.saturating_sub(RECORD_HEADER_SIZE).min(u16::MAX as usize);
+        if record.len() > max_len {
+            return Err(anyhow!("a record must be at most {} bytes", max_len));
+        }
+
+        let size = RECORD_HEADER_SIZE + record.len();
+
+        let mut dropped = 0;
+        while self.used + size > N {
+            self.pop_front();
+            dropped += 1;
+        }
+
+        self.buffer[self.used..self.used + RECORD_HEADER_SIZE]
+            .copy_from_slice(&(record.len() as u16).to_le_bytes());
+        self.buffer[self.used + RECORD_HEADER_SIZE..self.used + size].copy_from_slice(record);
+        self.used += size;
+        self.count += 1;
+
+        Ok(dropped)
+    }
+
+    /// Remove the oldest record
+    pub fn pop_front(&mut self) {
+        let Some(first) = self.iter().next() else {
+            return;
+        };
+
+        let size = RECORD_HEADER_SIZE + first.len();
+        self.buffer.copy_within(size..self.used, 0);
+        self.used -= size;
+        self.count -= 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.used = 0;
+        self.count = 0;
+    }
+
+    /// The records, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        let mut offset = 0;
+
+        std::iter::from_fn(move || {
+            if offset + RECORD_HEADER_SIZE > self.used {
+                return None;
+            }
+
+            let len = u16::from_le_bytes([self.buffer[offset], self.buffer[offset + 1]]) as usize;
+            let start = offset + RECORD_HEADER_SIZE;
+            offset = start + len;
+
+            Some(&self.buffer[start..start + len])
+        })
+    }
+}
+
+/// The state of the deep sleep cycles, kept in RTC memory
+#[repr(C)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SleepState {
+    /// Number of wakes since power-on
+    pub cycle: u32,
+    /// Pushes failed in a row
+    pub failures: u32,
+    /// No push is tried before this cycle after a failure
+    pub retry_cycle: u32,
+    /// The wall clock was synced once, the RTC keeps it through the deep sleep
+    pub clock_synced: bool,
+}
+
+impl Default for SleepState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SleepState {
+    pub const fn new() -> Self {
+        Self {
+            cycle: 0,
+            failures: 0,
+            retry_cycle: 0,
+            clock_synced: false,
+        }
+    }
+
+    /// Check if this wake connects and pushes the queued samples
+    /// # Arguments
+    /// * `queued` - Number of samples waiting, the sample of this wake included
+    /// * `nearly_full` - The queue is about to drop samples
+    pub fn push_due(&self, config: &PowerConfig, queued: usize, nearly_full: bool) -> bool {
+        if queued == 0 || self.cycle < self.retry_cycle {
+            return false;
+        }
+
+        self.failures > 0 || nearly_full || self.cycle % config.push_every.max(1) == 0
+    }
+
+    /// Record the outcome of a push, a failed push is retried after 1, 2, 4... wakes
+    pub fn push_done(&mut self, sent: bool) {
+        if sent {
+            self.failures = 0;
+            self.retry_cycle = 0;
+        } else {
+            let backoff = 1_u32
+                .checked_shl(self.failures)
+                .unwrap_or(u32::MAX)
+                .min(MAX_PUSH_BACKOFF_CYCLES);
+            self.failures = self.failures.saturating_add(1);
+            self.retry_cycle = self.cycle.saturating_add(backoff);
+        }
+    }
+
+    /// Move to the next wake, called before going to sleep
+    pub fn next_cycle(&mut self) {
+        self.cycle = self.cycle.saturating_add(1);
+    }
+}
+
+/// How long to sleep so that the wakes stay one interval apart
+/// # Arguments
+/// * `wake_interval_secs` - The time from one wake to the next
+/// * `awake_ms` - How long this wake took
+pub fn sleep_duration_ms(wake_interval_secs: u32, awake_ms: u64) -> u64 {
+    (wake_interval_secs as u64 * 1000)
+        .saturating_sub(awake_ms)
+        .max(MIN_SLEEP_MS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(push_every: u32) -> PowerConfig {
+        PowerConfig {
+            mode: PowerMode::DeepSleep,
+            wake_interval_secs: 60,
+            push_every,
+            sink_url: "http://sink.local/samples".into(),
+        }
+    }
+
+    fn records<const N: usize>(queue: &SampleQueue<N>) -> Vec<Vec<u8>> {
+        queue.iter().map(|record| record.to_vec()).collect()
+    }
+
+    #[test]
+    fn validate_bounds() {
+        assert!(PowerConfig::default().validate().is_ok());
+        assert!(config(1).validate().is_ok());
+        assert!(config(MAX_PUSH_EVERY).validate().is_ok());
+
+        assert!(config(0).validate().is_err());
+        assert!(config(MAX_PUSH_EVERY + 1).validate().is_err());
+
+        let interval = |wake_interval_secs| PowerConfig {
+            wake_interval_secs,
+            ..config(1)
+        };
+        assert!(interval(MIN_WAKE_INTERVAL_SECS).validate().is_ok());
+        assert!(interval(MAX_WAKE_INTERVAL_SECS).validate().is_ok());
+        assert!(interval(MIN_WAKE_INTERVAL_SECS - 1).validate().is_err());
+        assert!(interval(MAX_WAKE_INTERVAL_SECS + 1).validate().is_err());
+
+        let url = |sink_url: &str| PowerConfig {
+            sink_url: sink_url.into(),
+            ..config(1)
+        };
+        assert!(url("https://sink.local").validate().is_ok());
+        assert!(url("").validate().is_err());
+        assert!(url("ftp://sink.local").validate().is_err());
+    }
+
+    #[test]
+    fn sleep_keeps_the_wakes_one_interval_apart() {
+        assert_eq!(sleep_duration_ms(60, 0), 60_000);
+        assert_eq!(sleep_duration_ms(60, 4_500), 55_500);
+        // A wake longer than the interval still sleeps a little
+        assert_eq!(sleep_duration_ms(60, 59_500), MIN_SLEEP_MS);
+        assert_eq!(sleep_duration_ms(60, 120_000), MIN_SLEEP_MS);
+    }
+
+    #[test]
+    fn push_every_n_wakes() {
+        let config = config(3);
+        let mut state = SleepState::new();
+        let mut pushes = vec![];
+
+        for _ in 0..7 {
+            pushes.push(state.push_due(&config, 1, false));
+            state.next_cycle();
+        }
+
+        assert_eq!(pushes, vec![true, false, false, true, false, false, true]);
+    }
+
+    #[test]
+    fn push_due_needs_samples_and_a_nearly_full_queue_pushes_early() {
+        let config = config(3);
+        let mut state = SleepState::new();
+
+        assert!(!state.push_due(&config, 0, false));
+
+        state.next_cycle();
+        assert!(!state.push_due(&config, 2, false));
+        assert!(state.push_due(&config, 2, true));
+    }
+
+    #[test]
+    fn failed_pushes_back_off_up_to_the_cap() {
+        let config = config(1);
+        let mut state = SleepState::new();
+        let mut retries = vec![];
+
+        for _ in 0..10 {
+            while !state.push_due(&config, 1, false) {
+                state.next_cycle();
+            }
+            let failed_at = state.cycle;
+            state.push_done(false);
+            retries.push(state.retry_cycle - failed_at);
+            state.next_cycle();
+        }
+
+        assert_eq!(retries, vec![1, 2, 4, 8, 16, 32, 32, 32, 32, 32]);
+        assert_eq!(state.failures, 10);
+    }
+
+    #[test]
+    fn the_backoff_holds_even_when_the_queue_fills() {
+        let config = config(1);
+        let mut state = SleepState::new();
+        state.push_done(false);
+        state.push_done(false);
+
+        // Retry at cycle 2
+        state.next_cycle();
+        assert!(!state.push_due(&config, 5, true));
+        state.next_cycle();
+        assert!(state.push_due(&config, 5, true));
+    }
+
+    #[test]
+    fn a_successful_push_resets_the_backoff() {
+        let config = config(4);
+        let mut state = SleepState::new();
+        state.push_done(false);
+        state.next_cycle();
+
+        // Failed pushes are retried without waiting for push_every
+        assert!(state.push_due(&config, 2, false));
+        state.push_done(true);
+
+        assert_eq!(state.failures, 0);
+        assert_eq!(state.retry_cycle, 0);
+        assert!(!state.push_due(&config, 2, false));
+
+        for _ in 0..3 {
+            state.next_cycle();
+        }
+        assert!(state.push_due(&config, 2, false));
+    }
+
+    #[test]
+    fn queue_keeps_the_records_in_order() {
+        let mut queue = SampleQueue::<32>::new();
+        assert!(queue.is_empty());
+
+        assert_eq!(queue.push(b"one").unwrap(), 0);
+        assert_eq!(queue.push(b"").unwrap(), 0);
+        assert_eq!(queue.push(b"three").unwrap(), 0);
+
+        assert_eq!(queue.len(), 3);
+        assert_eq!(
+            records(&queue),
+            vec![b"one".to_vec(), vec![], b"three".to_vec()]
+        );
+
+        queue.pop_front();
+        assert_eq!(records(&queue), vec![vec![], b"three".to_vec()]);
+
+        queue.clear();
+        assert!(queue.is_empty());
+        assert_eq!(queue.iter().count(), 0);
+        queue.pop_front();
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn a_full_queue_drops_the_oldest_records() {
+        // Room for 4 records of 6 bytes
+        let mut queue = SampleQueue::<32>::new();
+        for record in [b"aaaaaa", b"bbbbbb", b"cccccc", b"dddddd"] {
+            assert_eq!(queue.push(record).unwrap(), 0);
+        }
+        assert!(queue.is_nearly_full());
+
+        assert_eq!(queue.push(b"eeeeee").unwrap(), 1);
+        assert_eq!(queue.len(), 4);
+        assert_eq!(records(&queue)[0], b"bbbbbb".to_vec());
+
+        // A larger record drops as many as needed
+        assert_eq!(queue.push(&[b'f'; 20]).unwrap(), 3);
+        assert_eq!(records(&queue), vec![b"eeeeee".to_vec(), vec![b'f'; 20]]);
+    }
+
+    #[test]
+    fn a_record_larger_than_the_queue_is_refused() {
+        let mut queue = SampleQueue::<16>::new();
+        queue.push(b"kept").unwrap();
+
+        assert!(queue.push(&[0; 15]).is_err());
+        assert_eq!(queue.push(&[0; 14]).unwrap(), 1);
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn nearly_full_at_three_quarters() {
+        let mut queue = SampleQueue::<16>::new();
+        queue.push(&[0; 8]).unwrap();
+        assert!(!queue.is_nearly_full());
+
+        queue.push(&[]).unwrap();
+        assert!(queue.is_nearly_full());
+    }
+}
@@ -0,0 +1,197 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Error};
+use embassy_time::{Duration, Timer};
+use esp_idf_svc::sntp::{EspSntp, SntpConf, SyncStatus};
+use lazy_static::lazy_static;
+use log::{info, warn};
+
+use crate::device::uptime_ms;
+use crate::device_config::{device_config_get, device_config_update};
+use crate::time_sync::{TimeConfig, TimeStatus, TimeSyncState};
+
+const SNTP_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    static ref TIME_CONFIG: Mutex<TimeConfig> = Mutex::new(TimeConfig::default());
+    static ref TIME_SYNC: Mutex<TimeSyncState> = Mutex::new(TimeSyncState::new(
+        TimeConfig::default().resync_interval_secs
+    ));
+    static ref SNTP: Mutex<Option<EspSntp>> = Mutex::new(None);
+}
+
+/// Mirrors TimeSyncState::is_valid without a lock, the logger reads it for every entry
+static TIME_VALID: AtomicBool = AtomicBool::new(false);
+
+/// The time sync status reported to the clients
+#[derive(Debug, Clone)]
+pub struct TimeSyncInfo {
+    pub status: TimeStatus,
+    pub last_sync_epoch_ms: Option<u64>,
+    pub config: TimeConfig,
+}
+
+fn apply_timezone(timezone: &str) {
+    std::env::set_var("TZ", timezone);
+    unsafe { esp_idf_sys::tzset() };
+}
+
+/// Load the time configuration from the device config and apply the timezone
+pub fn time_config_load() {
+    let config = device_config_get().time;
+
+    info!("Time config: {:?}", config);
+    apply_timezone(&config.timezone);
+    TIME_SYNC
+        .lock()
+        .unwrap()
+        .set_resync_interval(config.resync_interval_secs);
+    *TIME_CONFIG.lock().unwrap() = config;
+}
+
+/// Set and persist the time configuration.
+/// SNTP is restarted with the new servers if it is running.
+pub fn time_config_set(config: TimeConfig) -> Result<(), Error> {
+    device_config_update(|device| device.time = config.clone())?;
+
+    time_config_apply(config)
+}
+
+/// Apply a stored time configuration.
+/// SNTP is restarted with the new servers if it is running.
+pub fn time_config_apply(config: TimeConfig) -> Result<(), Error> {
+    info!("Time config set to {:?}", config);
+    apply_timezone(&config.timezone);
+    TIME_SYNC
+        .lock()
+        .unwrap()
+        .set_resync_interval(config.resync_interval_secs);
+    *TIME_CONFIG.lock().unwrap() = config;
+
+    if SNTP.lock().unwrap().is_some() {
+        sntp_start()?;
+    }
+
+    Ok(())
+}
+
+pub fn time_config_get() -> TimeConfig {
+    TIME_CONFIG.lock().unwrap().clone()
+}
+
+/// Start the SNTP client with the configured servers, must be called once
+/// the network is up. A running client is replaced.
+pub fn sntp_start() -> Result<(), Error> {
+    let config = time_config_get();
+
+    let mut conf = SntpConf::default();
+    if config.servers.len() > conf.servers.len() {
+        return Err(anyhow!(
+            "{} time servers configured, the firmware supports {}",
+            config.servers.len(),
+            conf.servers.len()
+        ));
+    }
+
+    for (slot, server) in conf.servers.iter_mut().zip(config.servers.iter()) {
+        *slot = server.as_str();
+    }
+
+    let mut sntp = SNTP.lock().unwrap();
+    // Only one client can exist at a time
+    sntp.take();
+
+    *sntp = Some(EspSntp::new(&conf)?);
+
+    unsafe {
+        esp_idf_sys::sntp_set_sync_interval(config.resync_interval_secs.saturating_mul(1000))
+    };
+
+    info!("SNTP started with servers {:?}", config.servers);
+
+    Ok(())
+}
+
+/// Check if the wall clock was set by SNTP since boot
+pub fn time_is_valid() -> bool {
+    TIME_VALID.load(Ordering::Relaxed)
+}
+
+pub fn time_sync_info() -> TimeSyncInfo {
+    let sync = TIME_SYNC.lock().unwrap();
+
+    TimeSyncInfo {
+        status: sync.status(uptime_ms()),
+        last_sync_epoch_ms: sync.last_sync_epoch_ms(),
+        config: time_config_get(),
+    }
+}
+
+fn sntp_completed() -> bool {
+    SNTP.lock()
+        .unwrap()
+        .as_ref()
+        .map(|sntp| sntp.get_sync_status() == SyncStatus::Completed)
+        .unwrap_or(false)
+}
+
+fn sntp_record_sync(now: u64) {
+    let epoch_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+
+    let first_sync = {
+        let mut sync = TIME_SYNC.lock().unwrap();
+        let first_sync = !sync.is_valid();
+        sync.on_sync(now, epoch_ms);
+        first_sync
+    };
+    TIME_VALID.store(true, Ordering::Relaxed);
+
+    if first_sync {
+        info!("Time synced, wall clock is now valid");
+    }
+}
+
+/// Wait for the first sync, for the battery mode which runs no executor
+/// # Returns
+/// * The wall clock is valid
+pub fn sntp_wait_sync(timeout: std::time::Duration) -> bool {
+    let deadline = std::time::Instant::now() + timeout;
+
+    while std::time::Instant::now() < deadline {
+        if sntp_completed() {
+            sntp_record_sync(uptime_ms());
+            return true;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(
+            SNTP_POLL_INTERVAL.as_millis(),
+        ));
+    }
+
+    false
+}
+
+/// Watch the SNTP syncs and restart SNTP when the periodic sync is missed
+pub async fn run_time_sync() {
+    loop {
+        let now = uptime_ms();
+
+        if sntp_completed() {
+            sntp_record_sync(now);
+        }
+
+        let restart_due = TIME_SYNC.lock().unwrap().restart_due(now);
+        if restart_due {
+            warn!("Time sync missed, restarting SNTP");
+            if let Err(e) = sntp_start() {
+                warn!("Cannot restart SNTP: {:?}", e);
+            }
+        }
+
+        Timer::after(SNTP_POLL_INTERVAL).await;
+    }
+}
@@ -1,23 +1,30 @@
-
-
-use esp_idf_hal::task::executor::Executor;
 use embedded_svc::ws::asynch::server::Acceptor;
-use esp_idf_hal::task::executor::{Task, Local, Monitor, SpawnError, Wait};
+use esp_idf_hal::task::executor::Executor;
+use esp_idf_hal::task::executor::{Local, Monitor, SpawnError, Task, Wait};
 
+use crate::httpd::httpd_config_get;
+use crate::ota;
 use crate::sensors;
+use crate::sntp;
 use crate::ws;
 
 pub fn collect_high_prio<'a, const C: usize, M>(
     executor: &mut Executor<'a, C, M, Local>,
     tasks: &mut heapless::Vec<Task<()>, C>,
-    ws_acceptor: impl Acceptor + 'a,
+    ws_acceptor: &'a impl Acceptor,
     sensor_manager: sensors::SensorManager,
 ) -> Result<(), SpawnError>
 where
     M: Monitor + Default,
 {
-    executor.spawn_local_collect(ws::ws_conn_handler(ws_acceptor), tasks)?;
+    // One handler per allowed connection, each one serves a connection at a time
+    for handler_id in 0..httpd_config_get().ws_handlers() {
+        executor.spawn_local_collect(ws::ws_conn_handler(handler_id, ws_acceptor), tasks)?;
+    }
+
     executor.spawn_local_collect(sensors::run_sensor_manager(sensor_manager), tasks)?;
+    executor.spawn_local_collect(sntp::run_time_sync(), tasks)?;
+    executor.spawn_local_collect(ota::run_ota_health_check(), tasks)?;
 
     Ok(())
 }
@@ -25,8 +32,7 @@ where
 pub fn run<'a, const C: usize, M>(
     executor: &mut Executor<'a, C, M, Local>,
     tasks: heapless::Vec<Task<()>, C>,
-)
-where
+) where
     M: Monitor + Wait + Default,
 {
     executor.run_tasks(move || true, tasks);
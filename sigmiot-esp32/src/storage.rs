@@ -0,0 +1,116 @@
+use std::ffi::CString;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Error};
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use esp_idf_sys::esp;
+use lazy_static::lazy_static;
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+const NVS_NAMESPACE: &str = "sigmiot";
+pub const MAX_BLOB_SIZE: usize = 4000;
+
+lazy_static! {
+    static ref STORAGE: Mutex<Option<EspDefaultNvs>> = Mutex::new(None);
+}
+
+/// Open the sigmiot namespace in the default NVS partition.
+/// Must be called once before any other storage function.
+pub fn storage_init(partition: EspDefaultNvsPartition) -> Result<(), Error> {
+    let nvs = EspDefaultNvs::new(partition, NVS_NAMESPACE, true)?;
+    *STORAGE.lock().unwrap() = Some(nvs);
+
+    Ok(())
+}
+
+/// Load a value stored as JSON under the given key
+/// # Returns
+/// * None if the key does not exist, the storage is not initialized
+///   or the stored value cannot be parsed
+pub fn storage_load<T: DeserializeOwned>(key: &str) -> Option<T> {
+    let raw = storage_load_raw(key)?;
+
+    match serde_json::from_slice(&raw) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            warn!("Cannot parse '{}' from NVS: {:?}", key, e);
+            None
+        }
+    }
+}
+
+/// Store a value as JSON under the given key
+pub fn storage_store<T: Serialize>(key: &str, value: &T) -> Result<(), Error> {
+    let raw = serde_json::to_vec(value)?;
+    storage_store_raw(key, &raw)
+}
+
+/// Load raw bytes stored under the given key
+pub fn storage_load_raw(key: &str) -> Option<Vec<u8>> {
+    let storage = STORAGE.lock().unwrap();
+    let nvs = storage.as_ref()?;
+
+    let mut buf = vec![0_u8; MAX_BLOB_SIZE];
+    match nvs.get_raw(key, &mut buf) {
+        Ok(raw) => raw.map(|raw| raw.to_vec()),
+        Err(e) => {
+            warn!("Cannot read '{}' from NVS: {:?}", key, e);
+            None
+        }
+    }
+}
+
+/// Store raw bytes under the given key
+pub fn storage_store_raw(key: &str, raw: &[u8]) -> Result<(), Error> {
+    if raw.len() > MAX_BLOB_SIZE {
+        return Err(anyhow!("'{}' is too big for NVS: {} bytes", key, raw.len()));
+    }
+
+    let mut storage = STORAGE.lock().unwrap();
+    let nvs = storage
+        .as_mut()
+        .ok_or_else(|| anyhow!("Storage is not initialized"))?;
+
+    nvs.set_raw(key, raw)?;
+
+    Ok(())
+}
+
+/// Remove the value stored under the given key
+pub fn storage_remove(key: &str) -> Result<(), Error> {
+    let mut storage = STORAGE.lock().unwrap();
+    let nvs = storage
+        .as_mut()
+        .ok_or_else(|| anyhow!("Storage is not initialized"))?;
+
+    nvs.remove(key)?;
+
+    Ok(())
+}
+
+/// Remove every value of the sigmiot namespace, the config, the credentials and the Wi-Fi networks
+pub fn storage_erase_all() -> Result<(), Error> {
+    // Nothing is written meanwhile
+    let _storage = STORAGE.lock().unwrap();
+
+    let namespace = CString::new(NVS_NAMESPACE).unwrap();
+    let mut handle: esp_idf_sys::nvs_handle_t = 0;
+
+    unsafe {
+        esp!(esp_idf_sys::nvs_open(
+            namespace.as_ptr(),
+            esp_idf_sys::nvs_open_mode_t_NVS_READWRITE,
+            &mut handle
+        ))?;
+
+        let erased = esp!(esp_idf_sys::nvs_erase_all(handle))
+            .and_then(|()| esp!(esp_idf_sys::nvs_commit(handle)));
+        esp_idf_sys::nvs_close(handle);
+
+        erased?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,249 @@
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+/// Max number of SNTP servers, matches CONFIG_LWIP_SNTP_MAX_SERVERS
+pub const MAX_TIME_SERVERS: usize = 3;
+/// lwIP does not accept a sync interval under 15 seconds
+const MIN_RESYNC_INTERVAL_SECS: u32 = 15;
+/// A week, the interval is passed to lwIP in milliseconds as a u32
+const MAX_RESYNC_INTERVAL_SECS: u32 = 7 * 24 * 3600;
+/// The time is stale when no sync happened for this many resync intervals
+const STALE_AFTER_INTERVALS: u64 = 3;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimeConfig {
+    pub servers: Vec<String>,
+    /// POSIX TZ string, e.g. "CET-1CEST,M3.5.0,M10.5.0/3"
+    pub timezone: String,
+    pub resync_interval_secs: u32,
+}
+
+impl Default for TimeConfig {
+    fn default() -> Self {
+        Self {
+            servers: vec!["pool.ntp.org".into()],
+            timezone: "UTC0".into(),
+            resync_interval_secs: 3600,
+        }
+    }
+}
+
+impl TimeConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.servers.is_empty() || self.servers.len() > MAX_TIME_SERVERS {
+            return Err(anyhow!(
+                "between 1 and {} servers are required",
+                MAX_TIME_SERVERS
+            ));
+        }
+
+        if self.servers.iter().any(|s| s.is_empty()) {
+            return Err(anyhow!("empty server name"));
+        }
+
+        if self.timezone.is_empty() {
+            return Err(anyhow!("empty timezone"));
+        }
+
+        if !(MIN_RESYNC_INTERVAL_SECS..=MAX_RESYNC_INTERVAL_SECS)
+            .contains(&self.resync_interval_secs)
+        {
+            return Err(anyhow!(
+                "resync interval must be {} to {} seconds",
+                MIN_RESYNC_INTERVAL_SECS,
+                MAX_RESYNC_INTERVAL_SECS
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeStatus {
+    /// The clock was never synced since boot, wall clock timestamps are meaningless
+    NotSynced,
+    Synced,
+    /// The clock was synced but not recently, it may have drifted
+    Stale,
+}
+
+/// Tracks the SNTP syncs against the uptime, which is monotonic unlike the wall clock
+#[derive(Debug)]
+pub struct TimeSyncState {
+    resync_interval_ms: u64,
+    /// Uptime and wall clock of the last sync in milliseconds
+    last_sync: Option<(u64, u64)>,
+    /// Uptime of the last forced resync in milliseconds
+    last_restart_ms: Option<u64>,
+}
+
+impl TimeSyncState {
+    pub fn new(resync_interval_secs: u32) -> Self {
+        Self {
+            resync_interval_ms: resync_interval_secs as u64 * 1000,
+            last_sync: None,
+            last_restart_ms: None,
+        }
+    }
+
+    pub fn set_resync_interval(&mut self, resync_interval_secs: u32) {
+        self.resync_interval_ms = resync_interval_secs as u64 * 1000;
+    }
+
+    /// Record a completed sync
+    /// # Arguments
+    /// * `uptime_ms` - The milliseconds since boot when the sync completed
+    /// * `epoch_ms` - The wall clock set by the sync in milliseconds since the Unix epoch
+    pub fn on_sync(&mut self, uptime_ms: u64, epoch_ms: u64) {
+        self.last_sync = Some((uptime_ms, epoch_ms));
+        self.last_restart_ms = None;
+    }
+
+    pub fn status(&self, uptime_ms: u64) -> TimeStatus {
+        match self.last_sync {
+            None => TimeStatus::NotSynced,
+            Some((sync_ms, _))
+                if uptime_ms.saturating_sub(sync_ms)
+                    > self.resync_interval_ms * STALE_AFTER_INTERVALS =>
+            {
+                TimeStatus::Stale
+            }
+            Some(_) => TimeStatus::Synced,
+        }
+    }
+
+    /// A stale clock is still valid, it only drifts from the real time
+    pub fn is_valid(&self) -> bool {
+        self.last_sync.is_some()
+    }
+
+    /// Wall clock of the last sync in milliseconds since the Unix epoch
+    pub fn last_sync_epoch_ms(&self) -> Option<u64> {
+        self.last_sync.map(|(_, epoch_ms)| epoch_ms)
+    }
+
+    /// Check if the periodic sync was missed and SNTP should be restarted.
+    /// A restart is requested at most once per resync interval.
+    pub fn restart_due(&mut self, uptime_ms: u64) -> bool {
+        if self.status(uptime_ms) != TimeStatus::Stale {
+            return false;
+        }
+
+        match self.last_restart_ms {
+            Some(restart_ms) if uptime_ms.saturating_sub(restart_ms) < self.resync_interval_ms => {
+                false
+            }
+            _ => {
+                self.last_restart_ms = Some(uptime_ms);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPOCH_MS: u64 = 1_700_000_000_000;
+
+    #[test]
+    fn validate_bounds() {
+        assert!(TimeConfig::default().validate().is_ok());
+
+        let config = |servers: &[&str], timezone: &str, resync_interval_secs| TimeConfig {
+            servers: servers.iter().map(|s| s.to_string()).collect(),
+            timezone: timezone.into(),
+            resync_interval_secs,
+        };
+
+        assert!(config(&["a", "b", "c"], "UTC0", MIN_RESYNC_INTERVAL_SECS)
+            .validate()
+            .is_ok());
+        assert!(config(&["a"], "UTC0", MAX_RESYNC_INTERVAL_SECS)
+            .validate()
+            .is_ok());
+
+        assert!(config(&[], "UTC0", 3600).validate().is_err());
+        assert!(config(&["a", "b", "c", "d"], "UTC0", 3600)
+            .validate()
+            .is_err());
+        assert!(config(&["a", ""], "UTC0", 3600).validate().is_err());
+        assert!(config(&["a"], "", 3600).validate().is_err());
+        assert!(config(&["a"], "UTC0", MIN_RESYNC_INTERVAL_SECS - 1)
+            .validate()
+            .is_err());
+        assert!(config(&["a"], "UTC0", MAX_RESYNC_INTERVAL_SECS + 1)
+            .validate()
+            .is_err());
+        assert!(config(&["a"], "UTC0", u32::MAX).validate().is_err());
+    }
+
+    #[test]
+    fn max_resync_interval_fits_in_milliseconds() {
+        assert!(MAX_RESYNC_INTERVAL_SECS.checked_mul(1000).is_some());
+    }
+
+    #[test]
+    fn not_synced_until_the_first_sync() {
+        let mut sync = TimeSyncState::new(60);
+
+        assert_eq!(sync.status(0), TimeStatus::NotSynced);
+        assert_eq!(sync.status(1_000_000), TimeStatus::NotSynced);
+        assert!(!sync.is_valid());
+        assert_eq!(sync.last_sync_epoch_ms(), None);
+
+        sync.on_sync(5_000, EPOCH_MS);
+
+        assert_eq!(sync.status(5_000), TimeStatus::Synced);
+        assert!(sync.is_valid());
+        assert_eq!(sync.last_sync_epoch_ms(), Some(EPOCH_MS));
+    }
+
+    #[test]
+    fn stale_after_three_missed_intervals() {
+        let mut sync = TimeSyncState::new(60);
+        sync.on_sync(10_000, EPOCH_MS);
+
+        assert_eq!(sync.status(10_000 + 180_000), TimeStatus::Synced);
+        assert_eq!(sync.status(10_000 + 180_001), TimeStatus::Stale);
+        // A stale clock stays valid
+        assert!(sync.is_valid());
+
+        sync.on_sync(200_000, EPOCH_MS + 190_000);
+        assert_eq!(sync.status(200_000), TimeStatus::Synced);
+        assert_eq!(sync.last_sync_epoch_ms(), Some(EPOCH_MS + 190_000));
+    }
+
+    #[test]
+    fn the_resync_interval_moves_the_stale_limit() {
+        let mut sync = TimeSyncState::new(60);
+        sync.on_sync(0, EPOCH_MS);
+        assert_eq!(sync.status(200_000), TimeStatus::Stale);
+
+        sync.set_resync_interval(3600);
+        assert_eq!(sync.status(200_000), TimeStatus::Synced);
+    }
+
+    #[test]
+    fn restart_due_once_per_interval_while_stale() {
+        let mut sync = TimeSyncState::new(60);
+
+        // Never synced is not stale, SNTP is still on its first sync
+        assert!(!sync.restart_due(1_000_000));
+
+        sync.on_sync(0, EPOCH_MS);
+        assert!(!sync.restart_due(180_000));
+
+        assert!(sync.restart_due(180_001));
+        assert!(!sync.restart_due(180_002));
+        assert!(!sync.restart_due(240_000));
+        assert!(sync.restart_due(240_001));
+
+        // A sync clears the restart
+        sync.on_sync(250_000, EPOCH_MS + 250_000);
+        assert!(!sync.restart_due(250_000));
+        assert!(sync.restart_due(250_000 + 180_001));
+    }
+}
@@ -0,0 +1,99 @@
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Error};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{storage_load, storage_load_raw, storage_store, storage_store_raw};
+
+const TLS_CONFIG_STORAGE_KEY: &str = "tls";
+/// The PEM files are stored raw, a certificate and its key do not fit in one blob
+const TLS_CERT_STORAGE_KEY: &str = "tls_cert";
+const TLS_KEY_STORAGE_KEY: &str = "tls_key";
+
+// EMBEDDED_TLS_CERT and EMBEDDED_TLS_KEY, from the files given at build time through
+// the SIGMIOT_TLS_CERT and SIGMIOT_TLS_KEY variables
+include!(concat!(env!("OUT_DIR"), "/tls_embedded.rs"));
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// Serve HTTPS and WSS instead of HTTP and WS
+    pub enabled: bool,
+}
+
+/// The NUL-terminated PEM certificate and private key of the server
+#[derive(Clone, Copy)]
+pub struct TlsCredentials {
+    pub cert: &'static [u8],
+    pub key: &'static [u8],
+}
+
+lazy_static! {
+    static ref TLS_CREDENTIALS: Mutex<Option<TlsCredentials>> = Mutex::new(None);
+}
+
+/// Load the TLS config and the server certificate, the one stored in NVS
+/// takes precedence over the embedded one
+pub fn tls_load() {
+    let config = storage_load::<TlsConfig>(TLS_CONFIG_STORAGE_KEY).unwrap_or_default();
+    if !config.enabled {
+        info!("TLS disabled");
+        return;
+    }
+
+    let stored = storage_load_raw(TLS_CERT_STORAGE_KEY).zip(storage_load_raw(TLS_KEY_STORAGE_KEY));
+
+    let credentials = match (stored, EMBEDDED_TLS_CERT.zip(EMBEDDED_TLS_KEY)) {
+        (Some((cert, key)), _) => {
+            info!("TLS enabled with the stored certificate");
+            // The server keeps the certificate for its lifetime, which is the device uptime
+            TlsCredentials {
+                cert: Box::leak(nul_terminated(cert).into_boxed_slice()),
+                key: Box::leak(nul_terminated(key).into_boxed_slice()),
+            }
+        }
+        (None, Some((cert, key))) => {
+            info!("TLS enabled with the embedded certificate");
+            TlsCredentials { cert, key }
+        }
+        (None, None) => {
+            warn!("TLS enabled but there is no certificate, serving plain HTTP");
+            return;
+        }
+    };
+
+    *TLS_CREDENTIALS.lock().unwrap() = Some(credentials);
+}
+
+/// The server certificate, None if TLS is off
+pub fn tls_credentials() -> Option<TlsCredentials> {
+    *TLS_CREDENTIALS.lock().unwrap()
+}
+
+/// Enable or disable TLS and optionally store a new certificate, applied at the next boot
+/// # Arguments
+/// * `config` - Whether TLS is enabled
+/// * `cert_key` - The PEM certificate and private key, the current ones are kept if None
+pub fn tls_config_set(config: TlsConfig, cert_key: Option<(&str, &str)>) -> Result<(), Error> {
+    if let Some((cert, key)) = cert_key {
+        if !cert.contains("-----BEGIN CERTIFICATE-----") {
+            return Err(anyhow!("the certificate is not in PEM format"));
+        }
+        if !key.contains("PRIVATE KEY-----") {
+            return Err(anyhow!("the private key is not in PEM format"));
+        }
+
+        storage_store_raw(TLS_CERT_STORAGE_KEY, cert.as_bytes())?;
+        storage_store_raw(TLS_KEY_STORAGE_KEY, key.as_bytes())?;
+    }
+
+    storage_store(TLS_CONFIG_STORAGE_KEY, &config)
+}
+
+fn nul_terminated(mut pem: Vec<u8>) -> Vec<u8> {
+    if pem.last() != Some(&0) {
+        pem.push(0);
+    }
+    pem
+}
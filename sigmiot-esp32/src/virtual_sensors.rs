@@ -0,0 +1,369 @@
+use crate::sensors::SensorData;
+
+/// A sensor which has no hardware behind it and computes its values
+/// from the data of the real sensors
+pub trait VirtualSensor {
+    /// Recompute the values from the freshly read sensors data
+    /// # Arguments
+    /// * `sensors_data` - The data of all real sensors after the last read
+    fn update(&mut self, sensors_data: &[&SensorData]);
+
+    /// Get the computed sensor data
+    fn get_data(&self) -> &SensorData;
+
+    /// Get the sensor name
+    /// # Returns
+    /// * The sensor name
+    fn get_name(&self) -> &String;
+}
+
+/// Find a value of the given sensor in the sensors data
+/// # Arguments
+/// * `sensors_data` - The data to search in
+/// * `sensor_name` - The name of the source sensor
+/// * `value_name` - The name of the value
+fn find_value(sensors_data: &[&SensorData], sensor_name: &str, value_name: &str) -> Option<f32> {
+    sensors_data
+        .iter()
+        .find(|s| s.get_name() == sensor_name)
+        .and_then(|s| s.get_value(value_name))
+        .map(|v| v.value)
+}
+
+/// Dew point in °C using the Magnus formula
+pub fn dew_point(temperature: f32, humidity: f32) -> f32 {
+    const A: f32 = 17.62;
+    const B: f32 = 243.12;
+
+    let gamma = (humidity.max(0.1) / 100.0).ln() + A * temperature / (B + temperature);
+    B * gamma / (A - gamma)
+}
+
+/// Absolute humidity in g/m³
+pub fn absolute_humidity(temperature: f32, humidity: f32) -> f32 {
+    let saturation_pressure = 6.112 * (17.67 * temperature / (temperature + 243.5)).exp();
+    saturation_pressure * humidity * 2.1674 / (273.15 + temperature)
+}
+
+/// Heat index in °C using the NOAA (Rothfusz) regression
+pub fn heat_index(temperature: f32, humidity: f32) -> f32 {
+    let t = temperature * 9.0 / 5.0 + 32.0;
+    let rh = humidity;
+
+    // The simple formula is accurate enough below 80 °F
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+
+    let hi = if (simple + t) / 2.0 < 80.0 {
+        simple
+    } else {
+        let mut hi = -42.379 + 2.049_015_2 * t + 10.143_331 * rh
+            - 0.224_755_4 * t * rh
+            - 0.006_837_83 * t * t
+            - 0.054_817_17 * rh * rh
+            + 0.001_228_74 * t * t * rh
+            + 0.000_852_82 * t * rh * rh
+            - 0.000_001_99 * t * t * rh * rh;
+
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            hi -= ((13.0 - rh) / 4.0) * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            hi += ((rh - 85.0) / 10.0) * ((87.0 - t) / 5.0);
+        }
+
+        hi
+    };
+
+    (hi - 32.0) * 5.0 / 9.0
+}
+
+/// Altitude in meters from the barometric formula
+/// # Arguments
+/// * `pressure` - The measured pressure in hPa
+/// * `sea_level_pressure` - The reference pressure at sea level in hPa
+pub fn altitude(pressure: f32, sea_level_pressure: f32) -> f32 {
+    44330.0 * (1.0 - (pressure / sea_level_pressure).powf(1.0 / 5.255))
+}
+
+/// Dew point, absolute humidity and heat index computed from
+/// the temperature and humidity of a source sensor
+pub struct ComfortSensor {
+    source: String,
+    data: SensorData,
+}
+
+impl ComfortSensor {
+    /// Create a new ComfortSensor
+    /// # Arguments
+    /// * `sensor_name` - The name of the virtual sensor
+    /// * `sensor_location` - The location of the virtual sensor
+    /// * `source` - The name of the sensor providing temperature and humidity
+    pub fn new(sensor_name: &str, sensor_location: &str, source: &str) -> Self {
+        Self {
+            source: source.into(),
+            data: SensorData::new(
+                sensor_name,
+                vec![
+                    "dew_point".into(),
+                    "absolute_humidity".into(),
+                    "heat_index".into(),
+                ],
+                sensor_location.into(),
+            ),
+        }
+    }
+}
+
+impl VirtualSensor for ComfortSensor {
+    fn update(&mut self, sensors_data: &[&SensorData]) {
+        let temperature = find_value(sensors_data, &self.source, "temperature");
+        let humidity = find_value(sensors_data, &self.source, "humidity");
+
+        if let (Some(t), Some(rh)) = (temperature, humidity) {
+            self.data.push_value("dew_point", dew_point(t, rh), "°C");
+            self.data
+                .push_value("absolute_humidity", absolute_humidity(t, rh), "g/m³");
+            self.data.push_value("heat_index", heat_index(t, rh), "°C");
+        }
+    }
+
+    fn get_data(&self) -> &SensorData {
+        &self.data
+    }
+
+    fn get_name(&self) -> &String {
+        self.data.get_name()
+    }
+}
+
+/// Altitude computed from the pressure of a source sensor
+pub struct AltitudeSensor {
+    source: String,
+    sea_level_pressure: f32,
+    data: SensorData,
+}
+
+impl AltitudeSensor {
+    /// Create a new AltitudeSensor
+    /// # Arguments
+    /// * `sensor_name` - The name of the virtual sensor
+    /// * `sensor_location` - The location of the virtual sensor
+    /// * `source` - The name of the sensor providing pressure in hPa
+    /// * `sea_level_pressure` - The reference pressure at sea level in hPa
+    pub fn new(
+        sensor_name: &str,
+        sensor_location: &str,
+        source: &str,
+        sea_level_pressure: f32,
+    ) -> Self {
+        assert!(sea_level_pressure > 0.0);
+        Self {
+            source: source.into(),
+            sea_level_pressure,
+            data: SensorData::new(sensor_name, vec!["altitude".into()], sensor_location.into()),
+        }
+    }
+}
+
+impl VirtualSensor for AltitudeSensor {
+    fn update(&mut self, sensors_data: &[&SensorData]) {
+        if let Some(pressure) = find_value(sensors_data, &self.source, "pressure") {
+            self.data
+                .push_value("altitude", altitude(pressure, self.sea_level_pressure), "m");
+        }
+    }
+
+    fn get_data(&self) -> &SensorData {
+        &self.data
+    }
+
+    fn get_name(&self) -> &String {
+        self.data.get_name()
+    }
+}
+
+/// Day/night state computed from the illuminance of a source sensor.
+/// The value is 1 for day and 0 for night.
+pub struct DayNightSensor {
+    source: String,
+    threshold: f32,
+    hysteresis: f32,
+    is_day: Option<bool>,
+    data: SensorData,
+}
+
+impl DayNightSensor {
+    /// Create a new DayNightSensor
+    /// # Arguments
+    /// * `sensor_name` - The name of the virtual sensor
+    /// * `sensor_location` - The location of the virtual sensor
+    /// * `source` - The name of the sensor providing illuminance in lx
+    /// * `threshold` - The illuminance in lx separating day and night
+    /// * `hysteresis` - The band in lx around the threshold where the state is kept
+    pub fn new(
+        sensor_name: &str,
+        sensor_location: &str,
+        source: &str,
+        threshold: f32,
+        hysteresis: f32,
+    ) -> Self {
+        Self {
+            source: source.into(),
+            threshold,
+            hysteresis,
+            is_day: None,
+            data: SensorData::new(
+                sensor_name,
+                vec!["day_night".into()],
+                sensor_location.into(),
+            ),
+        }
+    }
+}
+
+impl VirtualSensor for DayNightSensor {
+    fn update(&mut self, sensors_data: &[&SensorData]) {
+        let illuminance = match find_value(sensors_data, &self.source, "illuminance") {
+            Some(illuminance) => illuminance,
+            None => return,
+        };
+
+        let half_band = self.hysteresis / 2.0;
+        let is_day = match self.is_day {
+            Some(true) => illuminance > self.threshold - half_band,
+            Some(false) => illuminance >= self.threshold + half_band,
+            None => illuminance >= self.threshold,
+        };
+
+        self.is_day = Some(is_day);
+        self.data
+            .push_value("day_night", if is_day { 1.0 } else { 0.0 }, "");
+    }
+
+    fn get_data(&self) -> &SensorData {
+        &self.data
+    }
+
+    fn get_name(&self) -> &String {
+        self.data.get_name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    fn fahrenheit_to_celsius(fahrenheit: f32) -> f32 {
+        (fahrenheit - 32.0) * 5.0 / 9.0
+    }
+
+    #[test]
+    fn dew_point_reference_values() {
+        assert_near(dew_point(20.0, 50.0), 9.3, 0.1);
+        assert_near(dew_point(30.0, 80.0), 26.2, 0.1);
+        assert_near(dew_point(0.0, 60.0), -6.8, 0.1);
+        // Saturated air is at its dew point
+        assert_near(dew_point(25.0, 100.0), 25.0, 0.01);
+    }
+
+    #[test]
+    fn absolute_humidity_reference_values() {
+        assert_near(absolute_humidity(20.0, 50.0), 8.65, 0.05);
+        assert_near(absolute_humidity(30.0, 80.0), 24.3, 0.1);
+        assert_near(absolute_humidity(20.0, 0.0), 0.0, 0.001);
+    }
+
+    #[test]
+    fn heat_index_matches_the_noaa_table() {
+        // The NOAA table gives °F, rounded to the degree
+        for (temperature, humidity, heat_index_f) in [
+            (90.0, 70.0, 106.0),
+            (100.0, 40.0, 109.0),
+            (84.0, 90.0, 98.0),
+            (80.0, 40.0, 80.0),
+        ] {
+            assert_near(
+                heat_index(fahrenheit_to_celsius(temperature), humidity),
+                fahrenheit_to_celsius(heat_index_f),
+                0.5,
+            );
+        }
+    }
+
+    #[test]
+    fn heat_index_is_close_to_the_temperature_in_mild_air() {
+        assert_near(heat_index(20.0, 50.0), 19.4, 0.1);
+    }
+
+    #[test]
+    fn altitude_of_the_standard_atmosphere() {
+        assert_near(altitude(1013.25, 1013.25), 0.0, 0.01);
+        assert_near(altitude(898.76, 1013.25), 1000.0, 1.0);
+        assert_near(altitude(795.01, 1013.25), 2000.0, 1.0);
+        // Under the reference pressure is above the sea level
+        assert_near(altitude(1000.0, 1020.0), 166.7, 0.5);
+        assert!(altitude(1030.0, 1013.25) < 0.0);
+    }
+
+    fn source(name: &str, values: &[(&str, f32)]) -> SensorData {
+        let mut data = SensorData::new(name, vec![], "room1".into());
+        for (value_name, value) in values {
+            data.push_value(value_name, *value, "");
+        }
+        data
+    }
+
+    fn value(sensor: &dyn VirtualSensor, value_name: &str) -> Option<f32> {
+        sensor.get_data().get_value(value_name).map(|v| v.value)
+    }
+
+    #[test]
+    fn comfort_sensor_reads_its_source() {
+        let mut sensor = ComfortSensor::new("comfort", "room1", "BME280");
+        let other = source("other", &[("temperature", 35.0), ("humidity", 90.0)]);
+
+        sensor.update(&[&other]);
+        assert_eq!(value(&sensor, "dew_point"), None);
+
+        let bme280 = source("BME280", &[("temperature", 20.0), ("humidity", 50.0)]);
+        sensor.update(&[&other, &bme280]);
+
+        assert_eq!(value(&sensor, "dew_point"), Some(dew_point(20.0, 50.0)));
+        assert_eq!(
+            value(&sensor, "absolute_humidity"),
+            Some(absolute_humidity(20.0, 50.0))
+        );
+        assert_eq!(value(&sensor, "heat_index"), Some(heat_index(20.0, 50.0)));
+    }
+
+    #[test]
+    fn altitude_sensor_uses_its_sea_level_pressure() {
+        let mut sensor = AltitudeSensor::new("altitude", "room1", "BME280", 1020.0);
+
+        sensor.update(&[&source("BME280", &[("pressure", 1000.0)])]);
+
+        assert_eq!(value(&sensor, "altitude"), Some(altitude(1000.0, 1020.0)));
+    }
+
+    #[test]
+    fn day_night_switches_outside_of_the_hysteresis_band() {
+        let mut sensor = DayNightSensor::new("daylight", "room1", "GY30", 50.0, 20.0);
+        let mut states = vec![];
+
+        for illuminance in [45.0, 55.0, 60.0, 45.0, 41.0, 39.0, 55.0, 60.0] {
+            sensor.update(&[&source("GY30", &[("illuminance", illuminance)])]);
+            states.push(value(&sensor, "day_night").unwrap());
+        }
+
+        assert_eq!(states, vec![0.0, 0.0, 1.0, 1.0, 1.0, 0.0, 0.0, 1.0]);
+    }
+}
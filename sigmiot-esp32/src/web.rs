@@ -0,0 +1,57 @@
+use anyhow::Error;
+use embedded_svc::http::server::{HandlerResult, Method, Request};
+use embedded_svc::http::Headers;
+use embedded_svc::io::Write;
+use esp_idf_svc::http::server::{EspHttpConnection, EspHttpServer};
+
+/// A gzip-compressed static file of the dashboard, embedded by build.rs
+pub struct WebAsset {
+    pub path: &'static str,
+    pub content_type: &'static str,
+    /// Quoted hash of the uncompressed content
+    pub etag: &'static str,
+    pub data: &'static [u8],
+}
+
+include!(concat!(env!("OUT_DIR"), "/web_assets.rs"));
+
+/// The file served at /
+const INDEX_PATH: &str = "/index.html";
+
+/// Serve the embedded dashboard files, index.html is also served at /
+pub fn web_register(server: &mut EspHttpServer) -> Result<(), Error> {
+    for asset in WEB_ASSETS.iter() {
+        server.fn_handler(asset.path, Method::Get, move |req| serve_asset(req, asset))?;
+
+        if asset.path == INDEX_PATH {
+            server.fn_handler("/", Method::Get, move |req| serve_asset(req, asset))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn serve_asset(req: Request<&mut EspHttpConnection>, asset: &WebAsset) -> HandlerResult {
+    // The file names carry no version, so the browsers revalidate them
+    // at every load and get a 304 until a new firmware changes them
+    let cache_headers = [("ETag", asset.etag), ("Cache-Control", "no-cache")];
+
+    if req.header("If-None-Match") == Some(asset.etag) {
+        req.into_response(304, Some("Not Modified"), &cache_headers)?;
+        return Ok(());
+    }
+
+    req.into_response(
+        200,
+        None,
+        &[
+            ("Content-Type", asset.content_type),
+            ("Content-Encoding", "gzip"),
+            cache_headers[0],
+            cache_headers[1],
+        ],
+    )?
+    .write_all(asset.data)?;
+
+    Ok(())
+}
@@ -1,15 +1,103 @@
-
+use std::ffi::CString;
 use std::net::Ipv4Addr;
 use std::time::Duration;
 
+use anyhow::{anyhow, Error};
 use embedded_svc::wifi::*;
 
+use esp_idf_hal::modem::Modem;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
-use esp_idf_svc::netif::{EspNetifWait, EspNetif};
+use esp_idf_svc::netif::{EspNetif, EspNetifWait};
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
-use esp_idf_svc::{wifi::*};
+use esp_idf_svc::wifi::*;
 use esp_idf_sys::EspError;
-use esp_idf_hal::modem::Modem;
+
+use crate::network::StaticIpConfig;
+use crate::storage::{storage_load, storage_store};
+use crate::wifi_policy::{KnownNetwork, ScannedNetwork};
+
+const WIFI_NETWORKS_STORAGE_KEY: &str = "wifi_networks";
+const MAX_SSID_LEN: usize = 32;
+const MAX_PASSWORD_LEN: usize = 64;
+const MAX_KNOWN_NETWORKS: usize = 8;
+
+pub fn validate_network(network: &KnownNetwork) -> Result<(), Error> {
+    if network.ssid.is_empty() || network.ssid.len() > MAX_SSID_LEN {
+        return Err(anyhow!("SSID must be 1 to {} bytes long", MAX_SSID_LEN));
+    }
+
+    if network.password.len() > MAX_PASSWORD_LEN {
+        return Err(anyhow!(
+            "password must be at most {} bytes long",
+            MAX_PASSWORD_LEN
+        ));
+    }
+
+    Ok(())
+}
+
+/// Load the known Wi-Fi networks from NVS. The network given at build time through
+/// the SIGMIOT_WIFI_SSID and SIGMIOT_WIFI_PASSWORD variables is used when none are stored.
+pub fn wifi_networks_load() -> Vec<KnownNetwork> {
+    let mut networks: Vec<KnownNetwork> =
+        storage_load(WIFI_NETWORKS_STORAGE_KEY).unwrap_or_default();
+
+    networks.retain(|network| validate_network(network).is_ok());
+
+    if networks.is_empty() {
+        if let Some(ssid) = option_env!("SIGMIOT_WIFI_SSID") {
+            networks.push(KnownNetwork {
+                ssid: ssid.to_string(),
+                password: option_env!("SIGMIOT_WIFI_PASSWORD")
+                    .unwrap_or("")
+                    .to_string(),
+                priority: 0,
+            });
+        }
+    }
+
+    networks
+}
+
+/// Add a known network or replace the one with the same SSID and persist the list
+pub fn wifi_networks_add(network: KnownNetwork) -> Result<(), Error> {
+    validate_network(&network)?;
+
+    let mut networks = wifi_networks_load();
+    networks.retain(|known| known.ssid != network.ssid);
+
+    if networks.len() >= MAX_KNOWN_NETWORKS {
+        return Err(anyhow!("too many networks, max is {}", MAX_KNOWN_NETWORKS));
+    }
+
+    networks.push(network);
+    storage_store(WIFI_NETWORKS_STORAGE_KEY, &networks)
+}
+
+/// Remove a known network and persist the list
+/// # Returns
+/// * false if there is no network with this SSID
+pub fn wifi_networks_remove(ssid: &str) -> Result<bool, Error> {
+    let mut networks = wifi_networks_load();
+    let count = networks.len();
+    networks.retain(|known| known.ssid != ssid);
+
+    if networks.len() == count {
+        return Ok(false);
+    }
+
+    storage_store(WIFI_NETWORKS_STORAGE_KEY, &networks)?;
+    Ok(true)
+}
+
+/// The access point the station is connected to
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkInfo {
+    pub ssid: String,
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    pub rssi: i8,
+}
 
 pub struct Wifi<'a> {
     wifi_inst: Box<EspWifi<'a>>,
@@ -17,73 +105,225 @@ pub struct Wifi<'a> {
 }
 
 impl<'a> Wifi<'a> {
-    pub fn new(modem: Modem) -> Self {
+    pub fn new(modem: Modem, default_nvs: EspDefaultNvsPartition) -> Self {
         //let periph = peripherals::Peripherals::take().unwrap();
 
         let sys_loop = EspSystemEventLoop::take().unwrap();
 
-        let default_nvs = EspDefaultNvsPartition::take().unwrap();
-
         let wifi = Box::new(EspWifi::new(modem, sys_loop.clone(), Some(default_nvs)).unwrap());
 
-        Wifi { wifi_inst: wifi, sys_loop }
+        Wifi {
+            wifi_inst: wifi,
+            sys_loop,
+        }
     }
 
-    pub fn connect(&mut self, ssid: &'static str, psk: &'static str) -> Result<(), EspError> {
-        let ap_infos = self.wifi_inst.scan()?;
-        let ours = ap_infos.into_iter().find(|a| a.ssid == ssid);
-        let channel = if let Some(ours) = ours {
-            println!("found configured access point {} on channel {}", ssid, ours.channel);
-            Some(ours.channel)
-        } else {
-            panic!("Configured access point was not found!");
-        };
+    /// Set the host name and the optional static IP of the station,
+    /// must be called before connecting
+    pub fn configure_network(
+        &mut self,
+        hostname: &str,
+        static_ip: Option<&StaticIpConfig>,
+    ) -> Result<(), Error> {
+        let handle = self.wifi_inst.sta_netif().handle();
 
-        self.wifi_inst.set_configuration(&Configuration::Client(ClientConfiguration {
-            ssid: ssid.into(),
-            bssid: None,
-            auth_method: Default::default(),
-            password: psk.into(),
-            channel
-        }))?;
+        let hostname = CString::new(hostname)?;
+        esp_idf_sys::esp!(unsafe {
+            esp_idf_sys::esp_netif_set_hostname(handle, hostname.as_ptr())
+        })?;
 
-        let config = self.wifi_inst.get_configuration().unwrap();
+        if let Some(static_ip) = static_ip {
+            let to_esp = |ip: Ipv4Addr| esp_idf_sys::esp_ip4_addr_t {
+                addr: u32::from_le_bytes(ip.octets()),
+            };
+            let netmask = Ipv4Addr::from(u32::MAX << (32 - static_ip.prefix_len as u32));
 
-        println!("WiFi config {:?}", config);
+            let ip_info = esp_idf_sys::esp_netif_ip_info_t {
+                ip: to_esp(static_ip.ip),
+                netmask: to_esp(netmask),
+                gw: to_esp(static_ip.gateway),
+            };
 
-        self.wifi_inst.start()?;
+            unsafe {
+                // Fails harmlessly if the DHCP client was already stopped
+                esp_idf_sys::esp_netif_dhcpc_stop(handle);
+                esp_idf_sys::esp!(esp_idf_sys::esp_netif_set_ip_info(handle, &ip_info))?;
 
-        self.wifi_inst.connect()?;
+                if let Some(dns) = static_ip.dns {
+                    let mut dns_info: esp_idf_sys::esp_netif_dns_info_t = Default::default();
+                    dns_info.ip.u_addr.ip4 = to_esp(dns);
+                    dns_info.ip.type_ = esp_idf_sys::ESP_IPADDR_TYPE_V4 as u8;
+                    esp_idf_sys::esp!(esp_idf_sys::esp_netif_set_dns_info(
+                        handle,
+                        esp_idf_sys::esp_netif_dns_type_t_ESP_NETIF_DNS_MAIN,
+                        &mut dns_info,
+                    ))?;
+                }
+            }
 
-        println!("WiFi connected!");
+            log::info!(
+                "Static IP {}/{} gateway {}",
+                static_ip.ip,
+                static_ip.prefix_len,
+                static_ip.gateway
+            );
+        }
 
+        Ok(())
+    }
 
-        if !EspNetifWait::new::<EspNetif>(self.wifi_inst.sta_netif(), &self.sys_loop)?.wait_with_timeout(
-            Duration::from_secs(30),
-            || {
-                self.wifi_inst.is_up().unwrap() && self.wifi_inst.sta_netif().get_ip_info().unwrap().ip != Ipv4Addr::new(0, 0, 0, 0)
-            }) {
-                panic!("Wifi did not connect or did not receive a DHCP lease");
-            };
+    pub fn sys_loop(&self) -> &EspSystemEventLoop {
+        &self.sys_loop
+    }
+
+    /// Start the station if it is not running, it must be started to scan
+    fn ensure_station_started(&mut self) -> Result<(), Error> {
+        if !matches!(
+            self.wifi_inst.get_configuration()?,
+            Configuration::Client(_)
+        ) {
+            if self.wifi_inst.is_started()? {
+                self.wifi_inst.stop()?;
+            }
+            self.wifi_inst
+                .set_configuration(&Configuration::Client(Default::default()))?;
+        }
+
+        if !self.wifi_inst.is_started()? {
+            self.wifi_inst.start()?;
+        }
+
+        Ok(())
+    }
+
+    /// Connect to an access point and wait for the DHCP lease
+    /// # Arguments
+    /// * `ssid` - The network name
+    /// * `psk` - The network password
+    /// * `channel` - The channel of the access point, found by a scan
+    /// * `bssid` - Connect to this access point of the network, any if None
+    pub fn connect(
+        &mut self,
+        ssid: &str,
+        psk: &str,
+        channel: Option<u8>,
+        bssid: Option<[u8; 6]>,
+    ) -> Result<(), Error> {
+        self.ensure_station_started()?;
+
+        if self.wifi_inst.is_connected()? {
+            self.wifi_inst.disconnect()?;
+        }
+
+        self.wifi_inst
+            .set_configuration(&Configuration::Client(ClientConfiguration {
+                ssid: ssid.into(),
+                bssid,
+                auth_method: Default::default(),
+                password: psk.into(),
+                channel,
+            }))?;
+
+        log::info!("Connecting to {} on channel {:?}", ssid, channel);
+
+        self.wifi_inst.connect()?;
+
+        log::info!("WiFi connected!");
+
+        if !EspNetifWait::new::<EspNetif>(self.wifi_inst.sta_netif(), &self.sys_loop)?
+            .wait_with_timeout(Duration::from_secs(30), || self.ip().is_some())
+        {
+            self.wifi_inst.disconnect()?;
+            return Err(anyhow!(
+                "Wifi did not connect or did not receive a DHCP lease"
+            ));
+        };
 
         let ip_info = self.wifi_inst.sta_netif().get_ip_info()?;
-        println!("Wifi DHCP info: {:?}", ip_info);
+        log::info!("Wifi DHCP info: {:?}", ip_info);
 
         Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn scan(&mut self) -> Result<Vec<String>, EspError> {
-        let access_points = self.wifi_inst.driver_mut().scan()?;
+    /// The IP address of the station once it is connected and has a lease
+    pub fn ip(&self) -> Option<Ipv4Addr> {
+        if !self.wifi_inst.is_up().unwrap_or(false) {
+            return None;
+        }
 
-        let mut ssids = Vec::new();
+        self.wifi_inst
+            .sta_netif()
+            .get_ip_info()
+            .ok()
+            .map(|info| info.ip)
+            .filter(|ip| !ip.is_unspecified())
+    }
 
-        for points in access_points.iter() {
-            println!("SSID: {}, channel {}, signal strength {}",
-                points.ssid, points.channel, points.signal_strength);
-            ssids.push(points.ssid.to_string().clone());
+    /// The access point the station is connected to, None if not connected
+    pub fn link_info(&self) -> Option<LinkInfo> {
+        let mut record: esp_idf_sys::wifi_ap_record_t = Default::default();
+        let result = unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut record) };
+        if result != esp_idf_sys::ESP_OK {
+            return None;
         }
 
-        Ok(ssids)
+        let ssid_len = record
+            .ssid
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(record.ssid.len());
+
+        Some(LinkInfo {
+            ssid: String::from_utf8_lossy(&record.ssid[..ssid_len]).into_owned(),
+            bssid: record.bssid,
+            channel: record.primary,
+            rssi: record.rssi,
+        })
+    }
+
+    /// Scan the access points in range
+    pub fn scan_networks(&mut self) -> Result<Vec<ScannedNetwork>, Error> {
+        self.ensure_station_started()?;
+
+        Ok(self
+            .wifi_inst
+            .scan()?
+            .into_iter()
+            .map(|ap| ScannedNetwork {
+                ssid: ap.ssid.to_string(),
+                bssid: ap.bssid,
+                channel: ap.channel,
+                rssi: ap.signal_strength,
+            })
+            .collect())
+    }
+
+    /// Stop the station and start an open access point
+    /// # Returns
+    /// * The IP address of the device on the access point network
+    pub fn start_access_point(&mut self, ssid: &str) -> Result<Ipv4Addr, Error> {
+        if self.wifi_inst.is_started()? {
+            self.wifi_inst.stop()?;
+        }
+
+        self.wifi_inst
+            .set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+                ssid: ssid.into(),
+                auth_method: AuthMethod::None,
+                channel: 1,
+                ..Default::default()
+            }))?;
+
+        self.wifi_inst.start()?;
+
+        let ip = self.wifi_inst.ap_netif().get_ip_info()?.ip;
+        log::info!("Access point {} started at {}", ssid, ip);
+
+        Ok(ip)
+    }
+
+    /// The MAC address of the station interface
+    pub fn mac(&self) -> Result<[u8; 6], EspError> {
+        self.wifi_inst.sta_netif().get_mac()
     }
 }
@@ -0,0 +1,262 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Only roam away from an access point weaker than this
+const ROAM_RSSI_THRESHOLD: i8 = -70;
+/// A roaming candidate must be this much stronger than the current access point
+const ROAM_HYSTERESIS_DB: i8 = 8;
+
+/// A network the device may connect to, the higher priority is preferred
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KnownNetwork {
+    pub ssid: String,
+    pub password: String,
+    #[serde(default)]
+    pub priority: u8,
+}
+
+/// An access point found by a scan
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScannedNetwork {
+    pub ssid: String,
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    pub rssi: i8,
+}
+
+/// An access point of a known network
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub network: KnownNetwork,
+    pub access_point: ScannedNetwork,
+}
+
+/// The access point the device is connected to
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurrentLink {
+    pub ssid: String,
+    pub bssid: [u8; 6],
+    pub rssi: i8,
+}
+
+/// Get the access points of the known networks, best first: by network priority
+/// then by signal strength
+pub fn rank_candidates(known: &[KnownNetwork], scanned: &[ScannedNetwork]) -> Vec<Candidate> {
+    let mut candidates: Vec<Candidate> = scanned
+        .iter()
+        .filter_map(|access_point| {
+            known
+                .iter()
+                .find(|network| network.ssid == access_point.ssid)
+                .map(|network| Candidate {
+                    network: network.clone(),
+                    access_point: access_point.clone(),
+                })
+        })
+        .collect();
+
+    candidates.sort_by(|a, b| {
+        b.network
+            .priority
+            .cmp(&a.network.priority)
+            .then(b.access_point.rssi.cmp(&a.access_point.rssi))
+    });
+
+    candidates
+}
+
+/// Decide if the device should move to another access point
+/// # Arguments
+/// * `current` - The access point the device is connected to
+/// * `known` - The known networks
+/// * `candidates` - The ranked candidates of a fresh scan
+/// # Returns
+/// * The candidate to roam to, None to stay
+pub fn roam_target<'a>(
+    current: &CurrentLink,
+    known: &[KnownNetwork],
+    candidates: &'a [Candidate],
+) -> Option<&'a Candidate> {
+    let best = candidates.first()?;
+    if best.access_point.bssid == current.bssid {
+        return None;
+    }
+
+    let current_priority = known
+        .iter()
+        .find(|network| network.ssid == current.ssid)
+        .map(|network| network.priority)
+        .unwrap_or(0);
+
+    if best.network.priority > current_priority {
+        // A preferred network came into range
+        return Some(best);
+    }
+
+    let weak = current.rssi < ROAM_RSSI_THRESHOLD;
+    let stronger = best.access_point.rssi as i16 >= current.rssi as i16 + ROAM_HYSTERESIS_DB as i16;
+
+    if best.network.priority == current_priority && weak && stronger {
+        Some(best)
+    } else {
+        None
+    }
+}
+
+/// Exponential backoff between reconnection attempts
+#[derive(Debug)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// The delay before the next attempt, doubled at every call up to the max
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .base
+            .checked_mul(1 << self.attempt.min(16))
+            .unwrap_or(self.max)
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn known(ssid: &str, priority: u8) -> KnownNetwork {
+        KnownNetwork {
+            ssid: ssid.into(),
+            password: "password".into(),
+            priority,
+        }
+    }
+
+    fn scanned(ssid: &str, bssid: u8, rssi: i8) -> ScannedNetwork {
+        ScannedNetwork {
+            ssid: ssid.into(),
+            bssid: [bssid; 6],
+            channel: 1,
+            rssi,
+        }
+    }
+
+    fn link(ssid: &str, bssid: u8, rssi: i8) -> CurrentLink {
+        CurrentLink {
+            ssid: ssid.into(),
+            bssid: [bssid; 6],
+            rssi,
+        }
+    }
+
+    /// The bssid of the access point the device would roam to
+    fn roam(current: &CurrentLink, known: &[KnownNetwork], scan: &[ScannedNetwork]) -> Option<u8> {
+        let candidates = rank_candidates(known, scan);
+        roam_target(current, known, &candidates).map(|c| c.access_point.bssid[0])
+    }
+
+    #[test]
+    fn candidates_by_priority_then_signal() {
+        let known = [known("home", 1), known("office", 2)];
+        let scan = [
+            scanned("home", 1, -40),
+            scanned("unknown", 2, -30),
+            scanned("office", 3, -80),
+            scanned("home", 4, -60),
+        ];
+
+        let bssids: Vec<u8> = rank_candidates(&known, &scan)
+            .iter()
+            .map(|c| c.access_point.bssid[0])
+            .collect();
+        assert_eq!(bssids, vec![3, 1, 4]);
+    }
+
+    #[test]
+    fn roam_only_from_a_weak_link_to_a_clearly_stronger_one() {
+        let known = [known("home", 0)];
+
+        // Strong enough, even if a better access point is around
+        assert_eq!(
+            roam(&link("home", 1, -65), &known, &[scanned("home", 2, -40)]),
+            None
+        );
+
+        // Weak, but the other access point is within the hysteresis
+        assert_eq!(
+            roam(&link("home", 1, -75), &known, &[scanned("home", 2, -68)]),
+            None
+        );
+
+        // Weak and the other access point is 8 dB stronger
+        assert_eq!(
+            roam(&link("home", 1, -75), &known, &[scanned("home", 2, -67)]),
+            Some(2)
+        );
+
+        // The current access point is already the best one
+        assert_eq!(
+            roam(&link("home", 1, -90), &known, &[scanned("home", 1, -90)]),
+            None
+        );
+    }
+
+    #[test]
+    fn roam_to_a_preferred_network_whatever_the_signal() {
+        let known = [known("home", 0), known("office", 1)];
+
+        assert_eq!(
+            roam(&link("home", 1, -40), &known, &[scanned("office", 2, -85)]),
+            Some(2)
+        );
+
+        // Not to a less preferred one, even from a weak link
+        assert_eq!(
+            roam(&link("office", 2, -85), &known, &[scanned("home", 1, -40)]),
+            None
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+
+        let delays: Vec<u64> = (0..6).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+
+        // No overflow after many failures
+        for _ in 0..100 {
+            backoff.next_delay();
+        }
+        assert_eq!(backoff.next_delay(), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn backoff_starts_over_after_a_reset() {
+        let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(60));
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.next_delay();
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_millis(500));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+}
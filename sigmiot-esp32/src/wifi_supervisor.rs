@@ -0,0 +1,253 @@
+use std::net::Ipv4Addr;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use esp_idf_svc::netif::IpEvent;
+use esp_idf_svc::wifi::WifiEvent;
+use lazy_static::lazy_static;
+use log::{info, warn};
+
+use crate::wifi::{wifi_networks_load, Wifi};
+use crate::wifi_policy::{rank_candidates, roam_target, Backoff, CurrentLink, KnownNetwork};
+
+/// Interval of the link checks reporting the RSSI changes
+const LINK_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(2);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(300);
+/// RSSI changes smaller than this are not reported
+const RSSI_REPORT_DELTA: i8 = 5;
+/// Scan for a better access point when the RSSI is under this
+const ROAM_SCAN_RSSI: i8 = -70;
+/// Scan every this many link checks even when the link is good,
+/// for a network with a higher priority coming into range
+const PRIORITY_SCAN_EVERY: u32 = 10;
+
+/// The state of the Wi-Fi link reported to the clients
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WifiStatus {
+    pub connected: bool,
+    pub ssid: String,
+    pub rssi: i8,
+    pub channel: u8,
+    pub ip: Option<Ipv4Addr>,
+    /// What changed last, e.g. "disconnected" or "roamed"
+    pub reason: String,
+    /// Incremented at every change so the clients can tell if they have seen it
+    pub seq: u64,
+}
+
+lazy_static! {
+    static ref WIFI_STATUS: Mutex<WifiStatus> = Mutex::new(WifiStatus::default());
+}
+
+enum SupervisorEvent {
+    Disconnected,
+    IpAssigned,
+}
+
+pub fn wifi_status() -> WifiStatus {
+    WIFI_STATUS.lock().unwrap().clone()
+}
+
+/// Update the reported status if it changed noticeably
+fn wifi_status_update(wifi: &Wifi, reason: &str) {
+    let link = wifi.link_info();
+    let ip = wifi.ip();
+
+    let mut status = WIFI_STATUS.lock().unwrap();
+    let mut next = WifiStatus {
+        connected: link.is_some() && ip.is_some(),
+        ssid: link.as_ref().map(|l| l.ssid.clone()).unwrap_or_default(),
+        rssi: link.as_ref().map(|l| l.rssi).unwrap_or(0),
+        channel: link.as_ref().map(|l| l.channel).unwrap_or(0),
+        ip,
+        reason: reason.to_string(),
+        seq: status.seq,
+    };
+
+    let changed = next.connected != status.connected
+        || next.ssid != status.ssid
+        || next.channel != status.channel
+        || next.ip != status.ip
+        || (next.rssi as i16 - status.rssi as i16).abs() >= RSSI_REPORT_DELTA as i16;
+
+    if !changed {
+        return;
+    }
+
+    next.seq += 1;
+    *status = next.clone();
+    drop(status);
+
+    if next.connected {
+        info!(
+            "Wi-Fi {}: {} channel {} RSSI {} dBm IP {:?}",
+            reason, next.ssid, next.channel, next.rssi, next.ip
+        );
+    } else {
+        warn!("Wi-Fi {}: not connected", reason);
+    }
+}
+
+/// Connect to the best known network in range, the other ones are tried in order
+/// if it fails
+pub fn wifi_connect_best(wifi: &mut Wifi, networks: &[KnownNetwork]) -> Result<(), Error> {
+    if networks.is_empty() {
+        return Err(anyhow!("no known networks"));
+    }
+
+    let candidates = rank_candidates(networks, &wifi.scan_networks()?);
+    if candidates.is_empty() {
+        return Err(anyhow!("no known network in range"));
+    }
+
+    for candidate in candidates.iter() {
+        let ap = &candidate.access_point;
+        info!(
+            "Connecting to {} on channel {} ({} dBm)",
+            ap.ssid, ap.channel, ap.rssi
+        );
+
+        match wifi.connect(
+            &candidate.network.ssid,
+            &candidate.network.password,
+            Some(ap.channel),
+            Some(ap.bssid),
+        ) {
+            Ok(()) => return Ok(()),
+            Err(e) => warn!("Cannot connect to {}: {:?}", ap.ssid, e),
+        }
+    }
+
+    Err(anyhow!("cannot connect to any known network"))
+}
+
+/// Watch the Wi-Fi link in a thread: reconnect with backoff when it drops,
+/// roam to a better access point and report the link changes
+pub fn wifi_supervisor_start(wifi: Wifi<'static>) -> Result<(), Error> {
+    let (tx, rx) = channel::<SupervisorEvent>();
+
+    let wifi_tx = tx.clone();
+    let wifi_subscription = wifi.sys_loop().subscribe(move |event: &WifiEvent| {
+        if matches!(event, WifiEvent::StaDisconnected) {
+            let _ = wifi_tx.send(SupervisorEvent::Disconnected);
+        }
+    })?;
+
+    let ip_subscription = wifi.sys_loop().subscribe(move |event: &IpEvent| {
+        if matches!(event, IpEvent::DhcpIpAssigned(_)) {
+            let _ = tx.send(SupervisorEvent::IpAssigned);
+        }
+    })?;
+
+    std::thread::Builder::new()
+        .name("wifi_supervisor".into())
+        .stack_size(8192)
+        .spawn(move || {
+            // The subscriptions stop when dropped
+            let _subscriptions = (wifi_subscription, ip_subscription);
+            supervise(wifi, rx);
+        })?;
+
+    Ok(())
+}
+
+fn supervise(mut wifi: Wifi<'static>, events: Receiver<SupervisorEvent>) {
+    let mut backoff = Backoff::new(RECONNECT_BASE_DELAY, RECONNECT_MAX_DELAY);
+    let mut checks = 0_u32;
+    wifi_status_update(&wifi, "connected");
+
+    loop {
+        match events.recv_timeout(LINK_CHECK_INTERVAL) {
+            Ok(SupervisorEvent::Disconnected) => {
+                // Our own reconnections and roaming disconnect too
+                if wifi.ip().is_none() {
+                    wifi_status_update(&wifi, "disconnected");
+                    reconnect(&mut wifi, &mut backoff);
+                }
+            }
+            Ok(SupervisorEvent::IpAssigned) => wifi_status_update(&wifi, "got IP"),
+            Err(RecvTimeoutError::Timeout) => {
+                checks = checks.wrapping_add(1);
+                check_link(&mut wifi, checks % PRIORITY_SCAN_EVERY == 0);
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+fn reconnect(wifi: &mut Wifi, backoff: &mut Backoff) {
+    loop {
+        match wifi_connect_best(wifi, &wifi_networks_load()) {
+            Ok(()) => {
+                backoff.reset();
+                wifi_status_update(wifi, "reconnected");
+                return;
+            }
+            Err(e) => {
+                let delay = backoff.next_delay();
+                warn!(
+                    "Wi-Fi reconnection failed: {:?}, retrying in {:?}",
+                    e, delay
+                );
+                std::thread::sleep(delay);
+            }
+        }
+    }
+}
+
+/// Report the link changes and roam if a better access point is in range
+/// # Arguments
+/// * `force_scan` - Scan even if the link is good
+fn check_link(wifi: &mut Wifi, force_scan: bool) {
+    let link = match wifi.link_info() {
+        Some(link) => link,
+        None => return,
+    };
+
+    wifi_status_update(wifi, "link changed");
+
+    if link.rssi >= ROAM_SCAN_RSSI && !force_scan {
+        return;
+    }
+
+    let networks = wifi_networks_load();
+    let scanned = match wifi.scan_networks() {
+        Ok(scanned) => scanned,
+        Err(e) => {
+            warn!("Wi-Fi scan failed: {:?}", e);
+            return;
+        }
+    };
+
+    let candidates = rank_candidates(&networks, &scanned);
+    let current = CurrentLink {
+        ssid: link.ssid.clone(),
+        bssid: link.bssid,
+        rssi: link.rssi,
+    };
+
+    if let Some(target) = roam_target(&current, &networks, &candidates) {
+        let ap = &target.access_point;
+        info!(
+            "Roaming from {} ({} dBm) to {} on channel {} ({} dBm)",
+            link.ssid, link.rssi, ap.ssid, ap.channel, ap.rssi
+        );
+
+        match wifi.connect(
+            &target.network.ssid,
+            &target.network.password,
+            Some(ap.channel),
+            Some(ap.bssid),
+        ) {
+            Ok(()) => wifi_status_update(wifi, "roamed"),
+            Err(e) => {
+                warn!("Roaming failed: {:?}", e);
+                let mut backoff = Backoff::new(RECONNECT_BASE_DELAY, RECONNECT_MAX_DELAY);
+                reconnect(wifi, &mut backoff);
+            }
+        }
+    }
+}
@@ -1,4 +1,3 @@
-
 use std::cell::Cell;
 
 use log::debug;
@@ -6,57 +5,72 @@ use log::debug;
 use embedded_svc::ws::asynch::server::Acceptor;
 use embedded_svc::ws::FrameType;
 
+use embassy_futures::select::select;
 use embassy_sync::blocking_mutex::raw::{NoopRawMutex, RawMutex};
 use embassy_sync::mutex::Mutex as AsyncMutex;
-use embassy_futures::select::{select, Either};
 
-use crate::data_channel::get_protobuf_data_async;
-use crate::sigmiot_log::remote_logger_set_enable;
+use crate::commands::{handle_request, ClientSession};
+use crate::data_channel::{get_protobuf_data_async, subscribe, DataSubscriber};
+use crate::sigmiot_log::{remote_logger_acquire, remote_logger_release};
 
-pub async fn ws_conn_handler<A: Acceptor>(acceptor: A) {
+pub async fn ws_conn_handler<A: Acceptor>(handler_id: usize, acceptor: &A) {
     loop {
-        debug!("[WS HANDLER] Wait for connection...");
+        debug!("[WS HANDLER {}] Wait for connection...", handler_id);
         let (sender, mut receiver) = acceptor.accept().await.unwrap();
-        debug!("[WS HANDLER] ..got connection");
+        debug!("[WS HANDLER {}] ..got connection", handler_id);
         let sender = AsyncMutex::<NoopRawMutex, _>::new(sender);
 
         let count_frames = AsyncMutex::<NoopRawMutex, _>::new(Cell::new(0_u32));
 
-        remote_logger_set_enable(true);
-
-        let mut open = true;
-        loop {
-            if !open {
-                break;
+        let mut subscriber = match subscribe() {
+            Some(subscriber) => subscriber,
+            None => {
+                debug!(
+                    "[WS HANDLER {}] No data subscriber available, dropping connection",
+                    handler_id
+                );
+                continue;
             }
-
-            open = process_connection(&mut receiver, &sender, &count_frames).await.unwrap();
-        }
-
-        remote_logger_set_enable(false);
-        debug!("[WS HANDLER] Connection closed");
+        };
+
+        let session =
+            AsyncMutex::<NoopRawMutex, _>::new(ClientSession::new(remote_logger_acquire()));
+
+        // Both futures live as long as the connection, neither is cancelled half-way
+        // through a frame or with a message taken from the subscriber
+        select(
+            receive_requests(&mut receiver, &sender, &count_frames, &session),
+            send_data(&mut subscriber, &sender, &count_frames, &session),
+        )
+        .await;
+
+        drop(session);
+        remote_logger_release();
+        debug!("[WS HANDLER {}] Connection closed", handler_id);
     }
 }
 
-async fn process_connection(
-    receiver: impl embedded_svc::ws::asynch::Receiver,
+/// Answer the requests of the client until it closes the connection
+async fn receive_requests(
+    mut receiver: impl embedded_svc::ws::asynch::Receiver,
     sender: &AsyncMutex<impl RawMutex, impl embedded_svc::ws::asynch::Sender>,
     counter: &AsyncMutex<impl RawMutex, Cell<u32>>,
-) -> Result<bool, ()> {
-    match select(
-        receive(receiver, sender, counter),
-        async {
-            let out_bytes = get_protobuf_data_async().await;
-            send(&sender, &counter, &out_bytes).await;
-        },
-    ).await {
-        Either::First(open) => {
-            return open;
-        }
-        Either::Second(_) => {
-            return Ok(true);
-        }
-    };
+    session: &AsyncMutex<impl RawMutex, ClientSession>,
+) {
+    while let Ok(true) = receive(&mut receiver, sender, counter, session).await {}
+}
+
+/// Send every published message to the client
+async fn send_data(
+    subscriber: &mut DataSubscriber,
+    sender: &AsyncMutex<impl RawMutex, impl embedded_svc::ws::asynch::Sender>,
+    counter: &AsyncMutex<impl RawMutex, Cell<u32>>,
+    session: &AsyncMutex<impl RawMutex, ClientSession>,
+) {
+    loop {
+        let out_bytes = get_protobuf_data_async(subscriber, session).await;
+        send(sender, counter, &out_bytes).await;
+    }
 }
 
 async fn send(
@@ -77,30 +91,35 @@ async fn send(
         .unwrap();
 }
 
-#[allow(unused)]
 pub async fn receive(
     mut receiver: impl embedded_svc::ws::asynch::Receiver,
     sender: &AsyncMutex<impl RawMutex, impl embedded_svc::ws::asynch::Sender>,
     counter: &AsyncMutex<impl RawMutex, Cell<u32>>,
+    session: &AsyncMutex<impl RawMutex, ClientSession>,
 ) -> Result<bool, ()> {
     let mut recv_buffer: [u8; 4096] = [0; 4096];
-    let (frame_type, mut size) = receiver.recv(&mut recv_buffer).await.unwrap();
-    size = if size > 0 { size } else { 1 };
-    let debug = core::str::from_utf8(&recv_buffer[..size - 1]).unwrap();
-
-    debug!("[WS RECEIVE] msg: {}", debug);
-
-    let count = counter.lock().await;
-    count.set(count.get() + 1);
-    debug!("[WS RECEIVE] Frame number: {:?}", count.get());
-    debug!(
-        "[WS RECEIVE] Frame {:?} Type: {:?}",
-        count.get(),
-        frame_type);
+    let (frame_type, size) = receiver.recv(&mut recv_buffer).await.unwrap();
+
+    {
+        let count = counter.lock().await;
+        count.set(count.get() + 1);
+        debug!("[WS RECEIVE] Frame number: {:?}", count.get());
+        debug!(
+            "[WS RECEIVE] Frame {:?} Type: {:?} Size: {}",
+            count.get(),
+            frame_type,
+            size
+        );
+    }
 
     let hold_open = match frame_type {
         FrameType::Text(_) => false, // We don't support text frames
-        FrameType::Binary(_) => true, // TODO - handle binary frames
+        FrameType::Binary(false) => {
+            let response = handle_request(&recv_buffer[..size], &mut *session.lock().await).await;
+            send(sender, counter, &response).await;
+            true
+        }
+        FrameType::Binary(true) => true, // Fragmented requests are not supported
         FrameType::Continue(_) => true,
         FrameType::Ping => true,
         FrameType::Pong => true,
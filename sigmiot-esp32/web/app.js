@@ -0,0 +1,165 @@
+const MAX_LOG_LINES = 200;
+const MAX_ALERTS = 20;
+const DEVICE_REFRESH_MS = 10000;
+const RECONNECT_MAX_MS = 30000;
+
+let reconnectDelay = 1000;
+let wifiStatus = null;
+
+function element(tag, className, text) {
+    const el = document.createElement(tag);
+    if (className) {
+        el.className = className;
+    }
+    if (text !== undefined) {
+        el.textContent = text;
+    }
+    return el;
+}
+
+function formatTime(timestampMs, wallClock) {
+    if (!wallClock) {
+        return "+" + (timestampMs / 1000).toFixed(1) + " s";
+    }
+    return new Date(timestampMs).toLocaleTimeString();
+}
+
+function formatValue(value) {
+    return Number.isInteger(value) ? value.toString() : value.toFixed(2);
+}
+
+function showSensors(sensors) {
+    const container = document.getElementById("sensors");
+    container.replaceChildren(...sensors.map((sensor) => {
+        const card = element("div", "card");
+        card.append(element("h3", null, sensor.name));
+        card.append(element("div", "meta",
+            [sensor.location, sensor.sensor_type, formatTime(sensor.timestamp_ms, sensor.wall_clock)]
+                .filter((s) => s).join(" · ")));
+
+        const table = element("table");
+        for (const value of sensor.values) {
+            const row = element("tr");
+            row.append(element("td", null, value.name));
+            row.append(element("td", "value", formatValue(value.value)));
+            row.append(element("td", null, value.unit + (value.filtered ? " ~" : "")));
+            table.append(row);
+        }
+        card.append(table);
+        return card;
+    }));
+}
+
+function showLogs(logs) {
+    const pre = document.getElementById("logs");
+    const follow = pre.scrollTop + pre.clientHeight >= pre.scrollHeight - 5;
+
+    for (const log of logs) {
+        const line = element("div", "log-" + log.level.toLowerCase(),
+            formatTime(log.timestamp_ms, log.wall_clock) + " " + log.level.padEnd(5) + " " +
+            log.target + ": " + log.message);
+        pre.append(line);
+    }
+    while (pre.childElementCount > MAX_LOG_LINES) {
+        pre.firstElementChild.remove();
+    }
+
+    if (follow) {
+        pre.scrollTop = pre.scrollHeight;
+    }
+}
+
+function showAlerts(alerts) {
+    const list = document.getElementById("alerts");
+    for (const alert of alerts) {
+        list.prepend(element("li", alert.raised ? "raised" : "cleared",
+            formatTime(alert.timestamp, true) + " " + alert.sensor_name + "/" + alert.value_name +
+            " " + formatValue(alert.value) + " " + alert.message));
+    }
+    while (list.childElementCount > MAX_ALERTS) {
+        list.lastElementChild.remove();
+    }
+}
+
+function showDevice(device) {
+    const rows = {
+        "Id": device.id,
+        "Host name": device.hostname,
+        "Firmware": device.firmware_version,
+        "IP": device.ip || "-",
+        "Uptime": Math.floor(device.uptime_ms / 60000) + " min",
+        "Clock": device.time_valid ? "synced" : "not synced",
+        "Free heap": device.free_heap + " bytes",
+    };
+
+    const wifi = wifiStatus || device.wifi;
+    rows["Wi-Fi"] = wifi.connected ? wifi.ssid + " (" + wifi.rssi + " dBm, channel " + wifi.channel + ")" : "not connected";
+
+    document.getElementById("device").replaceChildren(...Object.entries(rows).flatMap(([name, value]) =>
+        [element("dt", null, name), element("dd", null, value)]));
+}
+
+async function refreshDevice() {
+    try {
+        const response = await apiFetch("/api/v1/device");
+        if (response.ok) {
+            showDevice(await response.json());
+        }
+    } catch (e) {
+        console.warn("Cannot get the device info", e);
+    }
+}
+
+function handleMessage(msg) {
+    if (msg.sensors.length > 0) {
+        showSensors(msg.sensors);
+    }
+    if (msg.alerts.length > 0) {
+        showAlerts(msg.alerts);
+    }
+    if (msg.logs.length > 0) {
+        showLogs(msg.logs);
+    }
+    if (msg.wifi_status) {
+        wifiStatus = msg.wifi_status;
+        refreshDevice();
+    }
+}
+
+function setConnected(connected) {
+    const badge = document.getElementById("connection");
+    badge.textContent = connected ? "live" : "offline";
+    badge.className = "badge " + (connected ? "on" : "off");
+}
+
+function connect() {
+    const ws = new WebSocket(wsUrl("/ws"));
+    ws.binaryType = "arraybuffer";
+
+    ws.onopen = () => {
+        reconnectDelay = 1000;
+        setConnected(true);
+    };
+
+    ws.onmessage = (event) => {
+        try {
+            if (typeof event.data === "string") {
+                handleMessage(Object.assign({ sensors: [], logs: [], alerts: [] }, JSON.parse(event.data)));
+            } else {
+                handleMessage(decodeMessageResponse(new Uint8Array(event.data)));
+            }
+        } catch (e) {
+            console.warn("Cannot decode a message", e);
+        }
+    };
+
+    ws.onclose = () => {
+        setConnected(false);
+        setTimeout(connect, reconnectDelay);
+        reconnectDelay = Math.min(reconnectDelay * 2, RECONNECT_MAX_MS);
+    };
+}
+
+refreshDevice();
+setInterval(refreshDevice, DEVICE_REFRESH_MS);
+connect();
@@ -0,0 +1,30 @@
+// Credentials of the dashboard requests. A token given as ?token=... in the page URL
+// is kept for the session; with Basic authentication the browser sends the
+// credentials itself after its login prompt.
+
+const AUTH_TOKEN_KEY = "sigmiot-token";
+
+(function () {
+    const token = new URLSearchParams(location.search).get("token");
+    if (token) {
+        sessionStorage.setItem(AUTH_TOKEN_KEY, token);
+    }
+})();
+
+function authHeaders() {
+    const token = sessionStorage.getItem(AUTH_TOKEN_KEY);
+    return token ? { "Authorization": "Bearer " + token } : {};
+}
+
+function apiFetch(url, options = {}) {
+    return fetch(url, Object.assign({}, options, {
+        headers: Object.assign({}, options.headers || {}, authHeaders()),
+    }));
+}
+
+// The browsers cannot set headers on a WebSocket, the token goes in the query
+function wsUrl(path) {
+    const scheme = location.protocol === "https:" ? "wss:" : "ws:";
+    const token = sessionStorage.getItem(AUTH_TOKEN_KEY);
+    return scheme + "//" + location.host + path + (token ? "?token=" + encodeURIComponent(token) : "");
+}
@@ -0,0 +1,177 @@
+// Minimal decoder of the MessageResponse messages of protos/sigmiot_data.proto,
+// only the fields shown by the dashboard are decoded
+
+class ProtoReader {
+    constructor(bytes) {
+        this.bytes = bytes;
+        this.pos = 0;
+    }
+
+    done() {
+        return this.pos >= this.bytes.length;
+    }
+
+    varint() {
+        let value = 0n;
+        let shift = 0n;
+        for (;;) {
+            const byte = this.bytes[this.pos++];
+            value |= BigInt(byte & 0x7f) << shift;
+            if ((byte & 0x80) === 0) {
+                return value;
+            }
+            shift += 7n;
+        }
+    }
+
+    uint() {
+        return Number(this.varint());
+    }
+
+    int32() {
+        return Number(BigInt.asIntN(32, this.varint()));
+    }
+
+    bool() {
+        return this.varint() !== 0n;
+    }
+
+    float() {
+        const view = new DataView(this.bytes.buffer, this.bytes.byteOffset + this.pos, 4);
+        this.pos += 4;
+        return view.getFloat32(0, true);
+    }
+
+    bytesField() {
+        const len = this.uint();
+        const bytes = this.bytes.subarray(this.pos, this.pos + len);
+        this.pos += len;
+        return bytes;
+    }
+
+    string() {
+        return new TextDecoder().decode(this.bytesField());
+    }
+
+    message(decode) {
+        return decode(new ProtoReader(this.bytesField()));
+    }
+
+    skip(wireType) {
+        switch (wireType) {
+            case 0: this.varint(); break;
+            case 1: this.pos += 8; break;
+            case 2: this.bytesField(); break;
+            case 5: this.pos += 4; break;
+            default: throw new Error("unsupported wire type " + wireType);
+        }
+    }
+
+    // Call handle(field) for every field, it returns false for the unknown ones which are skipped
+    fields(handle) {
+        while (!this.done()) {
+            const key = this.uint();
+            const field = key >>> 3;
+            const wireType = key & 7;
+            if (!handle(field)) {
+                this.skip(wireType);
+            }
+        }
+    }
+}
+
+function decodeSensorValue(r) {
+    const value = { name: "", value: 0, raw_value: 0, unit: "", filtered: false };
+    r.fields((field) => {
+        switch (field) {
+            case 1: value.name = r.string(); return true;
+            case 2: value.value = r.float(); return true;
+            case 3: value.unit = r.string(); return true;
+            case 4: value.raw_value = r.float(); return true;
+            case 5: value.filtered = r.bool(); return true;
+            default: return false;
+        }
+    });
+    return value;
+}
+
+function decodeSensor(r) {
+    const sensor = { name: "", sensor_type: "", location: "", values: [], timestamp_ms: 0, wall_clock: false };
+    r.fields((field) => {
+        switch (field) {
+            case 1: sensor.name = r.string(); return true;
+            case 2: sensor.sensor_type = r.string(); return true;
+            case 3: sensor.location = r.string(); return true;
+            case 4: sensor.values.push(r.message(decodeSensorValue)); return true;
+            case 5: sensor.timestamp_ms = r.uint(); return true;
+            case 6: sensor.wall_clock = r.bool(); return true;
+            default: return false;
+        }
+    });
+    return sensor;
+}
+
+function decodeLog(r) {
+    const log = { message: "", level: "", timestamp_ms: 0, wall_clock: false, target: "" };
+    r.fields((field) => {
+        switch (field) {
+            case 1: log.message = r.string(); return true;
+            case 3: log.level = r.string(); return true;
+            case 4: log.timestamp_ms = r.uint(); return true;
+            case 5: log.wall_clock = r.bool(); return true;
+            case 6: log.target = r.string(); return true;
+            default: return false;
+        }
+    });
+    return log;
+}
+
+function decodeAlert(r) {
+    const alert = { rule_id: "", sensor_name: "", value_name: "", value: 0, raised: true, timestamp: 0, message: "" };
+    r.fields((field) => {
+        switch (field) {
+            case 1: alert.rule_id = r.string(); return true;
+            case 2: alert.sensor_name = r.string(); return true;
+            case 3: alert.value_name = r.string(); return true;
+            case 4: alert.value = r.float(); return true;
+            case 5: alert.raised = r.uint() === 0; return true;
+            case 6: alert.timestamp = r.uint(); return true;
+            case 7: alert.message = r.string(); return true;
+            default: return false;
+        }
+    });
+    return alert;
+}
+
+function decodeWifiStatus(r) {
+    const status = { connected: false, ssid: "", rssi: 0, channel: 0, ip: "", reason: "" };
+    r.fields((field) => {
+        switch (field) {
+            case 1: status.connected = r.bool(); return true;
+            case 2: status.ssid = r.string(); return true;
+            case 3: status.rssi = r.int32(); return true;
+            case 4: status.channel = r.uint(); return true;
+            case 5: status.ip = r.string(); return true;
+            case 6: status.reason = r.string(); return true;
+            default: return false;
+        }
+    });
+    return status;
+}
+
+function decodeMessageResponse(bytes) {
+    const r = new ProtoReader(bytes);
+    const msg = { status: 0, sensors: [], logs: [], alerts: [], time_valid: false, wifi_status: null };
+    r.fields((field) => {
+        switch (field) {
+            case 1: msg.status = r.uint(); return true;
+            case 2: msg.sensors.push(r.message(decodeSensor)); return true;
+            case 3: msg.logs.push(r.message(decodeLog)); return true;
+            case 6: msg.alerts.push(r.message(decodeAlert)); return true;
+            case 10: msg.time_valid = r.bool(); return true;
+            case 13: msg.wifi_status = r.message(decodeWifiStatus); return true;
+            default: return false;
+        }
+    });
+    return msg;
+}
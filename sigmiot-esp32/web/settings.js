@@ -0,0 +1,176 @@
+async function request(method, url, body) {
+    const response = await apiFetch(url, {
+        method,
+        headers: body ? { "Content-Type": "application/json" } : {},
+        body: body ? JSON.stringify(body) : undefined,
+    });
+    const text = await response.text();
+    if (!response.ok) {
+        let message = text;
+        try {
+            message = JSON.parse(text).error || text;
+        } catch (e) {
+            // Plain text error
+        }
+        throw new Error(message);
+    }
+    return text ? JSON.parse(text) : null;
+}
+
+function showResult(form, error) {
+    const result = form.querySelector(".result");
+    result.textContent = error ? error.message : "Saved";
+    result.style.color = error ? "#b71c1c" : "#2e7d32";
+}
+
+function handleSubmit(form, submit) {
+    form.addEventListener("submit", async (event) => {
+        event.preventDefault();
+        try {
+            await submit(form.elements);
+            showResult(form, null);
+        } catch (e) {
+            showResult(form, e);
+        }
+    });
+}
+
+async function loadLogLevels() {
+    const form = document.getElementById("log-levels");
+    const levels = await request("GET", "/api/v1/log_levels");
+    form.elements.max_level.value = levels.max_level;
+    form.elements.targets.value = levels.targets.map((t) => t.target + "=" + t.level).join("\n");
+}
+
+async function loadNetwork() {
+    const form = document.getElementById("network");
+    const config = await request("GET", "/api/v1/network");
+    form.elements.hostname.value = config.hostname;
+    form.elements.static.checked = config.static_ip !== null;
+    if (config.static_ip) {
+        form.elements.ip.value = config.static_ip.ip;
+        form.elements.prefix_len.value = config.static_ip.prefix_len;
+        form.elements.gateway.value = config.static_ip.gateway;
+        form.elements.dns.value = config.static_ip.dns || "";
+    }
+}
+
+async function loadWifiNetworks() {
+    const list = document.getElementById("wifi-networks");
+    const networks = await request("GET", "/api/v1/wifi/networks");
+    list.replaceChildren(...networks.map((network) => {
+        const item = document.createElement("li");
+        item.textContent = network.ssid + " (priority " + network.priority + ") ";
+
+        const remove = document.createElement("button");
+        remove.textContent = "Remove";
+        remove.onclick = async () => {
+            await request("DELETE", "/api/v1/wifi/networks/" + encodeURIComponent(network.ssid));
+            loadWifiNetworks();
+        };
+        item.append(remove);
+        return item;
+    }));
+}
+
+handleSubmit(document.getElementById("log-levels"), async (fields) => {
+    const targets = fields.targets.value.split("\n")
+        .map((line) => line.trim())
+        .filter((line) => line)
+        .map((line) => {
+            const [target, level] = line.split("=").map((s) => s.trim());
+            return { target, level };
+        });
+    await request("POST", "/api/v1/log_levels", { max_level: fields.max_level.value, targets });
+});
+
+handleSubmit(document.getElementById("network"), async (fields) => {
+    const staticIp = fields.static.checked ? {
+        ip: fields.ip.value,
+        prefix_len: Number(fields.prefix_len.value),
+        gateway: fields.gateway.value,
+        dns: fields.dns.value || null,
+    } : null;
+    await request("POST", "/api/v1/network", { hostname: fields.hostname.value, static_ip: staticIp });
+});
+
+handleSubmit(document.getElementById("wifi"), async (fields) => {
+    await request("POST", "/api/v1/wifi/networks", {
+        ssid: fields.ssid.value,
+        password: fields.password.value,
+        priority: Number(fields.priority.value),
+    });
+    fields.password.value = "";
+    loadWifiNetworks();
+});
+
+async function loadAuth() {
+    const status = await request("GET", "/api/v1/auth");
+    const methods = [];
+    if (status.token_set) {
+        methods.push("token");
+    }
+    if (status.username) {
+        methods.push("Basic as " + status.username);
+    }
+    document.getElementById("auth-status").textContent =
+        methods.length > 0 ? "Enabled: " + methods.join(", ") : "Disabled, leave all empty to keep it off";
+}
+
+handleSubmit(document.getElementById("auth"), async (fields) => {
+    await request("POST", "/api/v1/auth", {
+        token: fields.token.value,
+        username: fields.username.value,
+        password: fields.password.value,
+    });
+    if (fields.token.value) {
+        sessionStorage.setItem(AUTH_TOKEN_KEY, fields.token.value);
+    }
+    fields.password.value = "";
+    fields.token.value = "";
+    loadAuth();
+});
+
+handleSubmit(document.getElementById("tls"), async (fields) => {
+    const body = { enabled: fields.enabled.checked };
+    if (fields.cert_pem.value || fields.key_pem.value) {
+        body.cert_pem = fields.cert_pem.value;
+        body.key_pem = fields.key_pem.value;
+    }
+    await request("POST", "/api/v1/tls", body);
+    fields.key_pem.value = "";
+});
+
+async function loadDeviceConfig() {
+    const config = await request("GET", "/api/v1/config");
+    document.getElementById("device-config").elements.json.value = JSON.stringify(config, null, 2);
+}
+
+handleSubmit(document.getElementById("device-config"), async (fields) => {
+    const config = await request("POST", "/api/v1/config", JSON.parse(fields.json.value));
+    fields.json.value = JSON.stringify(config, null, 2);
+});
+
+document.getElementById("device-config").elements.file.addEventListener("change", async (event) => {
+    const file = event.target.files[0];
+    if (file) {
+        document.getElementById("device-config").elements.json.value = await file.text();
+    }
+});
+
+document.getElementById("device-config-export").onclick = () => {
+    const json = document.getElementById("device-config").elements.json.value;
+    const link = document.createElement("a");
+    link.href = URL.createObjectURL(new Blob([json], { type: "application/json" }));
+    link.download = "sigmiot-config.json";
+    link.click();
+    URL.revokeObjectURL(link.href);
+};
+
+document.getElementById("tls").elements.enabled.checked = location.protocol === "https:";
+
+loadAuth().catch((e) => console.warn("Cannot load the access settings", e));
+loadLogLevels().catch((e) => console.warn("Cannot load the log levels", e));
+loadNetwork().catch((e) => console.warn("Cannot load the network config", e));
+loadWifiNetworks().catch((e) => console.warn("Cannot load the Wi-Fi networks", e));
+loadDeviceConfig().catch((e) => console.warn("Cannot load the device config", e));
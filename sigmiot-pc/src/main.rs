@@ -1,9 +1,11 @@
 use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
 use std::env;
-use std::io::{stdin, stdout, Write};
+use std::io::{stdin, stdout};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use chrono::{Local, TimeZone};
 use termion::event::Key;
 use termion::input::TermRead;
 use termion::raw::IntoRawMode;
@@ -19,15 +21,42 @@ use log::{debug, error, info};
 use simplelog::{Config, LevelFilter, WriteLogger};
 use std::fs::File;
 
-use futures_util::StreamExt;
+use futures_util::{SinkExt, StreamExt};
 use tokio::sync::mpsc;
-use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{connect_async, connect_async_tls_with_config};
 
 use protobuf::{EnumOrUnknown, Message};
 
 include!(concat!(env!("OUT_DIR"), "/protos/mod.rs"));
 
-use sigmiot_data::{message_response, MessageResponse};
+mod ota;
+mod palette;
+mod tls;
+
+use ota::run_ota;
+use palette::{Palette, PaletteOutcome};
+use tls::{parse_fingerprint, tls_connector, TlsTrust};
+
+use sigmiot_data::{
+    message_response, DeviceHealth, GetLogLevelsRequest, HistoryRequest, LogLevelConfig,
+    MessageRequest, MessageResponse, SetLogLevelsRequest,
+};
+
+/// The mDNS service advertised by the devices
+const MDNS_SERVICE_TYPE: &str = "_sigmiot._tcp.local.";
+/// How long the discover mode browses for a device
+const DISCOVER_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many hours of history are requested from the device after connecting
+const HISTORY_BACKFILL_HOURS: u32 = 1;
+/// How many samples of every value are kept for the min/max display
+const HISTORY_MAX_SAMPLES: usize = 512;
+/// Timestamps before 2023-01-01 were taken before the device clock was set
+const MIN_WALL_CLOCK_MS: u64 = 1_672_531_200_000;
+/// The device log levels cycled through by the log level key
+const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
 
 #[derive(Debug, Clone)]
 pub struct SensorValue {
@@ -39,7 +68,6 @@ pub struct SensorValue {
 #[derive(Debug, Clone)]
 pub struct SensorData {
     sensor_name: String,
-    sensor_type: String,
     sensor_location: String,
     sensor_values: Vec<SensorValue>,
 }
@@ -47,20 +75,108 @@ pub struct SensorData {
 #[derive(Debug, Clone)]
 struct Esp32LogEntry {
     pub log_message: String,
-    pub log_timestamp: u64,
+    /// Milliseconds since the Unix epoch if `wall_clock` is set, since boot otherwise
+    pub log_timestamp_ms: u64,
+    pub wall_clock: bool,
     pub log_level: String,
+    pub target: String,
+    /// file:line of the log statement, empty if unknown
+    pub location: String,
+    pub task_name: String,
+}
+
+impl Esp32LogEntry {
+    fn from_response(log: &sigmiot_data::LogDataResponse) -> Self {
+        // Older firmwares only send whole seconds
+        let (log_timestamp_ms, wall_clock) = if log.log_timestamp_ms > 0 {
+            (log.log_timestamp_ms, log.wall_clock)
+        } else {
+            let ms = log.log_timestamp * 1000;
+            (ms, ms >= MIN_WALL_CLOCK_MS)
+        };
+
+        let location = match (log.file.is_empty(), log.line) {
+            (true, _) => String::new(),
+            (false, 0) => log.file.clone(),
+            (false, line) => format!("{}:{}", log.file, line),
+        };
+
+        Self {
+            log_message: log.log_message.clone(),
+            log_timestamp_ms,
+            wall_clock,
+            log_level: log.log_level.clone(),
+            target: log.target.clone(),
+            location,
+            task_name: log.task_name.clone(),
+        }
+    }
+
+    /// An entry made by the client, shown at the time it is made
+    fn local(log_level: &str, log_message: String) -> Self {
+        Self {
+            log_message,
+            log_timestamp_ms: Local::now().timestamp_millis() as u64,
+            wall_clock: true,
+            log_level: log_level.to_string(),
+            target: String::new(),
+            location: String::new(),
+            task_name: String::new(),
+        }
+    }
+
+    /// Local date and time for wall clock timestamps, time since boot otherwise
+    fn format_timestamp(&self) -> String {
+        if self.wall_clock {
+            match Local
+                .timestamp_millis_opt(self.log_timestamp_ms as i64)
+                .single()
+            {
+                Some(date) => date.format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+                None => format!("{} ms", self.log_timestamp_ms),
+            }
+        } else {
+            let secs = self.log_timestamp_ms / 1000;
+            format!(
+                "+{:02}:{:02}:{:02}.{:03}",
+                secs / 3600,
+                secs / 60 % 60,
+                secs % 60,
+                self.log_timestamp_ms % 1000
+            )
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct HistorySeries {
+    sensor_name: String,
+    value_name: String,
+    values: Vec<f32>,
 }
 
 #[derive(Debug)]
 enum ChannelMessage {
     LogsEsp32(Vec<Esp32LogEntry>),
     SensorsData(Vec<SensorData>),
+    History(Vec<HistorySeries>),
+    LogLevel(String),
+    /// The command palette, None once closed
+    Palette(Option<Palette>),
+    Health(DeviceHealth),
     Exit,
 }
 
 struct App {
     logs: Vec<Esp32LogEntry>,
+    /// The global log level of the device, empty until the device reports it
+    log_level: String,
     sensors_data: Vec<SensorData>,
+    /// Recent samples per (sensor name, value name)
+    history: HashMap<(String, String), VecDeque<f32>>,
+    palette: Option<Palette>,
+    /// The last health report of the device
+    health: Option<DeviceHealth>,
 }
 
 const CARGO_PKG_NAME: &str = env!("CARGO_PKG_NAME");
@@ -69,19 +185,194 @@ impl App {
     fn new() -> App {
         App {
             logs: vec![],
+            log_level: String::new(),
             sensors_data: vec![],
+            history: HashMap::new(),
+            palette: None,
+            health: None,
+        }
+    }
+
+    fn push_history(&mut self, sensor_name: &str, value_name: &str, value: f32) {
+        let samples = self
+            .history
+            .entry((sensor_name.to_string(), value_name.to_string()))
+            .or_default();
+
+        samples.push_back(value);
+        while samples.len() > HISTORY_MAX_SAMPLES {
+            samples.pop_front();
+        }
+    }
+
+    /// Prepend the history fetched from the device to the recorded samples
+    fn add_history(&mut self, history: Vec<HistorySeries>) {
+        for series in history {
+            let samples = self
+                .history
+                .entry((series.sensor_name, series.value_name))
+                .or_default();
+
+            for value in series.values.into_iter().rev().filter(|v| !v.is_nan()) {
+                if samples.len() >= HISTORY_MAX_SAMPLES {
+                    break;
+                }
+                samples.push_front(value);
+            }
         }
     }
 
+    /// Get the min and max of the recorded samples of a value
+    fn history_range(&self, sensor_name: &str, value_name: &str) -> Option<(f32, f32)> {
+        let samples = self
+            .history
+            .get(&(sensor_name.to_string(), value_name.to_string()))?;
+
+        samples.iter().fold(None, |range, v| match range {
+            Some((min, max)) => Some((v.min(min), v.max(max))),
+            None => Some((*v, *v)),
+        })
+    }
+
     fn add_log(&mut self, log: &mut Vec<Esp32LogEntry>) {
         self.logs.append(log);
     }
 
     fn add_sensors_data(&mut self, data: Vec<SensorData>) {
+        for sensor in data.iter() {
+            for value in sensor.sensor_values.iter() {
+                self.push_history(&sensor.sensor_name, &value.value_name, value.value);
+            }
+        }
+
         self.sensors_data = data;
     }
 }
 
+/// What to do once connected
+enum Command {
+    /// Show the sensors and the logs
+    Monitor,
+    /// Update the firmware of the device
+    Ota {
+        image: String,
+        /// File with the signature of the image SHA-256 digest
+        signature: Option<String>,
+    },
+}
+
+/// The command line:
+/// `sigmiot-pc [ota <image file> [--sig <signature file>]]
+/// <ws://host/ws | wss://host/ws | discover>
+/// [--token <token>] [--ca <pem file> | --pin <sha256>]`
+struct Args {
+    command: Command,
+    /// The WebSocket URL or "discover"
+    target: String,
+    /// Sent as a bearer token when the device requires authentication
+    token: Option<String>,
+    /// How the certificate of a wss:// device is checked
+    trust: TlsTrust,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Self {
+        let mut command = Command::Monitor;
+        let mut signature = None;
+        let mut target = None;
+        let mut token = env::var("SIGMIOT_TOKEN").ok();
+        let mut trust = TlsTrust::WebPki;
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .unwrap_or_else(|| panic!("{} requires a value", arg))
+            };
+            match arg.as_str() {
+                "ota" if target.is_none() => {
+                    command = Command::Ota {
+                        image: value(),
+                        signature: None,
+                    }
+                }
+                "--sig" => signature = Some(value()),
+                "--token" => token = Some(value()),
+                "--ca" => trust = TlsTrust::CaFile(value()),
+                "--pin" => {
+                    trust = TlsTrust::Pin(
+                        parse_fingerprint(&value()).unwrap_or_else(|e| panic!("{}", e)),
+                    )
+                }
+                _ if target.is_none() => target = Some(arg),
+                _ => panic!("Unexpected argument {}", arg),
+            }
+        }
+
+        if let Command::Ota {
+            signature: ota_signature,
+            ..
+        } = &mut command
+        {
+            *ota_signature = signature;
+        } else if signature.is_some() {
+            panic!("--sig is only used with ota");
+        }
+
+        Self {
+            command,
+            target: target.unwrap_or_else(|| {
+                panic!("This program requires a URL or \"discover\" as argument")
+            }),
+            token,
+            trust,
+        }
+    }
+}
+
+/// Browse for a device advertising the SigmIoT mDNS service
+/// # Returns
+/// * The WebSocket URL of the first device found
+fn discover_device() -> Option<String> {
+    let mdns = mdns_sd::ServiceDaemon::new().expect("Cannot start mDNS");
+    let receiver = mdns.browse(MDNS_SERVICE_TYPE).expect("Cannot browse mDNS");
+    println!("Looking for devices...");
+
+    let deadline = std::time::Instant::now() + DISCOVER_TIMEOUT;
+    let mut url = None;
+    while let Some(remaining) = deadline.checked_duration_since(std::time::Instant::now()) {
+        let info = match receiver.recv_timeout(remaining) {
+            Ok(mdns_sd::ServiceEvent::ServiceResolved(info)) => info,
+            Ok(_) => continue,
+            Err(_) => break,
+        };
+
+        let address = match info.get_addresses_v4().into_iter().next() {
+            Some(address) => address,
+            None => continue,
+        };
+        let path = info.get_property_val_str("path").unwrap_or("/ws");
+        // "wss" when the device serves HTTPS
+        let scheme = info.get_property_val_str("scheme").unwrap_or("ws");
+        let id = info
+            .get_property_val_str("id")
+            .unwrap_or(info.get_fullname());
+
+        info!("Discovered {} at {}:{}", id, address, info.get_port());
+        println!("Found {} at {}", id, address);
+        url = Some(format!(
+            "{}://{}:{}{}",
+            scheme,
+            address,
+            info.get_port(),
+            path
+        ));
+        break;
+    }
+
+    let _ = mdns.shutdown();
+    url
+}
+
 #[tokio::main]
 async fn main() {
     let log_file_name = format!("{}.log", CARGO_PKG_NAME);
@@ -94,14 +385,85 @@ async fn main() {
 
     info!("Starting {}...", CARGO_PKG_NAME);
 
-    let connect_addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| panic!("This program requires at least one argument"));
+    let args = Args::parse(env::args().skip(1));
+    let mut connect_addr = args.target;
+    if connect_addr == "discover" {
+        connect_addr = discover_device()
+            .unwrap_or_else(|| panic!("No device found within {:?}", DISCOVER_TIMEOUT));
+    }
     let url = url::Url::parse(&connect_addr).expect("Cannot parse URL");
     info!("Connecting to {}...", url);
-    let (mut ws, _) = connect_async(&url).await.expect("Failed to connect");
+    let mut request = url
+        .as_str()
+        .into_client_request()
+        .expect("Cannot build the request");
+    if let Some(token) = args.token {
+        request.headers_mut().insert(
+            AUTHORIZATION,
+            format!("Bearer {}", token).parse().expect("Invalid token"),
+        );
+    }
+    let (mut ws, _) = if url.scheme() == "wss" {
+        let connector = tls_connector(&args.trust).unwrap_or_else(|e| panic!("{}", e));
+        connect_async_tls_with_config(request, None, false, Some(connector))
+            .await
+            .expect("Failed to connect")
+    } else {
+        connect_async(request).await.expect("Failed to connect")
+    };
     info!("Connected to {}", url);
 
+    if let Command::Ota { image, signature } = args.command {
+        let image =
+            std::fs::read(&image).unwrap_or_else(|e| panic!("Cannot read {}: {}", image, e));
+        let signature = signature
+            .map(|path| {
+                std::fs::read(&path).unwrap_or_else(|e| panic!("Cannot read {}: {}", path, e))
+            })
+            .unwrap_or_default();
+
+        match run_ota(&mut ws, &image, signature).await {
+            Ok(()) => println!("Firmware updated, the device restarts into it"),
+            Err(e) => {
+                error!("{}", e);
+                println!("{}", e);
+            }
+        }
+
+        let _ = ws.close(None).await;
+        return;
+    }
+
+    let mut history_request = MessageRequest::new();
+    let mut history = HistoryRequest::new();
+    history.hours = HISTORY_BACKFILL_HOURS;
+    history_request.set_get_history(history);
+    ws.send(WsMessage::Binary(history_request.write_to_bytes().unwrap()))
+        .await
+        .expect("Failed to request history");
+
+    let mut log_levels_request = MessageRequest::new();
+    log_levels_request.set_get_log_levels(GetLogLevelsRequest::new());
+    ws.send(WsMessage::Binary(
+        log_levels_request.write_to_bytes().unwrap(),
+    ))
+    .await
+    .expect("Failed to request the log levels");
+
+    // The keys are read by a blocking thread, the terminal is in raw mode
+    // so Ctrl-C arrives as a key too
+    let (key_tx, mut key_rx) = mpsc::channel::<Key>(8);
+    std::thread::spawn(move || {
+        for key in stdin().keys().flatten() {
+            if key_tx.blocking_send(key).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut log_levels: Option<LogLevelConfig> = None;
+    let mut palette: Option<Palette> = None;
+
     // Create a new channel with a capacity of at most 32.
     let (tx, rx) = mpsc::channel::<ChannelMessage>(32);
 
@@ -118,7 +480,8 @@ async fn main() {
                         let msg = msg.unwrap();
                         if msg.is_binary() {
                             let data = msg.into_data();
-                            if let Err(e) = handle_binary_message(data, &tx).await {
+                            let handled = handle_binary_message(data, &tx, &mut log_levels).await;
+                            if let Err(e) = handled {
                                 error!("Error handling binary message: {:?}", e);
                                 break;
                             }
@@ -139,11 +502,61 @@ async fn main() {
 
             }
 
-            _ = tokio::signal::ctrl_c() => {
-                info!("Ctrl-C received, exiting...");
-                tx.send(ChannelMessage::Exit).await.unwrap();
-                ui_task_join.await.unwrap();
-                break;
+            key = key_rx.recv() => {
+                // The open palette takes the keys
+                if let (Some(open), Some(key)) = (palette.take(), key) {
+                    match open.handle_key(key) {
+                        PaletteOutcome::Open(open) => palette = Some(open),
+                        PaletteOutcome::Closed => {}
+                        PaletteOutcome::Run(action) => {
+                            info!("Running {:?}", action);
+                            let entry = Esp32LogEntry::local("CMD", action.label().to_string());
+                            tx.send(ChannelMessage::LogsEsp32(vec![entry])).await.unwrap();
+
+                            let request = action.request().write_to_bytes().unwrap();
+                            if let Err(e) = ws.send(WsMessage::Binary(request)).await {
+                                error!("Cannot send the command: {:?}", e);
+                            }
+                        }
+                    }
+
+                    tx.send(ChannelMessage::Palette(palette.clone())).await.unwrap();
+                    continue;
+                }
+
+                match key {
+                    Some(Key::Char('p')) => {
+                        palette = Some(Palette::default());
+                        tx.send(ChannelMessage::Palette(palette.clone())).await.unwrap();
+                    }
+                    Some(Key::Char('l')) => {
+                        let Some(levels) = log_levels.as_ref() else {
+                            info!("Device log levels not known yet");
+                            continue;
+                        };
+
+                        let mut config = levels.clone();
+                        config.max_level = next_log_level(&config.max_level).to_string();
+                        info!("Setting the device log level to {}", config.max_level);
+
+                        let mut set_levels = SetLogLevelsRequest::new();
+                        set_levels.config = protobuf::MessageField::some(config);
+                        let mut request = MessageRequest::new();
+                        request.set_set_log_levels(set_levels);
+
+                        let request = request.write_to_bytes().unwrap();
+                        if let Err(e) = ws.send(WsMessage::Binary(request)).await {
+                            error!("Cannot send the log levels: {:?}", e);
+                        }
+                    }
+                    Some(Key::Char('q')) | Some(Key::Ctrl('c')) | None => {
+                        info!("Exit key received, exiting...");
+                        tx.send(ChannelMessage::Exit).await.unwrap();
+                        ui_task_join.await.unwrap();
+                        break;
+                    }
+                    Some(_) => {}
+                }
             }
         }
     }
@@ -153,7 +566,22 @@ async fn main() {
     info!("Connection closed");
 }
 
-async fn handle_binary_message(data: Vec<u8>, tx: &mpsc::Sender<ChannelMessage>) -> Result<(), ()> {
+/// The log level following the given one, wrapping around to the least verbose
+fn next_log_level(level: &str) -> &'static str {
+    match LOG_LEVELS
+        .iter()
+        .position(|l| l.eq_ignore_ascii_case(level))
+    {
+        Some(index) => LOG_LEVELS[(index + 1) % LOG_LEVELS.len()],
+        None => LOG_LEVELS[0],
+    }
+}
+
+async fn handle_binary_message(
+    data: Vec<u8>,
+    tx: &mpsc::Sender<ChannelMessage>,
+    log_levels: &mut Option<LogLevelConfig>,
+) -> Result<(), ()> {
     let message_resp = match MessageResponse::parse_from_bytes(&data) {
         Ok(msg) => msg,
         Err(e) => {
@@ -164,13 +592,51 @@ async fn handle_binary_message(data: Vec<u8>, tx: &mpsc::Sender<ChannelMessage>)
 
     debug!("MessageResponse: {:?}", message_resp);
 
-    if message_resp.status == EnumOrUnknown::new(message_response::Status::OK) {
+    if let Some(levels) = message_resp.log_levels.as_ref() {
+        tx.send(ChannelMessage::LogLevel(levels.max_level.clone()))
+            .await
+            .unwrap();
+        *log_levels = Some(levels.clone());
+    }
+
+    if let Some(health) = message_resp.health.as_ref() {
+        tx.send(ChannelMessage::Health(health.clone()))
+            .await
+            .unwrap();
+    }
+
+    if let Some(history) = message_resp.history.as_ref() {
+        let channel_msg: Vec<HistorySeries> = history
+            .series
+            .iter()
+            .map(|series| HistorySeries {
+                sensor_name: series.sensor_name.clone(),
+                value_name: series.value_name.clone(),
+                values: series.values.clone(),
+            })
+            .collect();
+
+        tx.send(ChannelMessage::History(channel_msg)).await.unwrap();
+    }
+
+    let mut channel_msg: Vec<Esp32LogEntry> = Vec::new();
+
+    if message_resp.status != EnumOrUnknown::new(message_response::Status::OK) {
+        error!(
+            "Error: {:?} {}",
+            message_resp.status, message_resp.status_message
+        );
+        channel_msg.push(Esp32LogEntry::local(
+            "ERROR",
+            message_resp.status_message.clone(),
+        ));
+    } else if !message_resp.sensors_data_response.is_empty() {
+        // Command responses carry no sensor data
         let sensors_data = &message_resp.sensors_data_response;
         let channel_msg: Vec<SensorData> = sensors_data
             .iter()
             .map(|sensor| SensorData {
                 sensor_name: sensor.sensor_name.clone(),
-                sensor_type: sensor.sensor_type.clone(),
                 sensor_location: sensor.sensor_location.clone(),
                 sensor_values: sensor
                     .sensor_values
@@ -187,20 +653,74 @@ async fn handle_binary_message(data: Vec<u8>, tx: &mpsc::Sender<ChannelMessage>)
         tx.send(ChannelMessage::SensorsData(channel_msg))
             .await
             .unwrap();
-    } else {
-        error!("Error: {:?}", message_resp.status);
     }
 
-    let logs = message_resp.log_data_response;
+    channel_msg.extend(
+        message_resp
+            .log_data_response
+            .iter()
+            .map(Esp32LogEntry::from_response),
+    );
+
+    channel_msg.extend(message_resp.alert_events.iter().map(|alert| Esp32LogEntry {
+        log_message: format!("[{}] {}", alert.rule_id, alert.message),
+        log_timestamp_ms: alert.timestamp * 1000,
+        wall_clock: alert.timestamp * 1000 >= MIN_WALL_CLOCK_MS,
+        log_level: "ALERT".to_string(),
+        target: alert.sensor_name.clone(),
+        location: String::new(),
+        task_name: String::new(),
+    }));
+
+    if let Some(status) = message_resp.wifi_status.as_ref() {
+        let log_message = if status.connected {
+            format!(
+                "{}: {} channel {} RSSI {} dBm IP {}",
+                status.reason, status.ssid, status.channel, status.rssi, status.ip
+            )
+        } else {
+            format!("{}: not connected", status.reason)
+        };
+
+        // The status carries no timestamp, it is shown at the time it is received
+        channel_msg.push(Esp32LogEntry::local("WIFI", log_message));
+    }
 
-    let channel_msg: Vec<Esp32LogEntry> = logs
-        .iter()
-        .map(|log| Esp32LogEntry {
-            log_message: log.log_message.clone(),
-            log_timestamp: log.log_timestamp,
-            log_level: log.log_level.clone(),
-        })
-        .collect();
+    if let Some(scan) = message_resp.i2c_scan.as_ref() {
+        let log_message = if scan.addresses.is_empty() {
+            "I2C scan: no device found".to_string()
+        } else {
+            let addresses: Vec<String> = scan
+                .addresses
+                .iter()
+                .map(|a| format!("0x{:02x}", a))
+                .collect();
+            format!("I2C scan: devices at {}", addresses.join(", "))
+        };
+        channel_msg.push(Esp32LogEntry::local("CMD", log_message));
+    }
+
+    if let Some(diagnostics) = message_resp.diagnostics.as_ref() {
+        if let Some(heap) = diagnostics.heap.as_ref() {
+            channel_msg.push(Esp32LogEntry::local(
+                "CMD",
+                format!(
+                    "Heap: {} bytes free, {} at least, largest block {}",
+                    heap.free, heap.min_free, heap.largest_free_block
+                ),
+            ));
+        }
+
+        channel_msg.extend(diagnostics.tasks.iter().map(|task| {
+            Esp32LogEntry::local(
+                "CMD",
+                format!(
+                    "Task {:<16} prio {:>2} {:<9} stack left {} bytes",
+                    task.name, task.priority, task.state, task.stack_high_water_mark
+                ),
+            )
+        }));
+    }
 
     tx.send(ChannelMessage::LogsEsp32(channel_msg))
         .await
@@ -210,9 +730,7 @@ async fn handle_binary_message(data: Vec<u8>, tx: &mpsc::Sender<ChannelMessage>)
 }
 
 async fn ui_task(mut rx: mpsc::Receiver<ChannelMessage>, mut app: App) {
-    // termion raw mode
-    //let stdout = stdout().into_raw_mode().unwrap();
-    let stdout = stdout();
+    let stdout = stdout().into_raw_mode().unwrap();
     let backend = TermionBackend::new(stdout);
 
     let mut terminal = Terminal::new(backend).unwrap();
@@ -238,6 +756,18 @@ async fn ui_task(mut rx: mpsc::Receiver<ChannelMessage>, mut app: App) {
             ChannelMessage::SensorsData(data) => {
                 app.add_sensors_data(data);
             }
+            ChannelMessage::History(history) => {
+                app.add_history(history);
+            }
+            ChannelMessage::LogLevel(level) => {
+                app.log_level = level;
+            }
+            ChannelMessage::Palette(palette) => {
+                app.palette = palette;
+            }
+            ChannelMessage::Health(health) => {
+                app.health = Some(health);
+            }
             ChannelMessage::Exit => {
                 info!("Exit received, exiting...");
                 break 'ui_loop;
@@ -258,46 +788,131 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .margin(1)
-        .constraints(
-            [
-                Constraint::Percentage(20),
-                Constraint::Percentage(20),
-                Constraint::Percentage(60),
-            ]
-            .as_ref(),
-        )
+        .constraints([Constraint::Percentage(40), Constraint::Percentage(60)].as_ref())
         .split(size);
 
-    let mut chunk = 0;
+    let sensors_count = app.sensors_data.len().max(1) as u32;
+    let sensor_chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(
+            (0..sensors_count)
+                .map(|_| Constraint::Ratio(1, sensors_count))
+                .collect::<Vec<_>>(),
+        )
+        .split(chunks[0]);
 
-    for sensor in &app.sensors_data {
+    for (sensor, chunk) in app.sensors_data.iter().zip(sensor_chunks.iter()) {
         let mut sensor_data_str = String::new();
 
         for values in &sensor.sensor_values {
             sensor_data_str.push_str(
                 format!("{}: {} {}\n", values.value_name, values.value, values.unit).as_str(),
             );
+
+            if let Some((min, max)) = app.history_range(&sensor.sensor_name, &values.value_name) {
+                sensor_data_str.push_str(format!("  [{:.1} .. {:.1}]\n", min, max).as_str());
+            }
         }
 
-        let title = format!(" Sensor {} ", sensor.sensor_name);
+        let title = if sensor.sensor_location.is_empty() {
+            format!(" Sensor {} ", sensor.sensor_name)
+        } else {
+            format!(
+                " Sensor {} @ {} ",
+                sensor.sensor_name, sensor.sensor_location
+            )
+        };
         let paragraph = Paragraph::new(sensor_data_str)
             .block(Block::default().title(title).borders(Borders::ALL));
 
-        f.render_widget(paragraph, chunks[chunk]);
-
-        chunk += 1;
+        f.render_widget(paragraph, *chunk);
     }
 
     let logs_with_date = logs_to_tui_list_item(app);
 
+    let logs_title = if app.log_level.is_empty() {
+        " ESP32 Logs - [p] commands, [q] quit ".to_string()
+    } else {
+        format!(
+            " ESP32 Logs ({}) - [l] log level, [p] commands, [q] quit ",
+            app.log_level
+        )
+    };
+
     let logs = List::new(logs_with_date)
-        .block(Block::default().title(" ESP32 Logs ").borders(Borders::ALL))
+        .block(Block::default().title(logs_title).borders(Borders::ALL))
         .highlight_style(Style::default());
 
-    f.render_widget(logs, chunks[chunk]);
+    match app.health.as_ref() {
+        Some(health) => {
+            let bottom_chunks = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Percentage(70), Constraint::Percentage(30)].as_ref())
+                .split(chunks[1]);
+
+            f.render_widget(logs, bottom_chunks[0]);
+
+            let status = Paragraph::new(health_to_text(health)).block(
+                Block::default()
+                    .title(" Device health ")
+                    .borders(Borders::ALL),
+            );
+            f.render_widget(status, bottom_chunks[1]);
+        }
+        None => f.render_widget(logs, chunks[1]),
+    }
+
+    if let Some(palette) = app.palette.as_ref() {
+        palette.render(f, size);
+    }
 }
 
-fn logs_to_tui_list_item(app: &App) -> Vec<ListItem> {
+/// The lines of the device health panel
+fn health_to_text(health: &DeviceHealth) -> String {
+    let secs = health.uptime_ms / 1000;
+    let mut text = format!(
+        "Uptime: {}d {:02}:{:02}:{:02}\nReset: {}\n",
+        secs / 86400,
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60,
+        health.reset_reason
+    );
+
+    if let Some(heap) = health.heap.as_ref() {
+        text.push_str(&format!(
+            "Heap: {} KiB free, {} KiB min\nLargest block: {} KiB\n",
+            heap.free / 1024,
+            heap.min_free / 1024,
+            heap.largest_free_block / 1024
+        ));
+    }
+
+    match health.rssi {
+        Some(rssi) => text.push_str(&format!("RSSI: {} dBm\n", rssi)),
+        None => text.push_str("RSSI: not connected\n"),
+    }
+
+    if let Some(temperature) = health.cpu_temperature {
+        text.push_str(&format!("CPU: {:.1} °C\n", temperature));
+    }
+
+    text.push_str(&format!(
+        "Clients: {} WebSocket, {} HTTP\n\nStack left (bytes):\n",
+        health.ws_clients, health.http_clients
+    ));
+
+    for task in health.tasks.iter() {
+        text.push_str(&format!(
+            "  {:<16} {:>6}\n",
+            task.name, task.stack_high_water_mark
+        ));
+    }
+
+    text
+}
+
+fn logs_to_tui_list_item(app: &App) -> Vec<ListItem<'_>> {
     app.logs
         .iter()
         .map(|log| {
@@ -305,20 +920,42 @@ fn logs_to_tui_list_item(app: &App) -> Vec<ListItem> {
                 "ERROR" => Style::default().fg(Color::Red),
                 "WARN" => Style::default().fg(Color::Yellow),
                 "INFO" => Style::default().fg(Color::Blue),
+                "ALERT" => Style::default().fg(Color::Magenta),
+                "WIFI" => Style::default().fg(Color::Cyan),
+                "CMD" => Style::default().fg(Color::Green),
                 _ => Style::default(),
             };
 
-            let timestamp_str = format!("{:<9}", log.log_timestamp);
-
-            let log = Spans::from(vec![
+            let mut spans = vec![
                 Span::styled(
-                    timestamp_str,
+                    format!("{:<23}", log.format_timestamp()),
                     Style::default().add_modifier(Modifier::ITALIC),
                 ),
                 Span::raw(" "),
-                Span::styled(format!("{:<9}", log.log_level), sty),
-                Span::raw(log.log_message.clone()),
-            ]);
+                Span::styled(format!("{:<6}", log.log_level), sty),
+            ];
+
+            if !log.task_name.is_empty() {
+                spans.push(Span::raw(format!("[{}] ", log.task_name)));
+            }
+
+            if !log.target.is_empty() {
+                spans.push(Span::styled(
+                    format!("{} ", log.target),
+                    Style::default().fg(Color::DarkGray),
+                ));
+            }
+
+            if !log.location.is_empty() {
+                spans.push(Span::styled(
+                    format!("({}) ", log.location),
+                    Style::default().fg(Color::DarkGray),
+                ));
+            }
+
+            spans.push(Span::raw(log.log_message.clone()));
+
+            let log = Spans::from(spans);
 
             ListItem::new(vec![log])
         })
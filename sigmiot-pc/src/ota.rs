@@ -0,0 +1,142 @@
+use std::io::stdout;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use protobuf::{EnumOrUnknown, Message};
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tui::backend::{Backend, TermionBackend};
+use tui::layout::Rect;
+use tui::style::{Color, Style};
+use tui::widgets::{Block, Borders, Gauge};
+use tui::Terminal;
+
+use crate::sigmiot_data::{
+    message_response, MessageRequest, MessageResponse, OtaBeginRequest, OtaChunkRequest,
+    OtaEndRequest, OtaStatus,
+};
+
+/// Size of the image parts, a request must fit in the 4 KiB WebSocket frames of the device
+const OTA_CHUNK_SIZE: usize = 2048;
+/// How long the device may take to answer, the begin request waits for the flash erase
+const OTA_REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+/// Height of the progress bar with its borders
+const GAUGE_HEIGHT: u16 = 3;
+
+pub type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Send a firmware image to the device, the device restarts into it once verified
+/// # Arguments
+/// * `ws` - The connection to the device
+/// * `image` - The firmware image, as written to an OTA partition
+/// * `signature` - Signature of the image SHA-256 digest, empty if the firmware takes
+///   unsigned images
+pub async fn run_ota(ws: &mut WsStream, image: &[u8], signature: Vec<u8>) -> Result<(), String> {
+    let mut begin = OtaBeginRequest::new();
+    begin.size = image.len() as u32;
+    begin.sha256 = Sha256::digest(image).to_vec();
+    begin.signature = signature;
+
+    let mut request = MessageRequest::new();
+    request.set_ota_begin(begin);
+    let mut status = send_request(ws, request).await?;
+
+    let mut terminal = Terminal::new(TermionBackend::new(stdout())).map_err(|e| e.to_string())?;
+    terminal.clear().map_err(|e| e.to_string())?;
+    draw_progress(&mut terminal, &status)?;
+
+    for chunk in image.chunks(OTA_CHUNK_SIZE) {
+        let mut part = OtaChunkRequest::new();
+        part.offset = status.received;
+        part.data = chunk.to_vec();
+
+        let mut request = MessageRequest::new();
+        request.set_ota_chunk(part);
+        status = send_request(ws, request).await?;
+
+        draw_progress(&mut terminal, &status)?;
+    }
+
+    let mut request = MessageRequest::new();
+    request.set_ota_end(OtaEndRequest::new());
+    let status = send_request(ws, request).await?;
+
+    terminal
+        .set_cursor(0, GAUGE_HEIGHT)
+        .map_err(|e| e.to_string())?;
+    terminal.show_cursor().map_err(|e| e.to_string())?;
+
+    if !status.done {
+        return Err("The device did not accept the image".to_string());
+    }
+
+    Ok(())
+}
+
+/// Send a request and wait for its reply, the sensor data and logs pushed meanwhile are skipped
+async fn send_request(ws: &mut WsStream, request: MessageRequest) -> Result<OtaStatus, String> {
+    ws.send(WsMessage::Binary(request.write_to_bytes().unwrap()))
+        .await
+        .map_err(|e| format!("Cannot send the request: {}", e))?;
+
+    loop {
+        let msg = match tokio::time::timeout(OTA_REPLY_TIMEOUT, ws.next()).await {
+            Ok(Some(Ok(msg))) => msg,
+            Ok(Some(Err(e))) => return Err(format!("Connection error: {}", e)),
+            Ok(None) => return Err("The device closed the connection".to_string()),
+            Err(_) => return Err("The device did not answer".to_string()),
+        };
+
+        if !msg.is_binary() {
+            continue;
+        }
+
+        let response = MessageResponse::parse_from_bytes(&msg.into_data())
+            .map_err(|e| format!("Cannot parse the reply: {}", e))?;
+
+        if response.status != EnumOrUnknown::new(message_response::Status::OK) {
+            return Err(format!("Update failed: {}", response.status_message));
+        }
+
+        if let Some(status) = response.ota_status.into_option() {
+            return Ok(status);
+        }
+    }
+}
+
+fn draw_progress<B: Backend>(terminal: &mut Terminal<B>, status: &OtaStatus) -> Result<(), String> {
+    let ratio = if status.size > 0 {
+        status.received as f64 / status.size as f64
+    } else {
+        0.0
+    };
+
+    terminal
+        .draw(|f| {
+            let area = Rect {
+                height: GAUGE_HEIGHT.min(f.size().height),
+                ..f.size()
+            };
+
+            let gauge = Gauge::default()
+                .block(
+                    Block::default()
+                        .title(" Firmware update ")
+                        .borders(Borders::ALL),
+                )
+                .gauge_style(Style::default().fg(Color::Green))
+                .ratio(ratio.min(1.0))
+                .label(format!(
+                    "{} / {} KiB",
+                    status.received / 1024,
+                    status.size / 1024
+                ));
+
+            f.render_widget(gauge, area);
+        })
+        .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use termion::event::Key;
+use tui::backend::Backend;
+use tui::layout::Rect;
+use tui::style::{Color, Modifier, Style};
+use tui::widgets::{Block, Borders, Clear, List, ListItem, ListState};
+use tui::Frame;
+
+use crate::sigmiot_data::{
+    DiagnosticsRequest, FactoryResetRequest, ForceReadRequest, I2cScanRequest, MessageRequest,
+    RebootRequest,
+};
+
+/// The device management actions of the command palette
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaletteAction {
+    ForceRead,
+    I2cScan,
+    Diagnostics,
+    Reboot,
+    FactoryReset,
+}
+
+impl PaletteAction {
+    pub const ALL: [PaletteAction; 5] = [
+        PaletteAction::ForceRead,
+        PaletteAction::I2cScan,
+        PaletteAction::Diagnostics,
+        PaletteAction::Reboot,
+        PaletteAction::FactoryReset,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            PaletteAction::ForceRead => "Read the sensors now",
+            PaletteAction::I2cScan => "Scan the I2C bus",
+            PaletteAction::Diagnostics => "Show heap and tasks",
+            PaletteAction::Reboot => "Reboot",
+            PaletteAction::FactoryReset => "Factory reset",
+        }
+    }
+
+    /// The actions which cannot be undone are confirmed first
+    fn needs_confirmation(&self) -> bool {
+        *self == PaletteAction::FactoryReset
+    }
+
+    pub fn request(&self) -> MessageRequest {
+        let mut request = MessageRequest::new();
+        match self {
+            PaletteAction::ForceRead => request.set_force_read(ForceReadRequest::new()),
+            PaletteAction::I2cScan => request.set_i2c_scan(I2cScanRequest::new()),
+            PaletteAction::Diagnostics => request.set_get_diagnostics(DiagnosticsRequest::new()),
+            PaletteAction::Reboot => request.set_reboot(RebootRequest::new()),
+            PaletteAction::FactoryReset => request.set_factory_reset(FactoryResetRequest::new()),
+        }
+        request
+    }
+}
+
+/// The state of the open command palette
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Palette {
+    selected: usize,
+    /// The selected action waits for a second Enter
+    confirming: bool,
+}
+
+/// What a key did to the palette
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PaletteOutcome {
+    Open(Palette),
+    Closed,
+    Run(PaletteAction),
+}
+
+impl Palette {
+    pub fn handle_key(mut self, key: Key) -> PaletteOutcome {
+        match key {
+            Key::Up if !self.confirming => {
+                self.selected = self
+                    .selected
+                    .checked_sub(1)
+                    .unwrap_or(PaletteAction::ALL.len() - 1);
+            }
+            Key::Down if !self.confirming => {
+                self.selected = (self.selected + 1) % PaletteAction::ALL.len();
+            }
+            Key::Char('\n') => {
+                let action = PaletteAction::ALL[self.selected];
+                if self.confirming || !action.needs_confirmation() {
+                    return PaletteOutcome::Run(action);
+                }
+                self.confirming = true;
+            }
+            Key::Esc | Key::Char('p') => return PaletteOutcome::Closed,
+            _ => self.confirming = false,
+        }
+
+        PaletteOutcome::Open(self)
+    }
+
+    /// Draw the palette over the middle of the given area
+    pub fn render<B: Backend>(&self, f: &mut Frame<B>, area: Rect) {
+        let height = (PaletteAction::ALL.len() as u16 + 2).min(area.height);
+        let width = 44.min(area.width);
+        let popup = Rect {
+            x: area.x + (area.width - width) / 2,
+            y: area.y + (area.height - height) / 2,
+            width,
+            height,
+        };
+
+        let items: Vec<ListItem> = PaletteAction::ALL
+            .iter()
+            .map(|action| ListItem::new(action.label()))
+            .collect();
+
+        let (title, highlight) = if self.confirming {
+            (" Enter again to confirm ", Style::default().fg(Color::Red))
+        } else {
+            (" Commands - [Enter] run, [Esc] close ", Style::default())
+        };
+
+        let list = List::new(items)
+            .block(Block::default().title(title).borders(Borders::ALL))
+            .highlight_style(highlight.add_modifier(Modifier::REVERSED));
+
+        let mut state = ListState::default();
+        state.select(Some(self.selected));
+
+        f.render_widget(Clear, popup);
+        f.render_stateful_widget(list, popup, &mut state);
+    }
+}
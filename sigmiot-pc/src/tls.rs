@@ -0,0 +1,105 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use sha2::{Digest, Sha256};
+use tokio_tungstenite::Connector;
+
+/// How the certificate of a wss:// device is checked
+pub enum TlsTrust {
+    /// The public CAs, for a device with a certificate from a public CA
+    WebPki,
+    /// The CAs of a PEM file, e.g. the private CA which signed the device certificate
+    CaFile(String),
+    /// The SHA-256 fingerprint of the device certificate, for a self-signed one.
+    /// The host name and the validity dates are not checked.
+    Pin([u8; 32]),
+}
+
+/// Parse a SHA-256 fingerprint in hex, with or without colons like
+/// `openssl x509 -noout -fingerprint -sha256` prints it
+pub fn parse_fingerprint(value: &str) -> Result<[u8; 32], String> {
+    let hex: String = value.chars().filter(|c| *c != ':').collect();
+    if hex.len() != 64 {
+        return Err(format!(
+            "a SHA-256 fingerprint has 64 hex digits, got {}",
+            hex.len()
+        ));
+    }
+
+    let mut fingerprint = [0_u8; 32];
+    for (i, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| format!("invalid hex digit in {}", value))?;
+    }
+
+    Ok(fingerprint)
+}
+
+/// Build the TLS connector of the WebSocket client
+pub fn tls_connector(trust: &TlsTrust) -> Result<Connector, String> {
+    let builder = ClientConfig::builder().with_safe_defaults();
+
+    let config = match trust {
+        TlsTrust::WebPki => {
+            let mut roots = RootCertStore::empty();
+            roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }));
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        TlsTrust::CaFile(path) => {
+            let file = File::open(path).map_err(|e| format!("Cannot open {}: {}", path, e))?;
+            let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+                .map_err(|e| format!("Cannot read {}: {}", path, e))?;
+
+            let mut roots = RootCertStore::empty();
+            let (added, _) = roots.add_parsable_certificates(&certs);
+            if added == 0 {
+                return Err(format!("No CA certificate in {}", path));
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        TlsTrust::Pin(fingerprint) => builder
+            .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier {
+                fingerprint: *fingerprint,
+            }))
+            .with_no_client_auth(),
+    };
+
+    Ok(Connector::Rustls(Arc::new(config)))
+}
+
+/// Accepts only the server certificate with the pinned fingerprint
+struct PinnedCertVerifier {
+    fingerprint: [u8; 32],
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint: [u8; 32] = Sha256::digest(&end_entity.0).into();
+
+        if fingerprint == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "the certificate does not match the pinned fingerprint".into(),
+            ))
+        }
+    }
+}
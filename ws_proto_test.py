@@ -4,8 +4,20 @@
 # Run pip3 install protobuf to install the protobuf library
 # 'export PROTOCOL_BUFFERS_PYTHON_IMPLEMENTATION=python' to use the python implementation of protobuf
 # Run 'protoc -I=. --python_out=. protos/sigmiot_data.proto' to generate the python code from the proto file
-
+#
+# To stand in for a device serving WSS, pass a certificate and its key:
+#   openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes \
+#       -keyout key.pem -out cert.pem -days 365 -subj /CN=localhost \
+#       -addext subjectAltName=DNS:localhost,IP:127.0.0.1
+#   ./ws_proto_test.py --cert cert.pem --key key.pem
+# and connect with the fingerprint printed by 'openssl x509 -in cert.pem -noout -fingerprint -sha256':
+#   sigmiot-pc wss://localhost:8080/ws --pin <fingerprint>
+# or with the certificate of its CA, by name or by IP address like a discovered device:
+#   sigmiot-pc wss://127.0.0.1:8080/ws --ca ca.pem
+
+import argparse
 import asyncio
+import ssl
 import websockets
 
 from protos import sigmiot_data_pb2
@@ -129,10 +141,22 @@ async def handle_connection(websocket, path):
     await message_sender(websocket, path)
 
 if __name__ == "__main__":
-    print("Starting WebSocket server")
+    parser = argparse.ArgumentParser(description="Stand-in for a SigmIoT device")
+    parser.add_argument("--port", type=int, default=8080)
+    parser.add_argument("--cert", help="PEM certificate, serve WSS instead of WS")
+    parser.add_argument("--key", help="PEM private key of the certificate")
+    args = parser.parse_args()
+
+    ssl_context = None
+    if args.cert:
+        ssl_context = ssl.SSLContext(ssl.PROTOCOL_TLS_SERVER)
+        ssl_context.load_cert_chain(args.cert, args.key)
+
+    scheme = "wss" if ssl_context else "ws"
+    print("Starting WebSocket server at {}://localhost:{}/ws".format(scheme, args.port))
     print("Press Ctrl+C to stop the server")
 
     # Start the WebSocket server
     asyncio.get_event_loop().run_until_complete(
-        websockets.serve(handle_connection, 'localhost', 8080))
+        websockets.serve(handle_connection, 'localhost', args.port, ssl=ssl_context))
     asyncio.get_event_loop().run_forever()